@@ -5,6 +5,14 @@ use std::{fmt, hash};
 use blockchain::hash::Hash;
 use blockchain::hash160::Hash160;
 
+/// Version byte of P2PK outputs, encoded with a `P` prefix
+pub const P2PK_ADDRESS_VERSION: u8 = 0x37;
+/// Version byte of bare multisig identities, encoded with an `M` prefix
+pub const MULTISIG_ADDRESS_VERSION: u8 = 0x32;
+/// Version byte of pseudo-addresses of other scripts, encoded with an `N`
+/// prefix
+pub const SCRIPT_ADDRESS_VERSION: u8 = 0x35;
+
 #[derive(Clone, Debug)]
 pub struct Taint {
     pub label: u8,
@@ -62,6 +70,27 @@ impl Address {
         return Address::from_hash160(&hash160, version, taints);
     }
 
+    /// Identity of a bare multisig output, the hash of the number of required
+    /// signatures, the number of keys and the sorted set of keys.
+    pub fn from_multisig(
+        required: u32,
+        pubkeys: &[&[u8]],
+        taints: Option<VecDeque<Taint>>,
+    ) -> Address {
+        let mut keys = pubkeys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&required.to_le_bytes());
+        data.extend_from_slice(&(pubkeys.len() as u32).to_le_bytes());
+        for key in keys {
+            data.push(key.len() as u8);
+            data.extend_from_slice(key);
+        }
+        Address::from_hash160(&Hash160::from_data(&data), MULTISIG_ADDRESS_VERSION, taints)
+    }
+
     pub fn from_hash160(
         hash160: &Hash160,
         version: u8,
@@ -75,3 +104,38 @@ impl Address {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multisig_ignores_key_order() {
+        let (a, b, c) = ([2u8; 33], [3u8; 33], [4u8; 65]);
+        let address = Address::from_multisig(2, &[&a, &b, &c], None);
+        let orders: [[&[u8]; 3]; 5] = [
+            [&a, &c, &b],
+            [&b, &a, &c],
+            [&b, &c, &a],
+            [&c, &a, &b],
+            [&c, &b, &a],
+        ];
+        for keys in orders.iter() {
+            assert_eq!(Address::from_multisig(2, keys, None), address);
+        }
+        assert_eq!(address.addr[0], MULTISIG_ADDRESS_VERSION);
+    }
+
+    #[test]
+    fn multisig_depends_on_required_signatures_and_keys() {
+        let (a, b, c) = ([2u8; 33], [3u8; 33], [4u8; 65]);
+        let address = Address::from_multisig(2, &[&a, &b, &c], None);
+        assert_ne!(Address::from_multisig(1, &[&a, &b, &c], None), address);
+        assert_ne!(Address::from_multisig(3, &[&a, &b, &c], None), address);
+        assert_ne!(Address::from_multisig(2, &[&a, &b], None), address);
+        assert_ne!(
+            Address::from_multisig(2, &[&a, &b, &[5u8; 33]], None),
+            address
+        );
+    }
+}
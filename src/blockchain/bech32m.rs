@@ -0,0 +1,116 @@
+//! Address encoding of witness version 1 and later programs (BIP350), the
+//! bech32 crate only implements the checksum of version 0 addresses.
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [
+    0x3b6a_57b2,
+    0x2650_8e6d,
+    0x1ea1_19fa,
+    0x3d42_33dd,
+    0x2a14_62b3,
+];
+/// Constant the checksum is xored with, 1 for bech32
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+/// Encodes a witness program as a bech32m address with the given
+/// human-readable part.
+pub fn encode(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(to_base32(program));
+
+    let mut values: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| b & 0x1f));
+    values.extend(&data);
+    values.extend(&[0; 6]);
+    let checksum = polymod(&values) ^ BECH32M_CONST;
+
+    let mut address = format!("{}1", hrp);
+    for value in data {
+        address.push(CHARSET[value as usize] as char);
+    }
+    for i in 0..6 {
+        address.push(CHARSET[(checksum >> (5 * (5 - i)) & 0x1f) as usize] as char);
+    }
+    address
+}
+
+/// Regroups bytes to 5 bit values, the last one padded with zeros.
+fn to_base32(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity((data.len() * 8).div_ceil(5));
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push((acc >> bits & 0x1f) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push((acc << (5 - bits) & 0x1f) as u8);
+    }
+    out
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk = 1u32;
+    for value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ff_ffff) << 5 ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if top >> i & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    // Valid addresses of BIP350
+    #[test]
+    fn encode_version_1() {
+        let program =
+            "751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6"
+                .from_hex()
+                .unwrap();
+        assert_eq!(
+            encode("bc", 1, &program),
+            "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y"
+        );
+        let program = "000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"
+            .from_hex()
+            .unwrap();
+        assert_eq!(
+            encode("tb", 1, &program),
+            "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c"
+        );
+        let program = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+            .from_hex()
+            .unwrap();
+        assert_eq!(
+            encode("bc", 1, &program),
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"
+        );
+    }
+
+    #[test]
+    fn encode_version_16() {
+        assert_eq!(encode("bc", 16, &[0x75, 0x1e]), "bc1sw50qgdz25j");
+    }
+
+    #[test]
+    fn encode_version_2() {
+        let program = "751e76e8199196d454941c45d1b3a323".from_hex().unwrap();
+        assert_eq!(
+            encode("bc", 2, &program),
+            "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs"
+        );
+    }
+}
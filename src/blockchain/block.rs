@@ -2,6 +2,8 @@ use byteorder::{LittleEndian, ReadBytesExt};
 
 use blockchain::buffer::*;
 use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::transaction::Transaction;
 use parser::{ParseError, ParseResult};
 
 pub struct BlockHeader<'a>(&'a [u8; 80]);
@@ -15,7 +17,7 @@ pub struct Transactions<'a> {
 }
 
 impl<'a> Block<'a> {
-    pub fn read(slice: &mut &'a [u8]) -> ParseResult<Block<'a>> {
+    pub fn read(slice: &mut &'a [u8], params: &ChainParams) -> ParseResult<Block<'a>> {
         while slice.len() > 0 && slice[0] == 0 {
             *slice = &slice[1..];
         }
@@ -26,8 +28,8 @@ impl<'a> Block<'a> {
             match block_magic {
                 // Incomplete blk file
                 0x00 => Err(ParseError::Eof),
-                // Bitcoin magic amount
-                0xd9b4bef9 => {
+                // Network magic amount
+                magic if magic == params.magic => {
                     let block_len = read_u32(slice)? as usize;
                     if block_len < 80 {
                         Err(ParseError::Eof)
@@ -48,6 +50,80 @@ impl<'a> Block<'a> {
     pub fn transactions(&self) -> Transactions {
         Transactions::new(&self.0[80..])
     }
+
+    /// Height committed to in the coinbase script of BIP34 (version 2+) blocks.
+    pub fn coinbase_height(&self) -> Option<usize> {
+        if self.header().version() < 2 {
+            return None;
+        }
+
+        let transactions = self.transactions();
+        let mut slice = transactions.slice;
+        read_u32(&mut slice).ok()?;
+        if slice.first() == Some(&0x00) {
+            // Segwit marker and flag
+            read_slice(&mut slice, 2).ok()?;
+        }
+        if transactions.count == 0 || read_var_int(&mut slice).ok()? == 0 {
+            return None;
+        }
+        read_slice(&mut slice, 36).ok()?;
+        let script_len = read_var_int(&mut slice).ok()? as usize;
+        let script = read_slice(&mut slice, script_len).ok()?;
+
+        match *script.first()? {
+            // OP_1 to OP_16
+            op @ 0x51..=0x60 => Some((op - 0x50) as usize),
+            len @ 1..=8 if script.len() > len as usize => Some(
+                script[1..len as usize + 1]
+                    .iter()
+                    .rev()
+                    .fold(0, |height, byte| height << 8 | *byte as usize),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether the block meets its proof of work target and its transactions
+    /// match the merkle root, so it was not cut short by a later block.
+    pub fn is_intact(&self) -> bool {
+        let header = self.header();
+        if !header.check_pow() {
+            return false;
+        }
+
+        let transactions = self.transactions();
+        let mut slice = transactions.slice;
+        let mut txids = Vec::with_capacity(capacity(transactions.count, slice, 60));
+        for _ in 0..transactions.count {
+            match Transaction::read_txid(&mut slice) {
+                Ok(txid) => txids.push(txid),
+                Err(_) => return false,
+            }
+        }
+        !txids.is_empty() && slice.is_empty() && merkle_root(txids) == *header.merkle_root()
+    }
+}
+
+/// Merkle root of the given txids, the last one is paired with itself on
+/// levels with an odd count.
+pub fn merkle_root(mut hashes: Vec<Hash>) -> Hash {
+    while hashes.len() > 1 {
+        if hashes.len() % 2 == 1 {
+            let last = hashes[hashes.len() - 1];
+            hashes.push(last);
+        }
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| {
+                let mut data = [0u8; 64];
+                data[..32].copy_from_slice(&pair[0]);
+                data[32..].copy_from_slice(&pair[1]);
+                Hash::from_data(&data)
+            })
+            .collect();
+    }
+    hashes[0]
 }
 
 impl<'a> Transactions<'a> {
@@ -69,12 +145,94 @@ impl<'a> BlockHeader<'a> {
         Hash::from_data(self.0)
     }
 
+    pub fn version(&self) -> u32 {
+        let mut slice = &self.0[..4];
+        slice.read_u32::<LittleEndian>().unwrap()
+    }
+
     pub fn prev_hash(&self) -> &'a Hash {
         Hash::from_slice(array_ref!(self.0, 4, 32))
     }
 
+    pub fn merkle_root(&self) -> &'a Hash {
+        Hash::from_slice(array_ref!(self.0, 36, 32))
+    }
+
     pub fn timestamp(&self) -> u32 {
         let mut slice = &self.0[68..];
         slice.read_u32::<LittleEndian>().unwrap()
     }
+
+    pub fn bits(&self) -> u32 {
+        let mut slice = &self.0[72..];
+        slice.read_u32::<LittleEndian>().unwrap()
+    }
+
+    /// Whether the header hash is at or below the target encoded in `nBits`.
+    /// Negative, zero and overflowing targets never pass.
+    pub fn check_pow(&self) -> bool {
+        let bits = self.bits();
+        let mantissa = bits & 0x007fffff;
+        if mantissa == 0 || bits & 0x00800000 != 0 {
+            return false;
+        }
+
+        // Little endian 256 bit target, like the hash
+        let mut target = [0u8; 32];
+        let exponent = (bits >> 24) as usize;
+        for i in 0..3 {
+            // Bytes shifted out below the target are dropped
+            if exponent + i < 3 {
+                continue;
+            }
+            let byte = (mantissa >> (8 * i)) as u8;
+            match target.get_mut(exponent + i - 3) {
+                Some(b) => *b = byte,
+                None if byte != 0 => return false,
+                None => {}
+            }
+        }
+        if target.iter().all(|b| *b == 0) {
+            return false;
+        }
+
+        let hash = self.cur_hash();
+        for i in (0..32).rev() {
+            if hash[i] != target[i] {
+                return hash[i] < target[i];
+            }
+        }
+        true
+    }
+
+    /// Expected number of hashes needed to find this block, 2^256 / target.
+    pub fn work(&self) -> u128 {
+        work(self.bits())
+    }
+}
+
+/// Expected number of hashes needed to find a block with the `nBits` target,
+/// 2^256 / target. Work beyond 128 bits saturates, which no real network
+/// gets close to.
+pub fn work(bits: u32) -> u128 {
+    let mantissa = (bits & 0x007fffff) as u128;
+    if mantissa == 0 || bits & 0x00800000 != 0 {
+        return 0;
+    }
+
+    // target = mantissa * 2^shift, so work = 2^(256 - shift) / mantissa
+    let shift = 8 * ((bits >> 24) as i32 - 3);
+    let exp = 256 - shift;
+    if exp <= 0 {
+        0
+    } else if exp < 128 {
+        (1u128 << exp) / mantissa
+    } else {
+        let work = (1u128 << 127) / mantissa;
+        if exp - 127 < work.leading_zeros() as i32 {
+            work << (exp - 127)
+        } else {
+            u128::MAX
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use byteorder::{ByteOrder, LittleEndian};
+use std::cmp;
 
 use parser::{EofError, Result};
 
@@ -19,6 +20,12 @@ pub fn read_slice<'a>(slice: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
     }
 }
 
+/// Capacity to reserve for `count` items of at least `min_size` bytes read
+/// from `slice`, so a corrupted count can not reserve more than the data holds.
+pub fn capacity(count: u64, slice: &[u8], min_size: usize) -> usize {
+    cmp::min(count, (slice.len() / min_size) as u64) as usize
+}
+
 pub fn read_u8(slice: &mut &[u8]) -> Result<u8> {
     if slice.len() == 0 {
         Err(EofError)
@@ -50,3 +57,37 @@ pub fn read_var_int(slice: &mut &[u8]) -> Result<u64> {
     };
     Ok(n)
 }
+
+/// Little-endian base-128 varint, as used by LevelDB.
+pub fn read_leb128(slice: &mut &[u8]) -> Result<u64> {
+    let mut n: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = read_u8(slice)?;
+        if shift > 63 {
+            return Err(EofError);
+        }
+        n |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+/// Bitcoin Core's VARINT: big-endian base-128 where every continuation
+/// byte also adds one, used by the block index, undo and chainstate data.
+pub fn read_msb_varint(slice: &mut &[u8]) -> Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let b = read_u8(slice)?;
+        if n > (u64::MAX >> 7) {
+            return Err(EofError);
+        }
+        n = (n << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+        n += 1;
+    }
+}
@@ -2,9 +2,15 @@
 pub mod buffer;
 
 pub mod address;
+pub mod bech32m;
 pub mod block;
 pub mod bytecode;
 pub mod hash;
 pub mod hash160;
+pub mod params;
 pub mod script;
+pub mod spend;
+pub mod taint;
 pub mod transaction;
+pub mod undo;
+pub mod utxo;
@@ -0,0 +1,176 @@
+use bitcoin_bech32::constants::Network;
+
+use blockchain::hash::Hash;
+
+/// Network specific constants used while reading blocks and scripts.
+#[derive(PartialEq, Eq, Debug)]
+pub struct ChainParams {
+    pub name: &'static str,
+    /// Message start bytes preceding every block in blk files
+    pub magic: u32,
+    pub pubkey_address_version: u8,
+    pub script_address_version: u8,
+    pub bech32_network: Network,
+    /// Blocks older than these timestamps are not checked for P2SH and segwit outputs
+    pub p2sh_timestamp: u32,
+    pub segwit_timestamp: u32,
+    /// Default port of the bitcoind JSON-RPC server
+    pub rpc_port: u16,
+    pub p2p_port: u16,
+    /// Number of blocks after which the block subsidy is halved
+    pub subsidy_halving_interval: usize,
+    /// Hash of the genesis block in internal byte order
+    pub genesis_hash: Hash,
+}
+
+pub static MAINNET: ChainParams = ChainParams {
+    name: "mainnet",
+    magic: 0xd9b4bef9,
+    pubkey_address_version: 0x00,
+    script_address_version: 0x05,
+    bech32_network: Network::Bitcoin,
+    p2sh_timestamp: 1333238400,
+    segwit_timestamp: 1503539857,
+    rpc_port: 8332,
+    p2p_port: 8333,
+    subsidy_halving_interval: 210_000,
+    genesis_hash: Hash([
+        0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7,
+        0x4f, 0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ]),
+};
+
+pub static TESTNET: ChainParams = ChainParams {
+    name: "testnet",
+    magic: 0x0709110b,
+    pubkey_address_version: 0x6f,
+    script_address_version: 0xc4,
+    bech32_network: Network::Testnet,
+    p2sh_timestamp: 1333238400,
+    // BIP9 start time of the segwit deployment
+    segwit_timestamp: 1462060800,
+    rpc_port: 18332,
+    p2p_port: 18333,
+    subsidy_halving_interval: 210_000,
+    genesis_hash: Hash([
+        0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3,
+        0xae, 0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00,
+        0x00, 0x00,
+    ]),
+};
+
+pub static SIGNET: ChainParams = ChainParams {
+    name: "signet",
+    magic: 0x40cf030a,
+    pubkey_address_version: 0x6f,
+    script_address_version: 0xc4,
+    // Signet shares the "tb" human-readable part with testnet
+    bech32_network: Network::Testnet,
+    p2sh_timestamp: 0,
+    segwit_timestamp: 0,
+    rpc_port: 38332,
+    p2p_port: 38333,
+    subsidy_halving_interval: 210_000,
+    genesis_hash: Hash([
+        0xf6, 0x1e, 0xee, 0x3b, 0x63, 0xa3, 0x80, 0xa4, 0x77, 0xa0, 0x63, 0xaf, 0x32, 0xb2, 0xbb,
+        0xc9, 0x7c, 0x9f, 0xf9, 0xf0, 0x1f, 0x2c, 0x42, 0x25, 0xe9, 0x73, 0x98, 0x81, 0x08, 0x00,
+        0x00, 0x00,
+    ]),
+};
+
+pub static REGTEST: ChainParams = ChainParams {
+    name: "regtest",
+    magic: 0xdab5bffa,
+    pubkey_address_version: 0x6f,
+    script_address_version: 0xc4,
+    bech32_network: Network::Regtest,
+    p2sh_timestamp: 0,
+    segwit_timestamp: 0,
+    rpc_port: 18443,
+    p2p_port: 18444,
+    subsidy_halving_interval: 150,
+    genesis_hash: Hash([
+        0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b,
+        0xbf, 0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88,
+        0x91, 0x0f,
+    ]),
+};
+
+pub static NETWORKS: [&ChainParams; 4] = [&MAINNET, &TESTNET, &SIGNET, &REGTEST];
+
+impl ChainParams {
+    pub fn from_name(name: &str) -> Option<&'static ChainParams> {
+        NETWORKS.iter().find(|params| params.name == name).cloned()
+    }
+
+    /// New coins a block at `height` may create, in satoshis.
+    pub fn block_subsidy(&self, height: usize) -> u64 {
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        5_000_000_000 >> halvings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_bech32::constants::hrp;
+
+    /// Values of Bitcoin Core's chainparams.cpp and chainparamsbase.cpp.
+    #[test]
+    fn bitcoin_core_values() {
+        let expected = [
+            (
+                "mainnet",
+                [0xf9, 0xbe, 0xb4, 0xd9],
+                (0, 5),
+                "bc",
+                (8332, 8333),
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            ),
+            (
+                "testnet",
+                [0x0b, 0x11, 0x09, 0x07],
+                (111, 196),
+                "tb",
+                (18332, 18333),
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            ),
+            (
+                "signet",
+                [0x0a, 0x03, 0xcf, 0x40],
+                (111, 196),
+                "tb",
+                (38332, 38333),
+                "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            ),
+            (
+                "regtest",
+                [0xfa, 0xbf, 0xb5, 0xda],
+                (111, 196),
+                "bcrt",
+                (18443, 18444),
+                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            ),
+        ];
+
+        for &(name, magic, versions, bech32_hrp, ports, genesis) in expected.iter() {
+            let params = ChainParams::from_name(name).unwrap();
+            assert_eq!(params.name, name);
+            assert_eq!(params.magic.to_le_bytes(), magic, "{}", name);
+            assert_eq!(
+                (params.pubkey_address_version, params.script_address_version),
+                versions,
+                "{}",
+                name
+            );
+            assert_eq!(hrp(&params.bech32_network), bech32_hrp, "{}", name);
+            assert_eq!((params.rpc_port, params.p2p_port), ports, "{}", name);
+            assert_eq!(params.genesis_hash.to_string(), genesis, "{}", name);
+        }
+        assert_eq!(ChainParams::from_name("testnet4"), None);
+    }
+}
@@ -1,9 +1,9 @@
-use bitcoin_bech32::constants::Network;
 use bitcoin_bech32::WitnessProgram;
 use std::fmt;
 
 use blockchain::bytecode::Bytecode;
 use blockchain::bytecode::Bytecode::*;
+use blockchain::params::ChainParams;
 use parser::{ParseError, ParseResult};
 
 #[derive(Clone)]
@@ -14,10 +14,31 @@ pub enum ScriptType<'a> {
     Multisig(u32, Vec<&'a [u8]>),
     ScriptHash(&'a [u8; 20]),
     WitnessScriptHash(&'a [u8; 34]),
+    WitnessV1Taproot(&'a [u8; 32]),
+    /// Witness version 1 to 16 program not defined yet
+    WitnessUnknown(u8, &'a [u8]),
+    /// Provably unspendable, starting with OP_RETURN
+    NullData,
     Unknown(Script<'a>),
     Invalid,
 }
 
+/// Kind of a `ScriptType`, without the data borrowed from the script.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
+pub enum ScriptKind {
+    Pubkey,
+    PubkeyHash,
+    WitnessPubkeyHash,
+    Multisig,
+    ScriptHash,
+    WitnessScriptHash,
+    WitnessV1Taproot,
+    WitnessUnknown,
+    NullData,
+    Unknown,
+    Invalid,
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Copy)]
 pub struct Script<'a> {
     slice: &'a [u8],
@@ -33,14 +54,17 @@ struct ScriptIter<'a> {
 impl<'a> fmt::Debug for ScriptType<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let t = match self {
-            ScriptType::Pubkey(_) => ("Pubkey"),
-            ScriptType::PubkeyHash(_) => ("PubkeyHash"),
-            ScriptType::WitnessPubkeyHash(_) => ("WitnessPubkeyHash"),
-            ScriptType::Multisig(_, _) => ("Multisig"),
-            ScriptType::ScriptHash(_) => ("ScriptHash"),
-            ScriptType::WitnessScriptHash(_) => ("WitnessScriptHash"),
-            ScriptType::Unknown(_) => ("Unknown"),
-            ScriptType::Invalid => ("Invalid"),
+            ScriptType::Pubkey(_) => "Pubkey",
+            ScriptType::PubkeyHash(_) => "PubkeyHash",
+            ScriptType::WitnessPubkeyHash(_) => "WitnessPubkeyHash",
+            ScriptType::Multisig(_, _) => "Multisig",
+            ScriptType::ScriptHash(_) => "ScriptHash",
+            ScriptType::WitnessScriptHash(_) => "WitnessScriptHash",
+            ScriptType::WitnessV1Taproot(_) => "WitnessV1Taproot",
+            ScriptType::WitnessUnknown(_, _) => "WitnessUnknown",
+            ScriptType::NullData => "NullData",
+            ScriptType::Unknown(_) => "Unknown",
+            ScriptType::Invalid => "Invalid",
         };
 
         let mut d = f.debug_struct("ScriptType");
@@ -49,6 +73,24 @@ impl<'a> fmt::Debug for ScriptType<'a> {
     }
 }
 
+impl<'a> ScriptType<'a> {
+    pub fn kind(&self) -> ScriptKind {
+        match *self {
+            ScriptType::Pubkey(_) => ScriptKind::Pubkey,
+            ScriptType::PubkeyHash(_) => ScriptKind::PubkeyHash,
+            ScriptType::WitnessPubkeyHash(_) => ScriptKind::WitnessPubkeyHash,
+            ScriptType::Multisig(_, _) => ScriptKind::Multisig,
+            ScriptType::ScriptHash(_) => ScriptKind::ScriptHash,
+            ScriptType::WitnessScriptHash(_) => ScriptKind::WitnessScriptHash,
+            ScriptType::WitnessV1Taproot(_) => ScriptKind::WitnessV1Taproot,
+            ScriptType::WitnessUnknown(_, _) => ScriptKind::WitnessUnknown,
+            ScriptType::NullData => ScriptKind::NullData,
+            ScriptType::Unknown(_) => ScriptKind::Unknown,
+            ScriptType::Invalid => ScriptKind::Invalid,
+        }
+    }
+}
+
 impl<'a> Script<'a> {
     pub fn new(slice: &'a [u8], timestamp: u32) -> Script<'a> {
         Script { slice, timestamp }
@@ -65,18 +107,36 @@ impl<'a> Script<'a> {
         self.slice
     }
 
-    pub fn to_scripttype(&self) -> ScriptType<'a> {
+    /// Version and program of a witness output script (BIP141).
+    fn witness_program(&self) -> Option<(u8, &'a [u8])> {
+        let len = self.slice.len();
+        if !(4..=42).contains(&len) || self.slice[1] as usize != len - 2 {
+            return None;
+        }
+        match self.slice[0] {
+            0x00 => Some((0, &self.slice[2..])),
+            // OP_1 to OP_16
+            op @ 0x51..=0x60 => Some((op - 0x50, &self.slice[2..])),
+            _ => None,
+        }
+    }
+
+    pub fn to_scripttype(&self, params: &ChainParams) -> ScriptType<'a> {
+        if self.slice.first() == Some(&0x6a) {
+            return ScriptType::NullData;
+        }
+
         let mut skipped_iter = self.iter();
         skipped_iter.skip_nops();
         let skipped_slice = skipped_iter.slice;
 
         match skipped_slice.len() {
             22 => {
-                if self.timestamp >= 1503539857 {
+                if self.timestamp >= params.segwit_timestamp {
                     if &self.slice[..2] == &[0x00, 0x14] {
                         return match WitnessProgram::from_scriptpubkey(
                             &self.slice[..22],
-                            Network::Bitcoin,
+                            params.bech32_network,
                         ) {
                             Ok(_) => ScriptType::WitnessPubkeyHash(array_ref!(&self.slice, 0, 22)),
                             Err(_) => ScriptType::Invalid,
@@ -84,6 +144,13 @@ impl<'a> Script<'a> {
                     }
                 }
             }
+            23 => {
+                if self.timestamp >= params.p2sh_timestamp {
+                    if &self.slice[..2] == &[0xa9, 0x14] && self.slice[22] == 0x87 {
+                        return ScriptType::ScriptHash(array_ref!(self.slice, 2, 20));
+                    }
+                }
+            }
             25 => {
                 if &skipped_slice[..3] == &[0x76, 0xa9, 0x14]
                     && (&skipped_slice[23..] == &[0x88, 0xac]
@@ -91,11 +158,6 @@ impl<'a> Script<'a> {
                 {
                     return ScriptType::PubkeyHash(array_ref!(skipped_slice, 3, 20));
                 }
-                if self.timestamp >= 1333238400 {
-                    if &self.slice[..2] == &[0xa9, 0x14] && self.slice[22] == 0x87 {
-                        return ScriptType::ScriptHash(array_ref!(self.slice, 2, 20));
-                    }
-                }
             }
             26 => {
                 if &skipped_slice[..3] == &[0x76, 0xa9, 0x14]
@@ -105,11 +167,11 @@ impl<'a> Script<'a> {
                 }
             }
             34 => {
-                if self.timestamp >= 1503539857 {
+                if self.timestamp >= params.segwit_timestamp {
                     if &self.slice[..2] == &[0x00, 0x20] {
                         return match WitnessProgram::from_scriptpubkey(
                             &self.slice[..34],
-                            Network::Bitcoin,
+                            params.bech32_network,
                         ) {
                             Ok(_) => ScriptType::WitnessScriptHash(array_ref!(&self.slice, 0, 34)),
                             Err(_) => ScriptType::Invalid,
@@ -140,6 +202,17 @@ impl<'a> Script<'a> {
             _ => {}
         }
 
+        if self.timestamp >= params.segwit_timestamp {
+            if let Some((version, program)) = self.witness_program() {
+                if version == 1 && program.len() == 32 {
+                    return ScriptType::WitnessV1Taproot(array_ref!(program, 0, 32));
+                }
+                if version > 0 {
+                    return ScriptType::WitnessUnknown(version, program);
+                }
+            }
+        }
+
         if let Ok(res) = skipped_iter.clone().read_pay_to_multisig() {
             return res;
         }
@@ -280,3 +353,73 @@ pub fn is_valid_pubkey(pubkey: &[u8]) -> bool {
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::MAINNET;
+    use rustc_serialize::hex::FromHex;
+
+    const P2SH: &str = "a914e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a87";
+    const P2PKH: &str = "76a914000102030405060708090a0b0c0d0e0f1011121388ac";
+    const P2TR: &str = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn kind(script: &str, timestamp: u32) -> ScriptKind {
+        let data = script.from_hex().unwrap();
+        Script::new(&data, timestamp).to_scripttype(&MAINNET).kind()
+    }
+
+    #[test]
+    fn script_hash() {
+        let data = P2SH.from_hex().unwrap();
+        match Script::new(&data, MAINNET.p2sh_timestamp).to_scripttype(&MAINNET) {
+            ScriptType::ScriptHash(hash) => assert_eq!(&hash[..], &data[2..22]),
+            other => panic!("classified as {:?}", other),
+        }
+        assert_eq!(kind(P2SH, MAINNET.p2sh_timestamp - 1), ScriptKind::Unknown);
+        assert_eq!(kind(P2PKH, 0), ScriptKind::PubkeyHash);
+    }
+
+    #[test]
+    fn taproot() {
+        let data = P2TR.from_hex().unwrap();
+        match Script::new(&data, MAINNET.segwit_timestamp).to_scripttype(&MAINNET) {
+            ScriptType::WitnessV1Taproot(program) => assert_eq!(&program[..], &data[2..]),
+            other => panic!("classified as {:?}", other),
+        }
+        assert_eq!(
+            kind(P2TR, MAINNET.segwit_timestamp - 1),
+            ScriptKind::Unknown
+        );
+    }
+
+    #[test]
+    fn witness_unknown() {
+        // Version 16 with a 2 byte program, version 1 with a 20 byte one
+        for &(script, version, len) in [
+            ("6002751e", 16, 2),
+            ("5114751e76e8199196d454941c45d1b3a323f1433bd6", 1, 20),
+            ("5210751e76e8199196d454941c45d1b3a323", 2, 16),
+        ]
+        .iter()
+        {
+            let data = script.from_hex().unwrap();
+            match Script::new(&data, MAINNET.segwit_timestamp).to_scripttype(&MAINNET) {
+                ScriptType::WitnessUnknown(v, program) => {
+                    assert_eq!(v, version);
+                    assert_eq!(program.len(), len);
+                }
+                other => panic!("{} classified as {:?}", script, other),
+            }
+            assert_ne!(
+                kind(script, MAINNET.segwit_timestamp - 1),
+                ScriptKind::WitnessUnknown
+            );
+        }
+        // Programs must have 2 to 40 bytes
+        assert_eq!(
+            kind("6001ff", MAINNET.segwit_timestamp),
+            ScriptKind::Unknown
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use blockchain::bytecode::Bytecode;
+use blockchain::bytecode::Bytecode::*;
+use blockchain::hash::ZERO_HASH;
+use blockchain::script::is_valid_pubkey;
+use blockchain::transaction::TransactionInput;
+use parser::ParseError;
+
+/// How an input satisfied the output it spends, as far as its scriptSig and
+/// witness tell.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
+pub enum SpendKind {
+    Coinbase,
+    Pubkey,
+    PubkeyHash,
+    /// Bare multisig, not wrapped in P2SH
+    Multisig,
+    ScriptHash,
+    /// P2WPKH wrapped in P2SH
+    NestedWitnessPubkeyHash,
+    /// P2WSH wrapped in P2SH
+    NestedWitnessScriptHash,
+    WitnessPubkeyHash,
+    WitnessScriptHash,
+    TaprootKeyPath,
+    TaprootScriptPath,
+    Unknown,
+}
+
+/// Classification of an input with what it revealed about the spent output.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Spend<'a> {
+    pub kind: SpendKind,
+    /// Redeem script, witness script or tapscript
+    pub script: Option<&'a [u8]>,
+    /// Public keys of the scriptSig, the witness and the revealed script,
+    /// 32 byte x-only keys for Taproot
+    pub pubkeys: Vec<&'a [u8]>,
+}
+
+impl<'a> Spend<'a> {
+    fn new(kind: SpendKind, script: Option<&'a [u8]>, pubkeys: Vec<&'a [u8]>) -> Self {
+        Spend {
+            kind,
+            script,
+            pubkeys,
+        }
+    }
+
+    pub fn classify(input: &TransactionInput<'a>) -> Spend<'a> {
+        if *input.prev_hash == ZERO_HASH {
+            return Spend::new(SpendKind::Coinbase, None, Vec::new());
+        }
+        let pushes = match read_pushes(input.script.as_slice()) {
+            Some(pushes) => pushes,
+            None => return Spend::new(SpendKind::Unknown, None, Vec::new()),
+        };
+        if input.witness.is_empty() {
+            return classify_script_sig(&pushes);
+        }
+
+        match pushes.len() {
+            0 => classify_witness(&input.witness),
+            // The redeem script is a version 0 witness program, Taproot
+            // outputs can't be wrapped in P2SH
+            1 => {
+                let mut spend = classify_witness_v0(&input.witness);
+                spend.kind = match (pushes[0].len(), spend.kind) {
+                    (22, SpendKind::WitnessPubkeyHash) => SpendKind::NestedWitnessPubkeyHash,
+                    (34, SpendKind::WitnessScriptHash) => SpendKind::NestedWitnessScriptHash,
+                    _ => SpendKind::Unknown,
+                };
+                spend
+            }
+            _ => Spend::new(SpendKind::Unknown, None, Vec::new()),
+        }
+    }
+}
+
+fn classify_script_sig<'a>(pushes: &[&'a [u8]]) -> Spend<'a> {
+    match pushes.len() {
+        0 | 1 if pushes.iter().all(|p| p.is_empty()) => {
+            Spend::new(SpendKind::Unknown, None, Vec::new())
+        }
+        1 if is_signature(pushes[0]) => Spend::new(SpendKind::Pubkey, None, Vec::new()),
+        2 if is_signature(pushes[0]) && is_valid_pubkey(pushes[1]) => {
+            Spend::new(SpendKind::PubkeyHash, None, vec![pushes[1]])
+        }
+        // OP_CHECKMULTISIG pops one item more than it uses
+        _ if pushes[0].is_empty() && pushes[1..].iter().all(|p| is_signature(p)) => {
+            Spend::new(SpendKind::Multisig, None, Vec::new())
+        }
+        _ if !pushes[pushes.len() - 1].is_empty() => {
+            let script = pushes[pushes.len() - 1];
+            Spend::new(
+                SpendKind::ScriptHash,
+                Some(script),
+                script_pubkeys(script, 0),
+            )
+        }
+        _ => Spend::new(SpendKind::Unknown, None, Vec::new()),
+    }
+}
+
+fn classify_witness<'a>(witness: &[&'a [u8]]) -> Spend<'a> {
+    let spend = classify_witness_v0(witness);
+    if spend.kind == SpendKind::WitnessPubkeyHash {
+        return spend;
+    }
+
+    // Taproot witnesses may end with an annex (BIP341). A witness script
+    // starting with OP_RESERVED fails, so P2WSH witnesses never do.
+    let mut stack = witness;
+    if stack.len() >= 2 && stack[stack.len() - 1].first() == Some(&0x50) {
+        stack = &stack[..stack.len() - 1];
+    }
+    // Taken for a key path spend even when it is a P2WSH witness script of
+    // 64 bytes without arguments, the spent output tells them apart.
+    if stack.len() == 1 && is_schnorr_signature(stack[0]) {
+        return Spend::new(SpendKind::TaprootKeyPath, None, Vec::new());
+    }
+    if stack.len() >= 2 {
+        let control = stack[stack.len() - 1];
+        if control.len() >= 33
+            && (control.len() - 33).is_multiple_of(32)
+            && control[0] & 0xfe == 0xc0
+        {
+            let script = stack[stack.len() - 2];
+            let mut pubkeys = vec![&control[1..33]];
+            pubkeys.extend(script_pubkeys(script, 32));
+            return Spend::new(SpendKind::TaprootScriptPath, Some(script), pubkeys);
+        }
+    }
+    spend
+}
+
+fn classify_witness_v0<'a>(witness: &[&'a [u8]]) -> Spend<'a> {
+    if witness.len() == 2 && witness[1].len() == 33 && is_valid_pubkey(witness[1]) {
+        return Spend::new(SpendKind::WitnessPubkeyHash, None, vec![witness[1]]);
+    }
+
+    let script = witness[witness.len() - 1];
+    Spend::new(
+        SpendKind::WitnessScriptHash,
+        Some(script),
+        script_pubkeys(script, 0),
+    )
+}
+
+/// Items pushed by a push only script, `None` for other scripts.
+fn read_pushes(mut slice: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut pushes = Vec::new();
+    loop {
+        match Bytecode::read(&mut slice) {
+            Ok(OP_PUSH(data)) => pushes.push(data),
+            Err(ParseError::Eof) => return Some(pushes),
+            _ => return None,
+        }
+    }
+}
+
+/// Public keys pushed by a script, also pushes of `xonly_len` bytes when it
+/// is not zero.
+fn script_pubkeys(mut slice: &[u8], xonly_len: usize) -> Vec<&[u8]> {
+    let mut pubkeys = Vec::new();
+    loop {
+        match Bytecode::read(&mut slice) {
+            Ok(OP_PUSH(data))
+                if is_valid_pubkey(data) || xonly_len > 0 && data.len() == xonly_len =>
+            {
+                pubkeys.push(data)
+            }
+            Ok(_) => {}
+            Err(_) => return pubkeys,
+        }
+    }
+}
+
+/// DER encoded signature followed by the sighash type.
+fn is_signature(data: &[u8]) -> bool {
+    data.len() >= 9 && data.len() <= 73 && data[0] == 0x30 && data[1] as usize == data.len() - 3
+}
+
+/// BIP340 signature, followed by a sighash type other than the default one
+/// when it has 65 bytes.
+fn is_schnorr_signature(data: &[u8]) -> bool {
+    match data.len() {
+        64 => true,
+        65 => matches!(data[64], 0x01 | 0x02 | 0x03 | 0x81 | 0x82 | 0x83),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::transaction::TransactionView;
+    use rustc_serialize::hex::FromHex;
+
+    // Transactions signed with throwaway keys, one per kind of input
+
+    /// Spends a P2PKH output.
+    const P2PKH_TX: &str = "02000000014bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785\
+                            459a000000006a4730440220236bc64f6683c8a9e08bb30d59b548bfd3b610a95753c8\
+                            79ee46a1f990cc780c022014c0ac7e2587c3ffd8a4229b345d44280e8f97163451007b\
+                            516bff5758f1d28a0121030a7a29a4565e231e663118b08926cbfb27c13d76a5462733\
+                            3ea6e609f2f71809fdffffff01905f010000000000160014d03f83fcf1e21682ea7cae\
+                            8430389fec20e9d4f900000000";
+    /// Spends a P2WPKH output wrapped in P2SH.
+    const NESTED_P2WPKH_TX: &str = "02000000000101dbc1b4c900ffe48d575b5da5c638040125f65db0fe3e2449\
+                                    4b76ea986457d98600000000171600141ee0f5b27bfd33fa218fa31c2c8121\
+                                    e4094eb921fdffffff01905f010000000000160014d03f83fcf1e21682ea7c\
+                                    ae8430389fec20e9d4f902483045022100b068923c63aa815d92fa417d5c5d\
+                                    5a4597e452585c06604689f83af71cc0413e02200f22d67ba6c5391b8dcfb5\
+                                    4d9382824aee7636ced14e0c2f648a3230f4a3bd0b0121030a7a29a4565e23\
+                                    1e663118b08926cbfb27c13d76a54627333ea6e609f2f7180900000000";
+    /// Spends a 2-of-3 multisig P2WSH output wrapped in P2SH.
+    const NESTED_P2WSH_TX: &str = "02000000000101084fed08b978af4d7d196a7446a86b58009e636b611db162\
+                                   11b65a9aadff29c500000000232200206d25bc6dea1ae446c1effcd979a2fb\
+                                   abf13feacb592aa4ea87a0c4017d0a08dffdffffff01905f01000000000016\
+                                   0014d03f83fcf1e21682ea7cae8430389fec20e9d4f90400483045022100a8\
+                                   4045c11abf2d9852bf26bf104d8e4e744ec134471fb373d0a84e4f4acfaf31\
+                                   02200670437f8848714530f0451b55415eec22739f35bbb5f64fc12367e817\
+                                   083184014730440220525438bb814c12e7a59ba7e595694ec3a0a55be75d7c\
+                                   595f2edbc0ab3cbd871c02200efc5907a4fde1a2e73dfe7af431b4f322748b\
+                                   ea0bfaba36d272197be181d14101695221030a7a29a4565e231e663118b089\
+                                   26cbfb27c13d76a54627333ea6e609f2f71809210215c7f5c207c6a484b0a2\
+                                   efa5e141827c457d38ea16bcae8d20b14a950a4d7ae32102ef7bd14418fa01\
+                                   83ae7133249849ebda4acbbe554c5e98ab95b720b794e88d1f53ae00000000";
+    /// Spends a P2WPKH output.
+    const P2WPKH_TX: &str = "02000000000101e52d9c508c502347344d8c07ad91cbd6068afc75ff6292f062a09c\
+                             a381c89e710000000000fdffffff01905f010000000000160014d03f83fcf1e21682\
+                             ea7cae8430389fec20e9d4f902483045022100b655bec4f7d6a6c313ef83651f9a7f\
+                             4cf982b7be7cbd96c175f8af68d5b8e82802203c908907ee97fd5a8654192ee83233\
+                             6eddace10683fa6cb07e5f90b1eb05aa2f0121030a7a29a4565e231e663118b08926\
+                             cbfb27c13d76a54627333ea6e609f2f7180900000000";
+    /// Spends a 2-of-3 multisig P2WSH output.
+    const P2WSH_TX: &str = "02000000000101e77b9a9ae9e30b0dbdb6f510a264ef9de781501d7b6b92ae89eb059c\
+                            5ab743db0000000000fdffffff01905f010000000000160014d03f83fcf1e21682ea7c\
+                            ae8430389fec20e9d4f904004730440220532499509c1fed3606d4803ba17b226a2a5e\
+                            9e67939a5246180c1103c185ff620220706172ec7490a85f0edb44ba54b32759dc486b\
+                            5188af498875506136a070e28d014730440220579b578211f5322ca411669ae4976bc8\
+                            89dcd2cc73ea85470c9f07495442de8502206a15a99b3cfd8f0742c1ebefe915869860\
+                            be1ec7ee403cdb99ce84fd03f0434e01695221030a7a29a4565e231e663118b08926cb\
+                            fb27c13d76a54627333ea6e609f2f71809210215c7f5c207c6a484b0a2efa5e141827c\
+                            457d38ea16bcae8d20b14a950a4d7ae32102ef7bd14418fa0183ae7133249849ebda4a\
+                            cbbe554c5e98ab95b720b794e88d1f53ae00000000";
+    /// Spends a Taproot output with a key path signature.
+    const TAPROOT_KEY_PATH_TX: &str = "0200000000010167586e98fad27da0b9968bc039a1ef34c939b9b8e523\
+                                       a8bef89d478608c5ecf60000000000fdffffff01905f01000000000016\
+                                       0014d03f83fcf1e21682ea7cae8430389fec20e9d4f901403f37c72938\
+                                       cd9f993b805ce79fcc7434b857292abb1769022fc61c16a5a6cca5bb86\
+                                       f82d504098c333005866dc295dcaa50595ee00bedde8d313e4cdbf8a54\
+                                       a200000000";
+    /// Spends a Taproot output with a `SIGHASH_ALL` key path signature and an annex.
+    const TAPROOT_KEY_PATH_ANNEX_TX: &str = "02000000000101ca358758f6d27e6cf45272937977a748fd8839\
+                                             1db679ceda7dc7bf1f005ee8790000000000fdffffff01905f01\
+                                             0000000000160014d03f83fcf1e21682ea7cae8430389fec20e9\
+                                             d4f902415925a5f77dcad69c7c536505ab197862660f6b5ba4fe\
+                                             1f4a516950ae866c76bf77a9ae6db6cc7e3428c2f6e3d8723e78\
+                                             00bdada57203d53cd1c3bc4e3a111ea7010650616e6e65780000\
+                                             0000";
+    /// Spends a Taproot output with a `<key> OP_CHECKSIG` leaf at depth 1.
+    const TAPROOT_SCRIPT_PATH_TX: &str = "02000000000101beead77994cf573341ec17b58bbf7eb34d2711c993\
+                                          c1d976b128b3188dc1829a0000000000fdffffff01905f0100000000\
+                                          00160014d03f83fcf1e21682ea7cae8430389fec20e9d4f9034004d0\
+                                          98a55b493a7df122c9bb99777bbd85257de4adf52a37cf8be708c85d\
+                                          50ebc9fd72c8353659a6150ea47f40b474463ce7406abb938c942f4d\
+                                          ac2b1eaacf18222015c7f5c207c6a484b0a2efa5e141827c457d38ea\
+                                          16bcae8d20b14a950a4d7ae3ac41c0ef7bd14418fa0183ae71332498\
+                                          49ebda4acbbe554c5e98ab95b720b794e88d1fa85b2107f791b26a84\
+                                          e7586c28cec7cb61202ed3d01944d832500f363782d67500000000";
+    /// Spends a bare 1-of-2 multisig output.
+    const BARE_MULTISIG_TX: &str = "02000000012b4c342f5433ebe591a1da77e013d1b72475562d48578dca8b84\
+                                    bac6651c3cb9000000004900473044022059a67144911f9f05ee5168a4f5c8\
+                                    65621507f94e2bc92788b8f55a2be2b1c0d8022004870de464ab0c00b7cbc2\
+                                    cf3d706345043397ca732d8e4c42df7bd1deb8c01301fdffffff01905f0100\
+                                    00000000160014d03f83fcf1e21682ea7cae8430389fec20e9d4f900000000";
+
+    const PUBKEY: &str = "030a7a29a4565e231e663118b08926cbfb27c13d76a54627333ea6e609f2f71809";
+    /// `OP_2 <PUBKEY> <key> <key> OP_3 OP_CHECKMULTISIG`
+    const MULTISIG_SCRIPT: &str = "5221030a7a29a4565e231e663118b08926cbfb27c13d76a54627333ea6e609f2f\
+                                   71809210215c7f5c207c6a484b0a2efa5e141827c457d38ea16bcae8d20b14a95\
+                                   0a4d7ae32102ef7bd14418fa0183ae7133249849ebda4acbbe554c5e98ab95b72\
+                                   0b794e88d1f53ae";
+
+    fn first_input(data: &[u8]) -> TransactionInput<'_> {
+        let mut slice = data;
+        TransactionView::read(&mut slice, 0)
+            .unwrap()
+            .inputs
+            .remove(0)
+    }
+
+    #[test]
+    fn classify_inputs() {
+        let vectors = [
+            (P2PKH_TX, SpendKind::PubkeyHash),
+            (NESTED_P2WPKH_TX, SpendKind::NestedWitnessPubkeyHash),
+            (NESTED_P2WSH_TX, SpendKind::NestedWitnessScriptHash),
+            (P2WPKH_TX, SpendKind::WitnessPubkeyHash),
+            (P2WSH_TX, SpendKind::WitnessScriptHash),
+            (TAPROOT_KEY_PATH_TX, SpendKind::TaprootKeyPath),
+            (TAPROOT_KEY_PATH_ANNEX_TX, SpendKind::TaprootKeyPath),
+            (TAPROOT_SCRIPT_PATH_TX, SpendKind::TaprootScriptPath),
+            (BARE_MULTISIG_TX, SpendKind::Multisig),
+        ];
+        for &(tx, kind) in vectors.iter() {
+            let data = tx.from_hex().unwrap();
+            assert_eq!(Spend::classify(&first_input(&data)).kind, kind);
+        }
+    }
+
+    #[test]
+    fn revealed_pubkeys() {
+        let pubkey = PUBKEY.from_hex().unwrap();
+        for tx in &[P2PKH_TX, NESTED_P2WPKH_TX, P2WPKH_TX] {
+            let data = tx.from_hex().unwrap();
+            let spend = Spend::classify(&first_input(&data));
+            assert_eq!(spend.script, None);
+            assert_eq!(spend.pubkeys, vec![&pubkey[..]]);
+        }
+
+        let multisig = MULTISIG_SCRIPT.from_hex().unwrap();
+        for tx in &[NESTED_P2WSH_TX, P2WSH_TX] {
+            let data = tx.from_hex().unwrap();
+            let spend = Spend::classify(&first_input(&data));
+            assert_eq!(spend.script, Some(&multisig[..]));
+            assert_eq!(spend.pubkeys.len(), 3);
+            assert_eq!(spend.pubkeys[0], &pubkey[..]);
+        }
+
+        let data = TAPROOT_SCRIPT_PATH_TX.from_hex().unwrap();
+        let spend = Spend::classify(&first_input(&data));
+        let leaf = "2015c7f5c207c6a484b0a2efa5e141827c457d38ea16bcae8d20b14a950a4d7ae3ac"
+            .from_hex()
+            .unwrap();
+        assert_eq!(spend.script, Some(&leaf[..]));
+        // Internal key of the control block, then the key of the leaf
+        assert_eq!(
+            spend.pubkeys,
+            vec![
+                &"ef7bd14418fa0183ae7133249849ebda4acbbe554c5e98ab95b720b794e88d1f"
+                    .from_hex()
+                    .unwrap()[..],
+                &leaf[1..33],
+            ]
+        );
+
+        let data = TAPROOT_KEY_PATH_ANNEX_TX.from_hex().unwrap();
+        let spend = Spend::classify(&first_input(&data));
+        assert_eq!(spend.script, None);
+        assert!(spend.pubkeys.is_empty());
+    }
+
+    #[test]
+    fn lone_witness_script() {
+        // `<60 bytes> OP_DROP OP_1` and `<61 bytes> OP_DROP OP_1`
+        let mut script64 = vec![0x4c, 60];
+        script64.extend_from_slice(&[0; 60]);
+        script64.extend_from_slice(&[0x75, 0x51]);
+        let mut script65 = vec![0x4c, 61];
+        script65.extend_from_slice(&[0; 61]);
+        script65.extend_from_slice(&[0x75, 0x51]);
+
+        let data = P2WSH_TX.from_hex().unwrap();
+        let mut input = first_input(&data);
+        // Can't be told apart from a key path signature
+        input.witness = vec![&script64];
+        assert_eq!(Spend::classify(&input).kind, SpendKind::TaprootKeyPath);
+        // Ends with an invalid sighash type
+        input.witness = vec![&script65];
+        let spend = Spend::classify(&input);
+        assert_eq!(spend.kind, SpendKind::WitnessScriptHash);
+        assert_eq!(spend.script, Some(&script65[..]));
+
+        // Only version 0 programs are wrapped in P2SH
+        let data = NESTED_P2WSH_TX.from_hex().unwrap();
+        let mut input = first_input(&data);
+        input.witness = vec![&script64];
+        let spend = Spend::classify(&input);
+        assert_eq!(spend.kind, SpendKind::NestedWitnessScriptHash);
+        assert_eq!(spend.script, Some(&script64[..]));
+    }
+
+    #[test]
+    fn annex_like_witness_script() {
+        // A witness script starting with OP_RESERVED, after a signature
+        let data = P2WSH_TX.from_hex().unwrap();
+        let mut input = first_input(&data);
+        let signature = input.witness[1];
+        let script = [0x50, 0x51];
+        input.witness = vec![signature, &script];
+        let spend = Spend::classify(&input);
+        assert_eq!(spend.kind, SpendKind::WitnessScriptHash);
+        assert_eq!(spend.script, Some(&script[..]));
+
+        // Key path signature with the default sighash type spelled out
+        let data = TAPROOT_KEY_PATH_ANNEX_TX.from_hex().unwrap();
+        let mut input = first_input(&data);
+        let mut signature = input.witness[0].to_vec();
+        signature[64] = 0x00;
+        input.witness[0] = &signature;
+        assert_eq!(Spend::classify(&input).kind, SpendKind::WitnessScriptHash);
+    }
+}
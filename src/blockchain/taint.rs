@@ -0,0 +1,111 @@
+use std::collections::{HashMap, VecDeque};
+
+use blockchain::address::Taint;
+use blockchain::hash::Hash;
+use blockchain::transaction::{Input, Output};
+
+/// Moves taints from the inputs of transactions to their outputs, starting
+/// with the outputs of the input file's transactions.
+pub struct TaintTracker {
+    /// Taints of transactions whose outputs have not been seen yet
+    start_txs: HashMap<Hash, VecDeque<Taint>>,
+}
+
+impl TaintTracker {
+    pub fn new() -> Self {
+        Self::with_start_txs(HashMap::new())
+    }
+
+    pub fn with_start_txs(start_txs: HashMap<Hash, VecDeque<Taint>>) -> Self {
+        TaintTracker { start_txs }
+    }
+
+    pub fn start_txs(&self) -> &HashMap<Hash, VecDeque<Taint>> {
+        &self.start_txs
+    }
+
+    /// Taints the outputs of `txid` with `taints`, unless it already is a
+    /// start transaction. Returns whether it was added.
+    pub fn add_start_tx(&mut self, txid: Hash, taints: VecDeque<Taint>) -> bool {
+        if self.start_txs.contains_key(&txid) {
+            return false;
+        }
+        self.start_txs.insert(txid, taints);
+        true
+    }
+
+    /// Splits the taints of the resolved `inputs` over the addresses of
+    /// `outputs`, in order.
+    pub fn propagate(&mut self, txid: &Hash, inputs: &[Input], outputs: &mut [Output]) {
+        let mut cur_taints: VecDeque<Taint> = Default::default();
+        for input in inputs {
+            let amount = input.amount.unwrap_or(0);
+            for address in &input.addresses {
+                if let Some(ref taints) = address.taints {
+                    if let Some(taints) = is_taint(&mut taints.clone(), amount) {
+                        cur_taints.extend(taints);
+                    }
+                }
+            }
+        }
+
+        let mut remove = false;
+        for output in outputs.iter_mut() {
+            let amount = output.amount;
+            for address in output.addresses.iter_mut() {
+                if let Some(start_taints) = self.start_txs.get_mut(txid) {
+                    remove = true;
+                    address.taints = is_taint(start_taints, amount);
+                }
+                if let Some(taints) = is_taint(&mut cur_taints, amount) {
+                    if taints.iter().any(|t| t.label != 0) {
+                        address.taints = Some(taints);
+                    }
+                }
+            }
+        }
+        if remove {
+            self.start_txs.remove(txid);
+        }
+    }
+}
+
+impl Default for TaintTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_taint(taints: &mut VecDeque<Taint>, amount: u64) -> Option<VecDeque<Taint>> {
+    let mut remaining = amount;
+    let mut new_taints = VecDeque::new();
+
+    while remaining > 0 && !taints.is_empty() {
+        let mut taint = taints.pop_front().unwrap();
+        if remaining >= taint.amount {
+            remaining -= taint.amount;
+            new_taints.push_back(taint);
+        } else {
+            taint.amount -= remaining;
+            new_taints.push_back(Taint {
+                label: taint.label,
+                amount: remaining,
+            });
+            taints.push_front(taint);
+            remaining = 0;
+        }
+    }
+
+    if remaining > 0 {
+        new_taints.push_back(Taint {
+            label: 0,
+            amount: remaining,
+        });
+    }
+
+    if new_taints.is_empty() {
+        None
+    } else {
+        Some(new_taints)
+    }
+}
@@ -1,18 +1,21 @@
-use bitcoin_bech32::constants::Network;
+use bitcoin_bech32::constants::hrp;
 use bitcoin_bech32::WitnessProgram;
+use byteorder::{ByteOrder, LittleEndian};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use fasthash::{xx, RandomState};
-use std::collections::hash_map::Entry as HashEntry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::fmt;
-use vec_map::VecMap;
 
-use blockchain::address::{Address, Taint};
+use blockchain::address::{Address, P2PK_ADDRESS_VERSION, SCRIPT_ADDRESS_VERSION};
+use blockchain::bech32m;
 use blockchain::buffer::*;
 use blockchain::hash::*;
 use blockchain::hash160::Hash160;
+use blockchain::params::ChainParams;
 use blockchain::script::*;
+use blockchain::spend::{Spend, SpendKind};
+use blockchain::taint::TaintTracker;
+use blockchain::utxo::UtxoSet;
 use parser::{ParseError, ParseResult};
 
 #[derive(PartialEq, Eq, Clone)]
@@ -22,15 +25,56 @@ pub struct Transaction {
     pub inputs_count: u64,
     pub outputs_count: u64,
     pub lock_time: u32,
-    pub inputs: HashMap<Address, u64>,
-    pub outputs: HashMap<Address, u64>,
+    /// Hash including the witnesses, the txid for non-segwit transactions
+    pub wtxid: Hash,
+    /// Serialized size including the witnesses
+    pub size: usize,
+    /// Serialized size without the witnesses
+    pub stripped_size: usize,
+    /// Inputs in transaction order, including the coinbase input
+    pub inputs: Vec<Input>,
+    /// Outputs in transaction order
+    pub outputs: Vec<Output>,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+/// A transaction input with the outpoint it spends.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Input {
+    pub prev_hash: Hash,
+    pub prev_index: u32,
+    pub sequence: u32,
+    /// Addresses of the spent output, empty when it is not known
+    pub addresses: Vec<Address>,
+    /// Amount of the spent output, `None` when it is not known
+    pub amount: Option<u64>,
+    /// How the scriptSig and witness spend the output
+    pub spend_type: SpendKind,
+    /// Redeem script, witness script or tapscript revealed by the input
+    pub revealed_script: Option<Vec<u8>>,
+    /// Public keys revealed by the input
+    pub pubkeys: Vec<Vec<u8>>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Output {
+    pub index: u32,
+    pub amount: u64,
+    pub script_type: ScriptKind,
+    /// Addresses the output pays to, a pseudo-address for non-standard
+    /// scripts
+    pub addresses: Vec<Address>,
+    /// Public keys listed by the script
+    pub pubkeys: Vec<Vec<u8>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct TransactionInput<'a> {
     pub prev_hash: &'a Hash,
     pub prev_index: u32,
     pub script: Script<'a>,
+    pub sequence: u32,
+    /// Witness stack, empty for non-segwit inputs
+    pub witness: Vec<&'a [u8]>,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -47,7 +91,10 @@ impl fmt::Debug for Transaction {
         d.field("lock_time", &self.lock_time);
         d.field("inputs_count", &self.inputs_count);
         d.field("outputs_count", &self.outputs_count);
-        if self.inputs_count == 1 && self.inputs.len() == 0 {
+        d.field("wtxid", &self.wtxid);
+        d.field("weight", &self.weight());
+        d.field("vsize", &self.vsize());
+        if self.is_coinbase() {
             d.field("inputs", &"coinbase".to_string());
         } else {
             d.field("inputs", &self.inputs);
@@ -57,23 +104,54 @@ impl fmt::Debug for Transaction {
     }
 }
 
-impl Transaction {
-    pub fn read(
-        slice: &mut &[u8],
-        timestamp: u32,
-        output_items: &mut HashMap<Hash, VecMap<Vec<(Address, u64)>>, RandomState<xx::Hash64>>,
-        start_txs: &mut HashMap<Hash, VecDeque<Taint>>,
-    ) -> ParseResult<Transaction> {
+/// A transaction as serialized, with the scripts borrowed from the block
+/// data. Reading it has no side effects, so it can be done on its own.
+#[derive(Clone, Debug)]
+pub struct TransactionView<'a> {
+    pub version: u32,
+    pub txid: Hash,
+    pub wtxid: Hash,
+    pub size: usize,
+    pub stripped_size: usize,
+    pub inputs: Vec<TransactionInput<'a>>,
+    pub outputs: Vec<TransactionOutput<'a>>,
+    pub lock_time: u32,
+}
+
+/// A transaction decoded without touching the unspent outputs, so it can be
+/// done on any thread.
+#[derive(Clone, Debug)]
+pub struct DecodedTransaction {
+    pub version: u32,
+    pub txid: Hash,
+    pub inputs_count: u64,
+    pub outputs_count: u64,
+    pub lock_time: u32,
+    pub wtxid: Hash,
+    pub size: usize,
+    pub stripped_size: usize,
+    /// Inputs with the outpoints they spend, not resolved yet
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+    /// Amount and addresses of the outputs spent by each input, set when read
+    /// together with the block's undo data. Only tainted outputs are kept in
+    /// the `UtxoSet` for such transactions.
+    pub spent: Option<Vec<(u64, Vec<Address>)>>,
+}
+
+impl<'a> TransactionView<'a> {
+    /// Reads a transaction, its scripts are classified as of `timestamp`.
+    pub fn read(slice: &mut &'a [u8], timestamp: u32) -> ParseResult<TransactionView<'a>> {
+        let data = *slice;
         let mut tx_hash = [0u8; 32];
         let mut sha256_hasher1 = Sha256::new();
         let mut sha256_hasher2 = sha256_hasher1;
-        let mut inputs = HashMap::<Address, u64>::new();
-        let mut outputs = HashMap::<Address, u64>::new();
 
-        sha256_hasher1.input(&slice[..4]);
-        let version = read_u32(slice)?;
+        let version_bytes = read_slice(slice, 4)?;
+        sha256_hasher1.input(version_bytes);
+        let version = LittleEndian::read_u32(version_bytes);
 
-        let marker = slice[0];
+        let marker = *slice.first().ok_or(ParseError::Eof)?;
         let inputs_count: u64;
         let mut slice_inputs_and_outputs = *slice;
         if marker == 0x00 {
@@ -91,47 +169,16 @@ impl Transaction {
         }
 
         // Read the inputs
-        let mut cur_taints: VecDeque<Taint> = Default::default();
+        let mut inputs = Vec::with_capacity(capacity(inputs_count, slice, 41));
         for _ in 0..inputs_count {
-            let txin = TransactionInput::read(slice, timestamp)?;
-            let mut output_item = None;
-            if let HashEntry::Occupied(mut occupied) = output_items.entry(*txin.prev_hash) {
-                output_item = occupied.get_mut().remove(txin.prev_index as usize);
-                if occupied.get().len() == 0 {
-                    occupied.remove();
-                }
-            }
-
-            if txin.prev_hash == &ZERO_HASH {
-                continue;
-            }
-
-            match output_item {
-                Some(output) => {
-                    for (mut address, amount) in output {
-                        let a = address.clone();
-                        match address.taints {
-                            Some(mut taint) => {
-                                address.taints = is_taint(&mut taint, amount);
-                                for t in address.taints.unwrap() {
-                                    cur_taints.push_back(t);
-                                }
-                            }
-                            None => {}
-                        }
-                        inputs.insert(a.to_owned(), amount);
-                    }
-                }
-                None => {}
-            }
+            inputs.push(TransactionInput::read(slice, timestamp)?);
         }
 
         // Read the outputs
         let outputs_count = read_var_int(slice)?;
-        let mut raw_outputs: Vec<TransactionOutput> = Vec::new();
+        let mut outputs = Vec::with_capacity(capacity(outputs_count, slice, 9));
         for _ in 0..outputs_count {
-            let txout = TransactionOutput::read(slice, timestamp)?;
-            raw_outputs.push(txout);
+            outputs.push(TransactionOutput::read(slice, timestamp)?);
         }
 
         // Hash the transaction data before the witnesses
@@ -139,112 +186,239 @@ impl Transaction {
         sha256_hasher1.input(read_slice(&mut slice_inputs_and_outputs, slice_len)?);
 
         // Read the witnesses
+        let witnesses_len = slice.len();
         if marker == 0x00 {
-            for _ in 0..inputs_count {
+            for input in inputs.iter_mut() {
                 let item_count = read_var_int(slice)?;
+                let mut witness = Vec::with_capacity(capacity(item_count, slice, 1));
                 for _ in 0..item_count {
                     let witness_len = read_var_int(slice)? as usize;
-                    let _witness = read_slice(slice, witness_len)?;
+                    witness.push(read_slice(slice, witness_len)?);
                 }
+                input.witness = witness;
             }
         }
+        let witnesses_len = witnesses_len - slice.len();
 
-        sha256_hasher1.input(&slice[..4]);
-        let lock_time = read_u32(slice)?;
+        let lock_time_bytes = read_slice(slice, 4)?;
+        sha256_hasher1.input(lock_time_bytes);
+        let lock_time = LittleEndian::read_u32(lock_time_bytes);
         sha256_hasher1.result(&mut tx_hash);
         sha256_hasher2.input(&tx_hash);
         sha256_hasher2.result(&mut tx_hash);
         let txid = *Hash::from_slice(&tx_hash);
 
-        let mut cur_outputs = VecMap::with_capacity(outputs_count as usize);
-        let mut remove = false;
-        for n in 0..raw_outputs.len() {
-            let txout = raw_outputs[n];
-            let output_item = match txout.script.to_scripttype() {
-                ScriptType::PubkeyHash(pkh) => Some(vec![Address::from_hash160(
-                    Hash160::from_slice(pkh),
-                    0x00,
-                    None,
-                )]),
-                ScriptType::ScriptHash(pkh) => Some(vec![Address::from_hash160(
-                    Hash160::from_slice(pkh),
-                    0x05,
-                    None,
-                )]),
-                ScriptType::Pubkey(pk) => Some(vec![Address::from_hash160(
-                    &Hash160::from_data(pk),
-                    0x00,
-                    None,
-                )]),
-                ScriptType::Multisig(_, pks) => Some(
-                    pks.iter()
-                        .map(|pk| Address::from_pubkey(pk, 0x05, None))
-                        .collect(),
-                ),
-                ScriptType::WitnessScriptHash(w) => Some(vec![Address {
-                    addr: WitnessProgram::from_scriptpubkey(w, Network::Bitcoin)
-                        .unwrap()
-                        .to_address()
-                        .as_bytes()
-                        .to_vec(),
-                    taints: None,
-                }]),
-                ScriptType::WitnessPubkeyHash(w) => Some(vec![Address {
-                    addr: WitnessProgram::from_scriptpubkey(w, Network::Bitcoin)
-                        .unwrap()
-                        .to_address()
-                        .as_bytes()
-                        .to_vec(),
-                    taints: None,
-                }]),
-                _ => None,
-            };
-
-            if let Some(output_item) = output_item {
-                let mut cur_output = Vec::new();
-
-                for m in 0..output_item.len() {
-                    let mut address = output_item[m].to_owned();
-                    if start_txs.len() > 0 && start_txs.contains_key(&txid) {
-                        remove = true;
-                        address.taints = is_taint(start_txs.get_mut(&txid).unwrap(), txout.amount);
-                    }
-                    if cur_taints.len() > 0 {
-                        let taints = is_taint(&mut cur_taints, txout.amount);
-                        for t in taints.clone().unwrap() {
-                            if t.label != 0 {
-                                address.taints = taints;
-                                break;
-                            }
-                        }
-                    }
-                    cur_output.insert(m as usize, (address.to_owned(), txout.amount));
-                    outputs.insert(address.to_owned(), txout.amount);
+        let size = data.len() - slice.len();
+        let (wtxid, stripped_size) = if marker == 0x00 {
+            // Marker and flag are not part of the stripped transaction
+            (Hash::from_data(&data[..size]), size - 2 - witnesses_len)
+        } else {
+            (txid, size)
+        };
+
+        Ok(TransactionView {
+            version,
+            txid,
+            wtxid,
+            size,
+            stripped_size,
+            inputs,
+            outputs,
+            lock_time,
+        })
+    }
+
+    /// Classifies the output scripts, the inputs are left unresolved.
+    pub fn decode(&self, params: &ChainParams) -> DecodedTransaction {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|txin| {
+                let spend = txin.spend();
+                Input {
+                    prev_hash: *txin.prev_hash,
+                    prev_index: txin.prev_index,
+                    sequence: txin.sequence,
+                    addresses: Vec::new(),
+                    amount: None,
+                    spend_type: spend.kind,
+                    revealed_script: spend.script.map(|s| s.to_vec()),
+                    pubkeys: spend.pubkeys.iter().map(|pk| pk.to_vec()).collect(),
+                }
+            })
+            .collect();
+
+        let outputs = self
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(n, txout)| {
+                let script_type = txout.script.to_scripttype(params);
+                Output {
+                    index: n as u32,
+                    amount: txout.amount,
+                    script_type: script_type.kind(),
+                    addresses: script_addresses(&txout.script, &script_type, params),
+                    pubkeys: script_pubkeys(&script_type),
                 }
-                cur_outputs.insert(n as usize, cur_output);
-            };
+            })
+            .collect();
+
+        DecodedTransaction {
+            version: self.version,
+            txid: self.txid,
+            inputs_count: self.inputs.len() as u64,
+            outputs_count: self.outputs.len() as u64,
+            lock_time: self.lock_time,
+            wtxid: self.wtxid,
+            size: self.size,
+            stripped_size: self.stripped_size,
+            inputs,
+            outputs,
+            spent: None,
         }
-        if remove {
-            start_txs.remove(&txid);
+    }
+}
+
+impl Transaction {
+    /// Resolves the inputs of a decoded transaction against `utxos`, moves
+    /// their taints to its outputs and adds those to `utxos`.
+    pub fn apply(
+        decoded: DecodedTransaction,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) -> Transaction {
+        let mut inputs = decoded.inputs;
+        let mut outputs = decoded.outputs;
+        let txid = decoded.txid;
+
+        utxos.spend(&mut inputs, decoded.spent.as_ref());
+        taints.propagate(&txid, &inputs, &mut outputs);
+        utxos.add(txid, &outputs, decoded.spent.is_none());
+
+        Transaction {
+            version: decoded.version,
+            txid,
+            inputs_count: decoded.inputs_count,
+            outputs_count: decoded.outputs_count,
+            lock_time: decoded.lock_time,
+            wtxid: decoded.wtxid,
+            size: decoded.size,
+            stripped_size: decoded.stripped_size,
+            inputs,
+            outputs,
         }
+    }
+
+    /// Weight in units of BIP141, witness data counts a quarter.
+    pub fn weight(&self) -> usize {
+        self.stripped_size * 3 + self.size
+    }
+
+    /// Virtual size in vbytes, the weight divided by four and rounded up.
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
 
-        if cur_outputs.len() > 0 {
-            let len = cur_outputs.len();
-            cur_outputs.reserve_len_exact(len);
-            output_items.insert(txid, cur_outputs);
+    /// Total amount of the outputs.
+    pub fn output_value(&self) -> u64 {
+        self.outputs.iter().map(|output| output.amount).sum()
+    }
+
+    /// Inputs minus outputs, `None` for the coinbase and when the amount of
+    /// a spent output is not known.
+    pub fn fee(&self) -> Option<u64> {
+        if self.is_coinbase() {
+            return None;
         }
+        let mut input_value = 0u64;
+        for input in &self.inputs {
+            input_value += input.amount?;
+        }
+        input_value.checked_sub(self.output_value())
+    }
 
-        let tx = Transaction {
-            version,
-            txid: txid,
-            inputs_count,
-            outputs_count,
-            lock_time,
-            inputs,
-            outputs,
-        };
+    /// Fee in satoshis per vbyte.
+    pub fn feerate(&self) -> Option<f64> {
+        self.fee().map(|fee| fee as f64 / self.vsize() as f64)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].prev_hash == ZERO_HASH
+    }
+
+    /// Distinct addresses of the resolved inputs, with the total amount
+    /// spent from each.
+    pub fn input_addresses(&self) -> HashMap<Address, u64> {
+        let mut addresses = HashMap::new();
+        for input in &self.inputs {
+            for address in &input.addresses {
+                *addresses.entry(address.clone()).or_insert(0) += input.amount.unwrap_or(0);
+            }
+        }
+        addresses
+    }
+
+    /// Distinct addresses of the outputs, with the total amount paid to each.
+    /// OP_RETURN outputs pay nobody and are left out, so they don't count as
+    /// payments for the clustering heuristics.
+    pub fn output_addresses(&self) -> HashMap<Address, u64> {
+        let mut addresses = HashMap::new();
+        for output in &self.outputs {
+            if output.script_type == ScriptKind::NullData {
+                continue;
+            }
+            for address in &output.addresses {
+                *addresses.entry(address.clone()).or_insert(0) += output.amount;
+            }
+        }
+        addresses
+    }
+
+    /// Skips over a transaction without touching the unspent outputs and
+    /// returns its txid.
+    pub fn read_txid(slice: &mut &[u8]) -> ParseResult<Hash> {
+        let mut tx_hash = [0u8; 32];
+        let mut sha256_hasher1 = Sha256::new();
+        let mut sha256_hasher2 = sha256_hasher1;
+
+        sha256_hasher1.input(read_slice(slice, 4)?);
+
+        let marker = *slice.first().ok_or(ParseError::Eof)?;
+        if marker == 0x00 {
+            *slice = &slice[1..];
+            if read_u8(slice)? != 0x01 {
+                return Err(ParseError::Invalid);
+            }
+        }
+
+        let mut slice_inputs_and_outputs = *slice;
+        let inputs_count = read_var_int(slice)?;
+        for _ in 0..inputs_count {
+            TransactionInput::read(slice, 0)?;
+        }
+        let outputs_count = read_var_int(slice)?;
+        for _ in 0..outputs_count {
+            TransactionOutput::read(slice, 0)?;
+        }
+        let slice_len = slice_inputs_and_outputs.len() - slice.len();
+        sha256_hasher1.input(read_slice(&mut slice_inputs_and_outputs, slice_len)?);
 
-        Ok(tx)
+        if marker == 0x00 {
+            for _ in 0..inputs_count {
+                let item_count = read_var_int(slice)?;
+                for _ in 0..item_count {
+                    let witness_len = read_var_int(slice)? as usize;
+                    read_slice(slice, witness_len)?;
+                }
+            }
+        }
+
+        sha256_hasher1.input(read_slice(slice, 4)?);
+        sha256_hasher1.result(&mut tx_hash);
+        sha256_hasher2.input(&tx_hash);
+        sha256_hasher2.result(&mut tx_hash);
+        Ok(Hash(tx_hash))
     }
 }
 
@@ -264,7 +438,7 @@ impl<'a> TransactionInput<'a> {
         let script = read_slice(slice, nbytes)?;
 
         // Read the sequence_no
-        let _ = read_u32(slice)?;
+        let sequence = read_u32(slice)?;
         let len = init_slice.len() - slice.len();
         let _ = read_slice(&mut init_slice, len)?;
 
@@ -272,8 +446,14 @@ impl<'a> TransactionInput<'a> {
             prev_hash,
             prev_index,
             script: Script::new(script, timestamp),
+            sequence,
+            witness: Vec::new(),
         })
     }
+    /// Classifies how the input spends its output.
+    pub fn spend(&self) -> Spend<'a> {
+        Spend::classify(self)
+    }
 }
 
 impl<'a> TransactionOutput<'a> {
@@ -296,38 +476,234 @@ impl<'a> TransactionOutput<'a> {
             script: Script::new(script, timestamp),
         })
     }
+
+    /// Addresses the output pays to.
+    pub fn addresses(&self, params: &ChainParams) -> Vec<Address> {
+        script_addresses(&self.script, &self.script.to_scripttype(params), params)
+    }
 }
 
-fn is_taint(taints: &mut VecDeque<Taint>, amount: u64) -> Option<VecDeque<Taint>> {
-    let mut remaining = amount;
-    let mut new_taints = VecDeque::new();
+fn script_addresses(
+    script: &Script,
+    script_type: &ScriptType,
+    params: &ChainParams,
+) -> Vec<Address> {
+    let address = match *script_type {
+        ScriptType::PubkeyHash(pkh) => Address::from_hash160(
+            Hash160::from_slice(pkh),
+            params.pubkey_address_version,
+            None,
+        ),
+        ScriptType::ScriptHash(pkh) => Address::from_hash160(
+            Hash160::from_slice(pkh),
+            params.script_address_version,
+            None,
+        ),
+        ScriptType::Pubkey(pk) => Address::from_pubkey(pk, P2PK_ADDRESS_VERSION, None),
+        ScriptType::Multisig(required, ref pks) => Address::from_multisig(required, pks, None),
+        ScriptType::WitnessScriptHash(w) => Address {
+            addr: WitnessProgram::from_scriptpubkey(w, params.bech32_network)
+                .unwrap()
+                .to_address()
+                .as_bytes()
+                .to_vec(),
+            taints: None,
+        },
+        ScriptType::WitnessPubkeyHash(w) => Address {
+            addr: WitnessProgram::from_scriptpubkey(w, params.bech32_network)
+                .unwrap()
+                .to_address()
+                .as_bytes()
+                .to_vec(),
+            taints: None,
+        },
+        ScriptType::WitnessV1Taproot(program) => Address {
+            addr: bech32m::encode(&hrp(&params.bech32_network), 1, program).into_bytes(),
+            taints: None,
+        },
+        ScriptType::WitnessUnknown(version, program) => Address {
+            addr: bech32m::encode(&hrp(&params.bech32_network), version, program).into_bytes(),
+            taints: None,
+        },
+        // Every output has an identity, so that its value is not lost
+        ScriptType::NullData | ScriptType::Unknown(_) | ScriptType::Invalid => {
+            Address::from_pubkey(script.as_slice(), SCRIPT_ADDRESS_VERSION, None)
+        }
+    };
+    vec![address]
+}
 
-    while remaining > 0 && !taints.is_empty() {
-        let mut taint = taints.pop_front().unwrap();
-        if remaining >= taint.amount {
-            remaining -= taint.amount;
-            new_taints.push_back(taint);
-        } else {
-            taint.amount -= remaining;
-            new_taints.push_back(Taint {
-                label: taint.label,
-                amount: remaining,
-            });
-            taints.push_front(taint);
-            remaining = 0;
+fn script_pubkeys(script_type: &ScriptType) -> Vec<Vec<u8>> {
+    match *script_type {
+        ScriptType::Pubkey(pk) => vec![pk.to_vec()],
+        ScriptType::Multisig(_, ref pks) => pks.iter().map(|pk| pk.to_vec()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::MAINNET;
+    use rustc_serialize::hex::FromHex;
+
+    /// Segwit transaction with a P2PKH and a P2WPKH output, one witness
+    /// input and a lock time of 7.
+    const SEGWIT_TX: &str = "01000000000101000102030405060708090a0b0c0d0e0f101112131415161718\
+                             191a1b1c1d1e1f0100000000ffffffff02a0860100000000001976a9140000000000\
+                             00000000000000000000000000000088ac881300000000000016001407070707070707\
+                             0707070707070707070707070702093030303030303030302102111111111111111111\
+                             111111111111111111111111111111111111111111111107000000";
+
+    /// Native P2WPKH example of BIP143, spending a P2PK and a P2WPKH output.
+    const BIP143_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541d\
+                             b4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd\
+                             3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc\
+                             22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa8\
+                             9e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000\
+                             001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000\
+                             001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203\
+                             609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a02205\
+                             73a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01210\
+                             25476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711\
+                             000000";
+
+    #[test]
+    fn read_segwit() {
+        let data = SEGWIT_TX.from_hex().unwrap();
+        let mut slice = &data[..];
+        let tx = TransactionView::read(&mut slice, 0).unwrap();
+        assert!(slice.is_empty());
+        assert_eq!(
+            tx.txid.to_string(),
+            "12635982f4694ebda52ddbe31b1da75da29a5349c9be461374da08d55491f456"
+        );
+        assert_eq!(
+            tx.wtxid.to_string(),
+            "5cc78ee1160b8bb4bba81c8e73a621a79d745c5bd034f16478ba113e894fc179"
+        );
+        assert_eq!(tx.size, 163);
+        assert_eq!(tx.stripped_size, 163 - 2 - 45);
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.inputs[0].witness.len(), 2);
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(tx.lock_time, 7);
+    }
+
+    #[test]
+    fn weight_and_vsize() {
+        let data = BIP143_TX.from_hex().unwrap();
+        let mut slice = &data[..];
+        let view = TransactionView::read(&mut slice, 0).unwrap();
+        assert_eq!(
+            view.txid.to_string(),
+            "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609"
+        );
+        assert_eq!(
+            view.wtxid.to_string(),
+            "c36c38370907df2324d9ce9d149d191192f338b37665a82e78e76a12c909b762"
+        );
+        assert!(view.inputs[0].witness.is_empty());
+        assert_eq!(view.inputs[1].witness.len(), 2);
+
+        let decoded = view.decode(&MAINNET);
+        let tx = Transaction::apply(decoded, &mut UtxoSet::new(), &mut TaintTracker::new());
+        assert_eq!(tx.size, data.len());
+        assert_eq!(tx.size, 343);
+        assert_eq!(tx.stripped_size, 233);
+        assert_eq!(tx.weight(), 1042);
+        assert_eq!(tx.vsize(), 261);
+
+        // Without witness data the ids are equal and the weight is four times
+        // the size
+        let data = SEGWIT_TX.from_hex().unwrap();
+        let mut stripped = data[..4].to_vec();
+        stripped.extend_from_slice(&data[6..114]);
+        stripped.extend_from_slice(&data[data.len() - 4..]);
+        let mut slice = &stripped[..];
+        let view = TransactionView::read(&mut slice, 0).unwrap();
+        assert_eq!(view.txid, view.wtxid);
+        assert_eq!(
+            view.txid.to_string(),
+            "12635982f4694ebda52ddbe31b1da75da29a5349c9be461374da08d55491f456"
+        );
+        let decoded = view.decode(&MAINNET);
+        let tx = Transaction::apply(decoded, &mut UtxoSet::new(), &mut TaintTracker::new());
+        assert_eq!((tx.size, tx.stripped_size), (116, 116));
+        assert_eq!((tx.weight(), tx.vsize()), (464, 116));
+    }
+
+    #[test]
+    fn read_truncated() {
+        let data = SEGWIT_TX.from_hex().unwrap();
+        for len in 0..data.len() {
+            let mut slice = &data[..len];
+            assert!(
+                TransactionView::read(&mut slice, 0).is_err(),
+                "read {} bytes",
+                len
+            );
+            let mut slice = &data[..len];
+            assert!(Transaction::read_txid(&mut slice).is_err());
         }
     }
 
-    if remaining > 0 {
-        new_taints.push_back(Taint {
-            label: 0,
-            amount: remaining,
-        });
+    #[test]
+    fn read_huge_counts() {
+        // Input count of 2^32 - 1 followed by nothing
+        let data = "0100000005feffffffff".from_hex().unwrap();
+        let mut slice = &data[..];
+        assert!(TransactionView::read(&mut slice, 0).is_err());
+        // Witness item count of 2^64 - 1 in a complete transaction body
+        let mut data = SEGWIT_TX.from_hex().unwrap();
+        data.truncate(114);
+        data.extend_from_slice(&"ffffffffffffffffff".from_hex().unwrap());
+        let mut slice = &data[..];
+        assert!(TransactionView::read(&mut slice, 0).is_err());
     }
 
-    if new_taints.len() == 0 {
-        return None;
-    } else {
-        return Some(new_taints);
+    #[test]
+    fn pseudo_addresses() {
+        // Pays to P2PKH, `OP_RETURN "hello"` and `OP_1` outputs
+        let data = "01000000010102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e\
+                    1f200000000000ffffffff0350c30000000000001976a91411111111111111111111\
+                    1111111111111111111188ac0000000000000000076a0568656c6c6fe80300000000\
+                    0000015100000000"
+            .from_hex()
+            .unwrap();
+        let mut slice = &data[..];
+        let decoded = TransactionView::read(&mut slice, 0)
+            .unwrap()
+            .decode(&MAINNET);
+        let tx = Transaction::apply(decoded, &mut UtxoSet::new(), &mut TaintTracker::new());
+
+        let kinds: Vec<_> = tx.outputs.iter().map(|o| o.script_type).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ScriptKind::PubkeyHash,
+                ScriptKind::NullData,
+                ScriptKind::Unknown
+            ]
+        );
+        let addresses: Vec<_> = tx
+            .outputs
+            .iter()
+            .map(|o| o.addresses[0].to_string())
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![
+                "12ZEw5Hcv1hTb6YUQJ69y1V7uhcoDz92PH",
+                "NUa7S5EY4jmfewu7E3UR2JLATkCahVkH6h",
+                "Nfo8Ncof8QJsfkjLt1uXSEkkd29cZQcCgf",
+            ]
+        );
+        assert_eq!(tx.outputs[2].addresses[0].addr[0], SCRIPT_ADDRESS_VERSION);
+
+        let outputs = tx.output_addresses();
+        assert_eq!(outputs.len(), 2);
+        assert!(!outputs.contains_key(&tx.outputs[1].addresses[0]));
+        assert_eq!(outputs[&tx.outputs[2].addresses[0]], 1000);
     }
 }
@@ -0,0 +1,383 @@
+use blockchain::address::Address;
+use blockchain::buffer::*;
+use blockchain::params::ChainParams;
+use blockchain::script::Script;
+use blockchain::transaction::TransactionOutput;
+use parser::ParseResult;
+
+/// Outputs spent by each non-coinbase transaction of a block, as amount and
+/// addresses like the outputs of a `DecodedTransaction`.
+pub type BlockUndo = Vec<Vec<(u64, Vec<Address>)>>;
+
+/// Scripts stored in compressed form by Bitcoin Core
+const SPECIAL_SCRIPTS: usize = 6;
+
+/// Reads an undo record from a `rev*.dat` file (`CBlockUndo`).
+pub fn read_block_undo(slice: &mut &[u8], params: &ChainParams) -> ParseResult<BlockUndo> {
+    let tx_count = read_var_int(slice)?;
+    let mut undo = Vec::with_capacity(capacity(tx_count, slice, 1));
+    for _ in 0..tx_count {
+        let input_count = read_var_int(slice)?;
+        let mut spent = Vec::with_capacity(capacity(input_count, slice, 3));
+        for _ in 0..input_count {
+            spent.push(read_spent_output(slice, params)?);
+        }
+        undo.push(spent);
+    }
+    Ok(undo)
+}
+
+fn read_spent_output(slice: &mut &[u8], params: &ChainParams) -> ParseResult<(u64, Vec<Address>)> {
+    // Height and coinbase flag
+    let code = read_msb_varint(slice)?;
+    if code >> 1 > 0 {
+        // Transaction version, written by old versions only but still read
+        let _version = read_msb_varint(slice)?;
+    }
+    read_compressed_output(slice, params)
+}
+
+/// Reads an output in Bitcoin Core's compressed form, as stored in undo
+/// records and the chainstate database. Scripts are classified as if all
+/// soft forks were active, the creating block is not known.
+pub fn read_compressed_output(
+    slice: &mut &[u8],
+    params: &ChainParams,
+) -> ParseResult<(u64, Vec<Address>)> {
+    let amount = decompress_amount(read_msb_varint(slice)?);
+    let script = read_compressed_script(slice)?;
+    let output = TransactionOutput {
+        amount,
+        script: Script::new(&script, u32::MAX),
+    };
+    Ok((amount, output.addresses(params)))
+}
+
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = x % 9 + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+fn read_compressed_script(slice: &mut &[u8]) -> ParseResult<Vec<u8>> {
+    let size = read_msb_varint(slice)? as usize;
+    let script = match size {
+        0 => [
+            &[0x76, 0xa9, 0x14][..],
+            read_slice(slice, 20)?,
+            &[0x88, 0xac],
+        ]
+        .concat(),
+        1 => [&[0xa9, 0x14][..], read_slice(slice, 20)?, &[0x87]].concat(),
+        2 | 3 => [&[33, size as u8][..], read_slice(slice, 32)?, &[0xac]].concat(),
+        4 | 5 => {
+            let mut pubkey = [0u8; 33];
+            pubkey[0] = size as u8 - 2;
+            pubkey[1..].copy_from_slice(read_slice(slice, 32)?);
+            match decompress_pubkey(&pubkey) {
+                Some(pubkey) => [&[65][..], &pubkey[..], &[0xac]].concat(),
+                // Not a point on the curve, Bitcoin Core leaves the script empty
+                None => Vec::new(),
+            }
+        }
+        _ => read_slice(slice, size - SPECIAL_SCRIPTS)?.to_vec(),
+    };
+    Ok(script)
+}
+
+/// secp256k1 field element, least significant limb first.
+type FieldElement = [u64; 4];
+
+/// Field prime, 2^256 - 2^32 - 977
+const P: FieldElement = [
+    0xffff_fffe_ffff_fc2f,
+    0xffff_ffff_ffff_ffff,
+    0xffff_ffff_ffff_ffff,
+    0xffff_ffff_ffff_ffff,
+];
+/// 2^256 mod P
+const P_COMPLEMENT: u64 = 0x1_0000_03d1;
+/// (P + 1) / 4, square roots are a single exponentiation as P = 3 mod 4
+const SQRT_EXPONENT: FieldElement = [
+    0xffff_ffff_bfff_ff0c,
+    0xffff_ffff_ffff_ffff,
+    0xffff_ffff_ffff_ffff,
+    0x3fff_ffff_ffff_ffff,
+];
+
+/// Uncompressed form of a compressed public key, `None` if its x coordinate
+/// is not on the curve.
+pub fn decompress_pubkey(pubkey: &[u8; 33]) -> Option<[u8; 65]> {
+    let x = fe_from_bytes(array_ref!(pubkey, 1, 32));
+    if fe_ge_p(&x) {
+        return None;
+    }
+
+    // y^2 = x^3 + 7
+    let y2 = fe_add(&fe_mul(&fe_mul(&x, &x), &x), &[7, 0, 0, 0]);
+    let mut y = fe_pow(&y2, &SQRT_EXPONENT);
+    if fe_mul(&y, &y) != y2 {
+        return None;
+    }
+    if y[0] & 1 != (pubkey[0] & 1) as u64 {
+        y = fe_sub(&P, &y);
+    }
+
+    let mut uncompressed = [0u8; 65];
+    uncompressed[0] = 0x04;
+    uncompressed[1..33].copy_from_slice(&pubkey[1..]);
+    uncompressed[33..].copy_from_slice(&fe_to_bytes(&y));
+    Some(uncompressed)
+}
+
+/// Compressed form of an uncompressed public key.
+pub fn compress_pubkey(pubkey: &[u8; 65]) -> [u8; 33] {
+    let mut compressed = [0u8; 33];
+    compressed[0] = 0x02 | (pubkey[64] & 1);
+    compressed[1..].copy_from_slice(&pubkey[1..33]);
+    compressed
+}
+
+fn fe_from_bytes(bytes: &[u8; 32]) -> FieldElement {
+    let mut fe = [0u64; 4];
+    for (i, limb) in fe.iter_mut().enumerate() {
+        *limb = bytes[24 - 8 * i..32 - 8 * i]
+            .iter()
+            .fold(0, |n, b| n << 8 | *b as u64);
+    }
+    fe
+}
+
+fn fe_to_bytes(fe: &FieldElement) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, limb) in fe.iter().enumerate() {
+        for j in 0..8 {
+            bytes[31 - 8 * i - j] = (limb >> (8 * j)) as u8;
+        }
+    }
+    bytes
+}
+
+fn fe_ge_p(a: &FieldElement) -> bool {
+    for i in (0..4).rev() {
+        if a[i] != P[i] {
+            return a[i] > P[i];
+        }
+    }
+    true
+}
+
+/// `a - b` for `a >= b`.
+fn fe_sub(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut out = [0u64; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        let (d, b1) = a[i].overflowing_sub(b[i]);
+        let (d, b2) = d.overflowing_sub(borrow);
+        out[i] = d;
+        borrow = (b1 || b2) as u64;
+    }
+    out
+}
+
+/// Adds `carry` times 2^256, folded back as multiples of 2^256 mod P.
+fn fe_normalize(mut out: FieldElement, carry: u64) -> FieldElement {
+    let mut carry = carry as u128 * P_COMPLEMENT as u128;
+    while carry > 0 {
+        for limb in out.iter_mut() {
+            let cur = *limb as u128 + carry;
+            *limb = cur as u64;
+            carry = cur >> 64;
+        }
+        carry *= P_COMPLEMENT as u128;
+    }
+    if fe_ge_p(&out) {
+        out = fe_sub(&out, &P);
+    }
+    out
+}
+
+fn fe_add(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut out = [0u64; 4];
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let cur = a[i] as u128 + b[i] as u128 + carry;
+        out[i] = cur as u64;
+        carry = cur >> 64;
+    }
+    fe_normalize(out, carry as u64)
+}
+
+fn fe_mul(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut wide = [0u64; 8];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let cur = wide[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            wide[i + j] = cur as u64;
+            carry = cur >> 64;
+        }
+        wide[i + 4] = carry as u64;
+    }
+
+    // high * 2^256 = high * P_COMPLEMENT (mod P)
+    let mut out = [0u64; 4];
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let cur = wide[i] as u128 + wide[i + 4] as u128 * P_COMPLEMENT as u128 + carry;
+        out[i] = cur as u64;
+        carry = cur >> 64;
+    }
+    fe_normalize(out, carry as u64)
+}
+
+fn fe_pow(a: &FieldElement, exponent: &FieldElement) -> FieldElement {
+    let mut out = [1, 0, 0, 0];
+    for i in (0..256).rev() {
+        out = fe_mul(&out, &out);
+        if exponent[i / 64] >> (i % 64) & 1 == 1 {
+            out = fe_mul(&out, a);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::MAINNET;
+    use rustc_serialize::hex::FromHex;
+
+    const G_X: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_Y: &str = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+    /// y coordinate of -G, which is odd
+    const NEG_G_Y: &str = "b7c52588d95c3b9aa25b0403f1eef75702e84bb7597aabe663b82f6f04ef2777";
+
+    fn hex(s: &str) -> Vec<u8> {
+        s.from_hex().unwrap()
+    }
+
+    #[test]
+    fn decompress_amounts() {
+        // Vectors of Bitcoin Core's compressor tests
+        let amounts = [
+            (0x0, 0),
+            (0x1, 1),
+            (0x7, 1_000_000),
+            (0x9, 100_000_000),
+            (0x32, 5_000_000_000),
+            (0x1406f40, 2_100_000_000_000_000),
+            (0x69f6bbd, 12_345_678),
+        ];
+        for &(compressed, amount) in amounts.iter() {
+            assert_eq!(decompress_amount(compressed), amount);
+        }
+    }
+
+    #[test]
+    fn decompress_scripts() {
+        let hash = [0x11u8; 20];
+        let x = hex(G_X);
+        let scripts = [
+            (
+                0u8,
+                &hash[..],
+                hex("76a914111111111111111111111111111111111111111188ac"),
+            ),
+            (
+                1,
+                &hash[..],
+                hex("a914111111111111111111111111111111111111111187"),
+            ),
+            (2, &x[..], [&[33, 2][..], &x, &[0xac]].concat()),
+            (3, &x[..], [&[33, 3][..], &x, &[0xac]].concat()),
+            (4, &x[..], [&[65, 4][..], &x, &hex(G_Y), &[0xac]].concat()),
+            (
+                5,
+                &x[..],
+                [&[65, 4][..], &x, &hex(NEG_G_Y), &[0xac]].concat(),
+            ),
+            // Other scripts are stored with their size plus 6
+            (8, &[0x6a, 0x51][..], vec![0x6a, 0x51]),
+        ];
+        for &(size, payload, ref script) in scripts.iter() {
+            let data = [&[size][..], payload].concat();
+            let mut slice = &data[..];
+            assert_eq!(&read_compressed_script(&mut slice).unwrap(), script);
+            assert!(slice.is_empty());
+            assert!(read_compressed_script(&mut &data[..data.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn compressed_output_addresses() {
+        let data = [&[0x32, 0x00][..], &[0; 20]].concat();
+        let (amount, addresses) = read_compressed_output(&mut &data[..], &MAINNET).unwrap();
+        assert_eq!(amount, 5_000_000_000);
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].to_string(), "1111111111111111111114oLvT2");
+
+        let data = [&[0x09, 0x01][..], &[0; 20]].concat();
+        let (amount, addresses) = read_compressed_output(&mut &data[..], &MAINNET).unwrap();
+        assert_eq!(amount, 100_000_000);
+        assert_eq!(
+            addresses[0].to_string(),
+            "31h1vYVSYuKP6AhS86fbRdMw9XHieotbST"
+        );
+    }
+
+    #[test]
+    fn compress_and_decompress_pubkeys() {
+        let x = hex(G_X);
+        for &(prefix, y) in [(2u8, G_Y), (3, NEG_G_Y)].iter() {
+            let compressed = [&[prefix][..], &x].concat();
+            let uncompressed = [&[4][..], &x, &hex(y)].concat();
+
+            let decompressed = decompress_pubkey(array_ref!(compressed, 0, 33)).unwrap();
+            assert_eq!(&decompressed[..], &uncompressed[..]);
+            assert_eq!(&compress_pubkey(&decompressed)[..], &compressed[..]);
+        }
+
+        // x at or above the field prime
+        assert!(decompress_pubkey(&[0xff; 33]).is_none());
+    }
+
+    #[test]
+    fn read_undo_record() {
+        let mut data = vec![0x02];
+        // Coinbase output of height 100, with the legacy version field
+        data.extend_from_slice(&[0x01, 0x80, 0x49, 0x00, 0x32, 0x00]);
+        data.extend_from_slice(&[0x11; 20]);
+        // Two spent outputs of height 0
+        data.extend_from_slice(&[0x02, 0x00, 0x09, 0x01]);
+        data.extend_from_slice(&[0x22; 20]);
+        data.extend_from_slice(&[0x00, 0x07, 0x08, 0x6a, 0x51]);
+
+        let undo = read_block_undo(&mut &data[..], &MAINNET).unwrap();
+        assert_eq!(undo.len(), 2);
+        assert_eq!(undo[0].len(), 1);
+        assert_eq!(undo[0][0].0, 5_000_000_000);
+        assert_eq!(undo[1].len(), 2);
+        assert_eq!(undo[1][0].0, 100_000_000);
+        assert_eq!(undo[1][1].0, 1_000_000);
+
+        for len in 0..data.len() {
+            assert!(read_block_undo(&mut &data[..len], &MAINNET).is_err());
+        }
+    }
+}
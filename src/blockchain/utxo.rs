@@ -0,0 +1,103 @@
+use fasthash::{xx, RandomState};
+use std::collections::hash_map::Entry as HashEntry;
+use std::collections::HashMap;
+use vec_map::VecMap;
+
+use blockchain::address::Address;
+use blockchain::hash::{Hash, ZERO_HASH};
+use blockchain::script::ScriptKind;
+use blockchain::transaction::{Input, Output};
+
+/// Addresses and amount of the unspent outputs, by txid and output index.
+pub type OutputItems = HashMap<Hash, VecMap<Vec<(Address, u64)>>, RandomState<xx::Hash64>>;
+
+/// The unspent outputs the inputs of later transactions are resolved against.
+pub struct UtxoSet {
+    items: OutputItems,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::with_items(HashMap::with_hasher(RandomState::<xx::Hash64>::new()))
+    }
+
+    pub fn with_items(items: OutputItems) -> Self {
+        UtxoSet { items }
+    }
+
+    pub fn items(&self) -> &OutputItems {
+        &self.items
+    }
+
+    /// Removes the outputs spent by `inputs` and fills in their addresses and
+    /// amounts. Outputs not in the set are taken from `spent`, the undo data
+    /// of the transaction, when available.
+    pub fn spend(&mut self, inputs: &mut [Input], spent: Option<&Vec<(u64, Vec<Address>)>>) {
+        for (n, input) in inputs.iter_mut().enumerate() {
+            let mut output_item = None;
+            if let HashEntry::Occupied(mut occupied) = self.items.entry(input.prev_hash) {
+                output_item = occupied.get_mut().remove(input.prev_index as usize);
+                if occupied.get().is_empty() {
+                    occupied.remove();
+                }
+            }
+
+            if input.prev_hash == ZERO_HASH {
+                continue;
+            }
+
+            // Untainted outputs are not kept when the undo data is available
+            if output_item.is_none() {
+                if let Some(&(amount, ref addresses)) = spent.and_then(|s| s.get(n)) {
+                    input.amount = Some(amount);
+                    output_item = Some(
+                        addresses
+                            .iter()
+                            .map(|address| (address.clone(), amount))
+                            .collect(),
+                    );
+                }
+            }
+
+            if let Some(output) = output_item {
+                for (address, amount) in output {
+                    input.amount = Some(amount);
+                    input.addresses.push(address);
+                }
+            }
+        }
+    }
+
+    /// Adds the outputs of transaction `txid`, only the tainted ones unless
+    /// `keep_untainted` is set.
+    pub fn add(&mut self, txid: Hash, outputs: &[Output], keep_untainted: bool) {
+        let mut cur_outputs = VecMap::with_capacity(outputs.len());
+        for output in outputs {
+            // Null data outputs can never be spent
+            if output.addresses.is_empty()
+                || output.script_type == ScriptKind::NullData
+                || !keep_untainted && output.addresses.iter().all(|a| a.taints.is_none())
+            {
+                continue;
+            }
+            let cur_output = output
+                .addresses
+                .iter()
+                .map(|address| (address.clone(), output.amount))
+                .collect();
+            cur_outputs.insert(output.index as usize, cur_output);
+        }
+
+        if !cur_outputs.is_empty() {
+            let len = cur_outputs.len();
+            cur_outputs.reserve_len_exact(len);
+            self.items.insert(txid, cur_outputs);
+        }
+    }
+}
+
+impl Default for UtxoSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
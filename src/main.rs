@@ -11,20 +11,25 @@ extern crate base58;
 extern crate bitcoin_bech32;
 extern crate byteorder;
 extern crate clap;
+extern crate crossbeam_channel;
+extern crate crossbeam_utils;
 extern crate crypto;
 extern crate dirs;
 extern crate fasthash;
 extern crate memmap;
 extern crate rustc_serialize;
+extern crate snap;
 extern crate time;
 extern crate vec_map;
 
 use std::io::Write;
+use std::process;
 
 pub mod blockchain;
 pub mod parser;
 
 use parser::parser::Parser;
+use parser::verify;
 use parser::Config;
 
 fn main() {
@@ -32,6 +37,14 @@ fn main() {
 
     info!("Starting blockchain parser...");
 
+    if let Some(ref path) = config.verify {
+        if !verify::run(&config, path) {
+            process::exit(1);
+        }
+        info!("Finished succesfully");
+        return;
+    }
+
     let mut parser = Parser::new(&config);
     parser.run();
 
@@ -1,40 +1,721 @@
+use byteorder::{ByteOrder, LittleEndian};
 use memmap::Mmap;
-use std::fs::File;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
+use std::path::{Path, PathBuf};
+use vec_map::VecMap;
+
+use blockchain::block::Block;
+use blockchain::buffer::*;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use parser::{ParseError, ParseResult};
+
+const XOR_KEY_LEN: usize = 8;
+/// Largest serialized block, 4M weight units
+const MAX_BLOCK_SIZE: usize = 4_000_000;
+/// Bytes read at a time when scanning a blk file, blocks are read from the
+/// first half so they always fit
+const SCAN_WINDOW: usize = 2 * (MAX_BLOCK_SIZE + 16);
 
 pub struct Blockchain {
-    pub maps: Vec<Mmap>,
+    /// Mapped blk files by file number
+    pub maps: VecMap<BlockFile>,
+    /// Mapped rev files by file number, only when reading undo data
+    undo_maps: Option<VecMap<BlockFile>>,
+    blocks_dir: PathBuf,
+    max_block: usize,
+    xor_key: Option<[u8; XOR_KEY_LEN]>,
+}
+
+/// Location of a best chain block inside the blk files.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockPos {
+    pub hash: Hash,
+    pub height: usize,
+    /// File number and offset of the block magic, zero for blocks fetched over RPC
+    pub file: usize,
+    pub offset: usize,
+    /// Offset of the magic of the block's undo record in the rev file with
+    /// the same number, only known from the block index
+    pub undo: Option<usize>,
+}
+
+/// A region of a blk file skipped because no block could be read from it.
+#[derive(Clone, Copy, Debug)]
+pub struct DamagedRange {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// What a scan of a blk file found, at offsets in the file.
+pub enum Scanned<'a> {
+    /// Block with its magic at the offset
+    Block(usize, Block<'a>),
+    /// Data without a network magic and valid length up to the next block
+    Damaged(usize, usize),
+    /// Block whose length runs into the next block, which starts at the
+    /// second offset
+    Overlapping(usize, usize),
+}
+
+/// A memory mapped blk or rev file. Files obfuscated by Bitcoin Core
+/// (`blocks/xor.dat`) are de-obfuscated on read, only the requested range is
+/// copied.
+pub struct BlockFile {
+    mmap: Mmap,
+    xor_key: Option<[u8; XOR_KEY_LEN]>,
 }
 
 impl Blockchain {
-    pub fn new(blocks_dir: &str, max_block: usize) -> Self {
-        let mut maps: Vec<Mmap> = Vec::new();
-        let mut n: usize = 0;
+    /// Maps the blk files, and the rev files too with `undo`.
+    pub fn new(blocks_dir: &str, max_block: usize, undo: bool) -> Self {
         let blocks_dir_path = PathBuf::from(blocks_dir);
+        let xor_key = read_xor_key(&blocks_dir_path);
+
+        if xor_key.is_some() {
+            info!("Found xor.dat, block files will be de-obfuscated");
+        }
+
+        let mut blockchain = Blockchain {
+            maps: VecMap::new(),
+            undo_maps: if undo { Some(VecMap::new()) } else { None },
+            blocks_dir: blocks_dir_path,
+            max_block,
+            xor_key,
+        };
+        blockchain.map_files(0);
+        blockchain.map_undo_files(0);
+
+        match blockchain.maps.keys().next() {
+            None => warn!("No block files found in {}", blocks_dir),
+            Some(0) => {}
+            Some(first) => info!(
+                "First block file is blk{:05}.dat (pruned node?), starting from there",
+                first
+            ),
+        }
+        blockchain
+    }
+
+    /// Maps blk files created since the last call and remaps the last known
+    /// one, which bitcoind may still be appending blocks to.
+    pub fn refresh(&mut self) {
+        let start = self.last_file().unwrap_or(0);
+        self.map_files(start);
+        self.map_undo_files(start);
+    }
+
+    /// Number of the last mapped blk file.
+    pub fn last_file(&self) -> Option<usize> {
+        self.maps.keys().next_back()
+    }
+
+    fn map_files(&mut self, start: usize) {
+        let mut maps = mem::replace(&mut self.maps, VecMap::new());
+        self.map_prefixed_files(&mut maps, "blk", start);
+        self.maps = maps;
+    }
+
+    fn map_undo_files(&mut self, start: usize) {
+        if let Some(mut maps) = self.undo_maps.take() {
+            self.map_prefixed_files(&mut maps, "rev", start);
+            self.undo_maps = Some(maps);
+        }
+    }
+
+    /// Maps all `<prefix>NNNNN.dat` files numbered `start` or higher, warning
+    /// about gaps in the numbering and files which can not be mapped.
+    fn map_prefixed_files(&self, maps: &mut VecMap<BlockFile>, prefix: &str, start: usize) {
+        let mut files: Vec<usize> = match fs::read_dir(&self.blocks_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| file_number(&entry.file_name().to_string_lossy(), prefix))
+                .filter(|n| *n >= start && (self.max_block == 0 || *n <= self.max_block))
+                .collect(),
+            Err(e) => {
+                warn!("Unable to list {}: {}", self.blocks_dir.display(), e);
+                return;
+            }
+        };
+        files.sort();
+
+        let mut expected = maps.keys().rfind(|n| *n < start).map(|n| n + 1);
+        for n in files {
+            match expected {
+                Some(expected) if n > expected => {
+                    warn!(
+                        "Block files {}{:05}.dat to {}{:05}.dat are missing",
+                        prefix,
+                        expected,
+                        prefix,
+                        n - 1
+                    )
+                }
+                _ => {}
+            }
+            expected = Some(n + 1);
+
+            let path = self.blocks_dir.join(format!("{}{:05}.dat", prefix, n));
+            let mmap = File::open(&path).and_then(|f| {
+                if f.metadata()?.len() == 0 {
+                    // Just created by bitcoind, mapped once it has data
+                    return Ok(None);
+                }
+                unsafe { Mmap::map(&f).map(Some) }
+            });
+            match mmap {
+                Ok(Some(mmap)) => {
+                    maps.insert(n, BlockFile::new(mmap, self.xor_key));
+                }
+                Ok(None) => {
+                    maps.remove(n);
+                }
+                Err(e) => {
+                    warn!("Unable to map {}: {}", path.display(), e);
+                    maps.remove(n);
+                }
+            }
+        }
+    }
+
+    /// Undo data of the block at `pos`, without the magic, length and
+    /// checksum. `None` when not reading undo data and for the genesis
+    /// block, which has none.
+    pub fn undo_at<'a>(
+        &'a self,
+        pos: &BlockPos,
+        prev_hash: &Hash,
+        params: &ChainParams,
+    ) -> ParseResult<Option<Cow<'a, [u8]>>> {
+        let (maps, offset) = match (&self.undo_maps, pos.undo) {
+            (Some(maps), Some(offset)) => (maps, offset),
+            (Some(_), None) if pos.height > 0 => return Err(ParseError::Eof),
+            _ => return Ok(None),
+        };
+        let file = maps.get(pos.file).ok_or(ParseError::Eof)?;
+        let len = file.record_len(offset, params)?;
+        let mut record = file.read_exact(offset + 8, len + 32)?;
+
+        // Bitcoin Core hashes the undo data together with the previous block hash
+        let data = [&prev_hash[..], &record[..len]].concat();
+        if Hash::from_data(&data)[..] != record[len..] {
+            return Err(ParseError::Invalid);
+        }
+        match record {
+            Cow::Borrowed(ref mut slice) => *slice = &slice[..len],
+            Cow::Owned(ref mut vec) => vec.truncate(len),
+        }
+        Ok(Some(record))
+    }
+
+    /// Serialized block at `pos`, without the magic and length.
+    pub fn block_at<'a>(
+        &'a self,
+        pos: &BlockPos,
+        params: &ChainParams,
+    ) -> ParseResult<Cow<'a, [u8]>> {
+        let file = self.maps.get(pos.file).ok_or(ParseError::Eof)?;
+        let len = file.record_len(pos.offset, params)?;
+        if len < 80 {
+            return Err(ParseError::Eof);
+        }
+        file.read_exact(pos.offset + 8, len)
+    }
+}
 
+impl BlockFile {
+    pub fn new(mmap: Mmap, xor_key: Option<[u8; XOR_KEY_LEN]>) -> Self {
+        BlockFile { mmap, xor_key }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+
+    /// De-obfuscated bytes from `offset`, at most `len` of them.
+    pub fn read(&self, offset: usize, len: usize) -> Cow<'_, [u8]> {
+        let start = offset.min(self.len());
+        let end = offset.saturating_add(len).min(self.len());
+        let slice = &self.mmap[start..end];
+        match self.xor_key {
+            Some(key) => {
+                let mut data = slice.to_vec();
+                xor(&mut data, &key, start);
+                Cow::Owned(data)
+            }
+            None => Cow::Borrowed(slice),
+        }
+    }
+
+    /// Exactly `len` de-obfuscated bytes from `offset`.
+    fn read_exact(&self, offset: usize, len: usize) -> ParseResult<Cow<'_, [u8]>> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(self.read(offset, len)),
+            _ => Err(ParseError::Eof),
+        }
+    }
+
+    /// Reads the blocks from `offset` on and re-synchronizes on the next
+    /// network magic after damaged data. Returns where the blocks end, before
+    /// any zero padding, and why when something else follows.
+    pub fn scan<F>(&self, offset: usize, params: &ChainParams, f: F) -> (usize, Option<ParseError>)
+    where
+        F: FnMut(Scanned),
+    {
+        self.scan_windows(offset, params, SCAN_WINDOW, f)
+    }
+
+    fn scan_windows<F>(
+        &self,
+        offset: usize,
+        params: &ChainParams,
+        size: usize,
+        mut f: F,
+    ) -> (usize, Option<ParseError>)
+    where
+        F: FnMut(Scanned),
+    {
+        let mut window = Window {
+            file: self,
+            start: offset,
+            data: Cow::Borrowed(&[]),
+            size,
+        };
+        let mut pos = offset;
+        let mut padding = None;
         loop {
-            unsafe {
-                match File::open(blocks_dir_path.join(format!("blk{:05}.dat", n))) {
-                    Ok(f) => {
-                        if max_block != 0 && n > max_block {
-                            break;
-                        }
-                        n += 1;
-                        match Mmap::map(&f) {
-                            Ok(m) => {
-                                maps.push(m);
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        break;
+            let (data, complete) = window.at(pos);
+            match data.iter().position(|b| *b != 0) {
+                Some(0) => {}
+                Some(n) => {
+                    padding.get_or_insert(pos);
+                    pos += n;
+                    continue;
+                }
+                None if complete => return (padding.unwrap_or(pos), None),
+                None => {
+                    padding.get_or_insert(pos);
+                    pos += data.len();
+                    continue;
+                }
+            }
+            let start = padding.take().unwrap_or(pos);
+
+            let slice = &mut &data[..];
+            let block = match Block::read(slice, params) {
+                Ok(block) => block,
+                Err(err) => match window.find_block(pos + 1, params) {
+                    Some(next) => {
+                        f(Scanned::Damaged(start, next));
+                        pos = next;
+                        continue;
                     }
-                };
+                    None => return (start, Some(err)),
+                },
+            };
+
+            // A partially written block followed by further blocks swallows
+            // the start of the next one, unlike a complete block whose
+            // payload happens to contain the network magic
+            let len = data.len() - slice.len();
+            if !at_block_boundary(slice, params) && !block.is_intact() {
+                if let Some(next) = find_block(&data[..len], 1, params) {
+                    f(Scanned::Overlapping(pos, pos + next));
+                    pos += next;
+                    continue;
+                }
+            }
+
+            f(Scanned::Block(pos, block));
+            pos += len;
+        }
+    }
+
+    /// Length of the block or undo record at `offset`, after checking its
+    /// magic.
+    fn record_len(&self, offset: usize, params: &ChainParams) -> ParseResult<usize> {
+        let header = self.read_exact(offset, 8)?;
+        let slice = &mut &header[..];
+        if read_u32(slice)? != params.magic {
+            return Err(ParseError::Invalid);
+        }
+        Ok(read_u32(slice)? as usize)
+    }
+}
+
+/// Part of a file read while scanning it.
+struct Window<'a> {
+    file: &'a BlockFile,
+    start: usize,
+    data: Cow<'a, [u8]>,
+    size: usize,
+}
+
+impl<'a> Window<'a> {
+    /// Data from `pos` on, at least half a window unless the file ends
+    /// first, and whether it reaches the end of the file.
+    fn at(&mut self, pos: usize) -> (&[u8], bool) {
+        let end = self.start + self.data.len();
+        if pos < self.start || pos > end || (pos + self.size / 2 > end && end < self.file.len()) {
+            self.start = pos;
+            self.data = self.file.read(pos, self.size);
+        }
+        let end = self.start + self.data.len();
+        (&self.data[pos - self.start..], end >= self.file.len())
+    }
+
+    /// Offset of the next block at or after `pos`, up to the end of the file.
+    fn find_block(&mut self, mut pos: usize, params: &ChainParams) -> Option<usize> {
+        loop {
+            let (data, complete) = self.at(pos);
+            if let Some(n) = find_block(data, 0, params) {
+                return Some(pos + n);
+            }
+            if complete {
+                return None;
+            }
+            // A magic and length cut off by the window is found in the next one
+            pos += data.len() - 7;
+        }
+    }
+}
+
+/// Applies the obfuscation key to `data` located at `offset` in its file.
+pub fn xor(data: &mut [u8], key: &[u8; XOR_KEY_LEN], offset: usize) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[(offset + i) % XOR_KEY_LEN];
+    }
+}
+
+/// Offset of the next network magic at or after `from` which is followed by
+/// a plausible block length.
+pub fn find_block(data: &[u8], from: usize, params: &ChainParams) -> Option<usize> {
+    let mut magic = [0u8; 4];
+    LittleEndian::write_u32(&mut magic, params.magic);
+
+    let mut offset = from;
+    while offset + 8 <= data.len() {
+        match data[offset..].windows(4).position(|w| w == magic) {
+            Some(n) => offset += n,
+            None => return None,
+        }
+        if offset + 8 > data.len() {
+            return None;
+        }
+        let len = LittleEndian::read_u32(&data[offset + 4..offset + 8]) as usize;
+        if (80..=MAX_BLOCK_SIZE).contains(&len) {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+/// Whether `data` continues with another block or only zero padding.
+pub fn at_block_boundary(data: &[u8], params: &ChainParams) -> bool {
+    match data.iter().position(|b| *b != 0) {
+        Some(n) => data.len() >= n + 4 && LittleEndian::read_u32(&data[n..]) == params.magic,
+        None => true,
+    }
+}
+
+impl fmt::Display for DamagedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "blk{:05}.dat at offset {} to {}",
+            self.file, self.start, self.end
+        )
+    }
+}
+
+/// File number of a `<prefix>NNNNN.dat` file name.
+fn file_number(name: &str, prefix: &str) -> Option<usize> {
+    let number = name.strip_prefix(prefix)?.strip_suffix(".dat")?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Reads the obfuscation key from `xor.dat`, an all-zero key means
+/// the files are stored as is.
+fn read_xor_key(blocks_dir: &Path) -> Option<[u8; XOR_KEY_LEN]> {
+    let mut key = [0u8; XOR_KEY_LEN];
+    match File::open(blocks_dir.join("xor.dat")) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_exact(&mut key) {
+                warn!("Unable to read xor.dat: {}", e);
+                return None;
+            }
+        }
+        Err(_) => return None,
+    }
+
+    if key.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::{MAINNET, REGTEST};
+    use std::env;
+
+    const KEY: [u8; XOR_KEY_LEN] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+    /// Maps `data` written to a file, obfuscated with `key`.
+    fn block_file(name: &str, data: &[u8], key: Option<[u8; XOR_KEY_LEN]>) -> BlockFile {
+        let path = env::temp_dir().join(format!("blockfast-{}.dat", name));
+        let mut stored = data.to_vec();
+        if let Some(ref key) = key {
+            xor(&mut stored, key, 0);
+        }
+        fs::write(&path, stored).unwrap();
+        let mmap = unsafe { Mmap::map(&File::open(&path).unwrap()).unwrap() };
+        BlockFile::new(mmap, key)
+    }
+
+    /// Framed block with `len` bytes of `fill`, not a valid block but
+    /// readable.
+    fn block(fill: u8, len: usize) -> Vec<u8> {
+        let mut data = vec![0xfa, 0xbf, 0xb5, 0xda];
+        data.extend_from_slice(&[len as u8, (len >> 8) as u8, 0, 0]);
+        data.extend(vec![fill; len]);
+        data
+    }
+
+    fn scan(file: &BlockFile, window: usize) -> (Vec<(usize, u8)>, usize, Option<ParseError>) {
+        let mut blocks = Vec::new();
+        let (end, error) = file.scan_windows(0, &REGTEST, window, |scanned| match scanned {
+            Scanned::Block(offset, block) => blocks.push((offset, block.0[0])),
+            _ => panic!("unexpected damaged data"),
+        });
+        (blocks, end, error)
+    }
+
+    /// Intact regtest block whose coinbase output script holds the network
+    /// magic and a plausible block length.
+    fn block_with_magic() -> Vec<u8> {
+        let mut tx = vec![1, 0, 0, 0, 1];
+        tx.extend_from_slice(&[0; 32]);
+        tx.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 1, 0x51, 0xff, 0xff, 0xff, 0xff, 1]);
+        tx.extend_from_slice(&[0; 8]);
+        tx.extend_from_slice(&[10, 0x6a, 8, 0xfa, 0xbf, 0xb5, 0xda, 100, 0, 0, 0]);
+        tx.extend_from_slice(&[0; 4]);
+
+        let mut data = vec![1, 0, 0, 0];
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(&Hash::from_data(&tx)[..]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0x7f, 0x20, 0, 0, 0, 0]);
+        data.push(1);
+        data.extend(tx);
+        while !Block(&data).header().check_pow() {
+            data[76] += 1;
+        }
+        assert!(Block(&data).is_intact());
+
+        let mut block = vec![0xfa, 0xbf, 0xb5, 0xda, data.len() as u8, 0, 0, 0];
+        block.extend(data);
+        block
+    }
+
+    #[test]
+    fn file_numbers() {
+        assert_eq!(file_number("blk00000.dat", "blk"), Some(0));
+        assert_eq!(file_number("blk01234.dat", "blk"), Some(1234));
+        assert_eq!(file_number("blk123456.dat", "blk"), Some(123456));
+        assert_eq!(file_number("rev00012.dat", "rev"), Some(12));
+        assert_eq!(file_number("rev00012.dat", "blk"), None);
+        assert_eq!(file_number("undo00012.dat", "undo"), Some(12));
+        for name in [
+            "blk.dat",
+            "blkindex.dat",
+            "blk+0001.dat",
+            "blk00001.dat.tmp",
+            "xblk00001.dat",
+        ]
+        .iter()
+        {
+            assert_eq!(file_number(name, "blk"), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn find_files_in_order() {
+        let dir = env::temp_dir().join("blockfast-files");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let names = [
+            "blk00003.dat",
+            "blk00000.dat",
+            "blk00010.dat",
+            "blk00001.dat",
+            "rev00000.dat",
+            "rev00001.dat",
+            "blkindex.dat",
+            "blk00002.dat.tmp",
+        ];
+        for name in names.iter() {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        fs::write(dir.join("xor.dat"), [0; XOR_KEY_LEN]).unwrap();
+        // Just created by bitcoind
+        fs::write(dir.join("blk00011.dat"), b"").unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+
+        let blockchain = Blockchain::new(&dir, 0, true);
+        assert_eq!(blockchain.maps.keys().collect::<Vec<_>>(), [0, 1, 3, 10]);
+        assert_eq!(&blockchain.maps[3].read(0, 100)[..], b"blk00003.dat");
+        let undo_maps = blockchain.undo_maps.as_ref().unwrap();
+        assert_eq!(undo_maps.keys().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(blockchain.last_file(), Some(10));
+
+        let blockchain = Blockchain::new(&dir, 3, false);
+        assert_eq!(blockchain.maps.keys().collect::<Vec<_>>(), [0, 1, 3]);
+        assert!(blockchain.undo_maps.is_none());
+    }
+
+    #[test]
+    fn find_plausible_blocks() {
+        let mut data = vec![0u8; 4];
+        // Magic with a too short and a too long length
+        data.extend_from_slice(&[0xfa, 0xbf, 0xb5, 0xda, 79, 0, 0, 0]);
+        data.extend_from_slice(&[0xfa, 0xbf, 0xb5, 0xda, 0x01, 0x09, 0x3d, 0]);
+        data.extend(block(1, 80));
+        assert_eq!(find_block(&data, 0, &REGTEST), Some(20));
+        assert_eq!(find_block(&data, 20, &REGTEST), Some(20));
+        assert_eq!(find_block(&data, 21, &REGTEST), None);
+        // Magic and length cut short
+        assert_eq!(find_block(&data[..27], 0, &REGTEST), None);
+        assert_eq!(find_block(&data, 0, &MAINNET), None);
+
+        assert!(at_block_boundary(&[], &REGTEST));
+        assert!(at_block_boundary(&[0; 10], &REGTEST));
+        assert!(at_block_boundary(&data[20..], &REGTEST));
+        assert!(at_block_boundary(&data[..24], &REGTEST));
+        assert!(!at_block_boundary(&[0, 0, 0xfa, 0xbf], &REGTEST));
+        assert!(!at_block_boundary(&data[1..], &MAINNET));
+    }
+
+    #[test]
+    fn resync_after_damaged_data() {
+        let mut data = block(1, 100);
+        // Garbage between blocks
+        let garbage = data.len();
+        data.extend(vec![0x99; 50]);
+        let intact = data.len();
+        data.extend(block_with_magic());
+        let garbage_after_intact = data.len();
+        data.extend_from_slice(&[0x42; 3]);
+        // Block cut short by a crash, overwritten by the next block
+        let partial = data.len();
+        data.extend(block(3, 150));
+        let next = partial + 60;
+        data.truncate(next);
+        data.extend(block(4, 100));
+        // Block still being written
+        let tail = data.len();
+        data.extend(block(5, 100));
+        data.truncate(tail + 30);
+
+        for &key in [None, Some(KEY)].iter() {
+            let file = block_file("resync", &data, key);
+            for &window in [SCAN_WINDOW, 400].iter() {
+                let mut found = Vec::new();
+                let end = file.scan_windows(0, &REGTEST, window, |scanned| {
+                    found.push(match scanned {
+                        Scanned::Block(offset, _) => ("block", offset, 0),
+                        Scanned::Damaged(start, next) => ("damaged", start, next),
+                        Scanned::Overlapping(offset, next) => ("overlapping", offset, next),
+                    })
+                });
+                assert_eq!(
+                    found,
+                    [
+                        ("block", 0, 0),
+                        ("damaged", garbage, intact),
+                        ("block", intact, 0),
+                        ("damaged", garbage_after_intact, partial),
+                        ("overlapping", partial, next),
+                        ("block", next, 0),
+                    ]
+                );
+                assert_eq!(end, (tail, Some(ParseError::Eof)));
             }
         }
-        Blockchain { maps }
+    }
+
+    #[test]
+    fn zero_padding_ends_the_blocks() {
+        let mut data = vec![0; 10];
+        data.extend(block(1, 100));
+        let end = data.len();
+        data.extend(vec![0; 1000]);
+        let file = block_file("padding", &data, Some(KEY));
+        let (blocks, padding, error) = scan(&file, 256);
+        assert_eq!(blocks, [(10, 1)]);
+        assert_eq!((padding, error), (end, None));
+
+        // Anything but a block after the padding
+        data.extend_from_slice(&[1; 8]);
+        let file = block_file("padding-garbage", &data, None);
+        assert_eq!(scan(&file, 256).1, end);
+        assert_eq!(scan(&file, 256).2, Some(ParseError::Invalid));
+    }
+
+    #[test]
+    fn read_obfuscated_ranges() {
+        let data: Vec<u8> = (0..100).collect();
+        let file = block_file("xor", &data, Some(KEY));
+        assert_ne!(file.mmap[..8], data[..8]);
+
+        assert_eq!(&file.read(0, 100)[..], &data[..]);
+        // Offsets not aligned to the key length
+        assert_eq!(&file.read(3, 10)[..], &data[3..13]);
+        assert_eq!(&file.read(13, 1)[..], &data[13..14]);
+        assert_eq!(&file.read(95, 10)[..], &data[95..]);
+        assert!(file.read(100, 10).is_empty());
+        assert!(file.read(usize::MAX, 10).is_empty());
+        assert!(file.read_exact(95, 10).is_err());
+        assert_eq!(&file.read_exact(95, 5).unwrap()[..], &data[95..]);
+
+        let plain = block_file("plain", &data, None);
+        match plain.read(3, 10) {
+            Cow::Borrowed(slice) => assert_eq!(slice, &data[3..13]),
+            Cow::Owned(_) => panic!("plain file copied"),
+        }
+    }
+
+    #[test]
+    fn scan_obfuscated_in_windows() {
+        let mut data = Vec::new();
+        for n in 1..10 {
+            data.extend(block(n, 100 + n as usize));
+        }
+        let end = data.len();
+        data.extend(vec![0; 300]);
+
+        let expected = scan(&block_file("scan-plain", &data, None), SCAN_WINDOW);
+        assert_eq!(expected.0.len(), 9);
+        assert_eq!(expected.0[1], (8 + 101, 2));
+        assert_eq!((expected.1, &expected.2), (end, &None));
+
+        let file = block_file("scan-xor", &data, Some(KEY));
+        for &window in [SCAN_WINDOW, 256, 301, 512].iter() {
+            assert_eq!(scan(&file, window), expected, "window of {} bytes", window);
+        }
     }
 }
@@ -1,108 +1,359 @@
-use crossbeam_channel::Sender;
-use fasthash::{xx, RandomState};
-use std::collections::HashMap;
+use crossbeam_channel::{self as channel, Receiver, Sender};
+use std::borrow::Cow;
+use std::io;
+use std::iter;
 
 use blockchain::block::Block;
-use blockchain::hash::{Hash, ZERO_HASH};
-use parser::blockchain::Blockchain;
+use blockchain::buffer::capacity;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::transaction::{DecodedTransaction, TransactionView};
+use blockchain::undo::read_block_undo;
+use parser::blockchain::{BlockPos, Blockchain};
+use parser::p2p::Peer;
+use parser::rpc::RpcClient;
 use parser::ParseError;
 
-pub enum BlockMessage<'a> {
-    OnBlock(Block<'a>),
+/// Blocks in chain order, each with the channel its decoded
+/// transactions arrive on.
+pub enum BlockMessage {
+    OnBlock(BlockPos, Receiver<Result<DecodedBlock, DecodeError>>),
     OnComplete(bool),
     OnError(ParseError),
 }
 
+/// A block handed to the decoding workers.
+pub struct BlockJob<'a> {
+    /// Serialized block without magic and length
+    data: Cow<'a, [u8]>,
+    /// Undo data of the block, when resolving inputs through the rev files
+    undo: Option<Cow<'a, [u8]>>,
+    tx: Sender<Result<DecodedBlock, DecodeError>>,
+}
+
+/// Why the transactions of a block could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Transaction at this position in the block is unreadable
+    Transaction(usize, ParseError),
+    /// Undo data is unreadable
+    Undo(ParseError),
+    /// Undo data does not have the spent outputs of every input
+    UndoMismatch,
+}
+
+pub struct DecodedBlock {
+    pub timestamp: u32,
+    /// Weight in units of BIP141
+    pub weight: usize,
+    pub transactions: Vec<DecodedTransaction>,
+}
+
 pub struct Blocks<'a> {
-    tx: Sender<BlockMessage<'a>>,
+    tx: Sender<BlockMessage>,
+    jobs: Sender<BlockJob<'a>>,
+    params: &'static ChainParams,
 }
 
 impl<'a> Blocks<'a> {
-    pub fn new(tx: Sender<BlockMessage<'a>>) -> Self {
-        Self { tx: tx.clone() }
+    pub fn new(
+        tx: Sender<BlockMessage>,
+        jobs: Sender<BlockJob<'a>>,
+        params: &'static ChainParams,
+    ) -> Self {
+        Self {
+            tx: tx.clone(),
+            jobs,
+            params,
+        }
     }
 
-    pub fn run(&mut self, blockchain: &'a Blockchain) {
-        let mut skipped: HashMap<Hash, Block, RandomState<xx::Hash64>> =
-            HashMap::with_capacity_and_hasher(100, RandomState::<xx::Hash64>::new());
-        let mut goal_prev_hash: Hash = ZERO_HASH;
-        let mut last_block: Option<Block> = None;
-        let mut height = 0;
-
-        for (n, mmap) in blockchain.maps.iter().enumerate() {
-            let mmap_slice = &mut &mmap[..];
-
-            info!(
-                "Processing block file {}/{}, height {}",
-                n,
-                blockchain.maps.len() - 1,
-                height
-            );
-            while mmap_slice.len() > 0 {
-                if skipped.contains_key(&goal_prev_hash) {
-                    self.tx
-                        .send(BlockMessage::OnBlock(last_block.unwrap()))
-                        .unwrap();
-                    height += 1;
-                    while let Some(block) = skipped.remove(&goal_prev_hash) {
-                        self.tx.send(BlockMessage::OnBlock(block)).unwrap();
-                        height += 1;
-                        goal_prev_hash = block.header().cur_hash();
-                        last_block = None;
-                    }
+    /// Reads the blocks of `chain` and sends them to the workers, stops
+    /// early once the receiving side hangs up.
+    pub fn run(&mut self, blockchain: &'a Blockchain, chain: &[BlockPos]) {
+        let mut last_file = None;
+
+        for pos in chain.iter() {
+            if last_file != Some(pos.file) {
+                info!(
+                    "Processing block file {}/{}, height {}",
+                    pos.file,
+                    blockchain.last_file().unwrap_or(0),
+                    pos.height
+                );
+                last_file = Some(pos.file);
+            }
+
+            let data = match blockchain.block_at(pos, self.params) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("Unable to read block {} at height {}", pos.hash, pos.height);
+                    let _ = self.tx.send(BlockMessage::OnError(err));
+                    return;
                 }
+            };
 
-                let block = match Block::read(mmap_slice) {
-                    Ok(block) => block,
-                    Err(_) => {
-                        assert_eq!(mmap_slice.len(), 0);
-                        break;
+            let prev_hash = *Block(&data).header().prev_hash();
+            let undo = match blockchain.undo_at(pos, &prev_hash, self.params) {
+                Ok(undo) => undo,
+                Err(err) => {
+                    error!(
+                        "Unable to read undo data of block {} at height {}",
+                        pos.hash, pos.height
+                    );
+                    let _ = self.tx.send(BlockMessage::OnError(err));
+                    return;
+                }
+            };
+
+            if !self.send(*pos, data, undo) {
+                return;
+            }
+        }
+
+        let _ = self.tx.send(BlockMessage::OnComplete(true));
+    }
+
+    /// Fetches the blocks from `start` to `end` height from a remote source.
+    pub fn fetch(&mut self, remote: &mut Remote, start: usize, end: usize) {
+        let complete = match *remote {
+            Remote::Rpc(ref mut client) => self.fetch_rpc(client, start, end),
+            Remote::Peer(ref mut peer, ref chain) => self.download(peer, &chain[..end + 1], start),
+        };
+        if complete {
+            let _ = self.tx.send(BlockMessage::OnComplete(true));
+        }
+    }
+
+    fn fetch_rpc(&mut self, client: &mut RpcClient, start: usize, end: usize) -> bool {
+        for height in start..end + 1 {
+            if height.is_multiple_of(1000) || height == start {
+                info!("Fetching blocks over RPC, height {}/{}", height, end);
+            }
+
+            let block = client
+                .block_hash(height)
+                .and_then(|hash| client.block(&hash).map(|data| (hash, data)));
+            let (hash, data) = match block {
+                Ok(block) => block,
+                Err(e) => {
+                    error!("Unable to fetch block at height {}: {}", height, e);
+                    let _ = self.tx.send(BlockMessage::OnError(ParseError::Eof));
+                    return false;
+                }
+            };
+            if data.len() < 80 || Block(&data).header().cur_hash() != hash {
+                error!("Invalid block {} at height {}", hash, height);
+                let _ = self.tx.send(BlockMessage::OnError(ParseError::Invalid));
+                return false;
+            }
+
+            if !self.send(remote_pos(hash, height), Cow::Owned(data), None) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn download(&mut self, peer: &mut Peer, chain: &[Hash], start: usize) -> bool {
+        let end = chain.len() - 1;
+        let mut height = start;
+        let mut complete = true;
+
+        let result = peer.blocks(&chain[start..], |hash, data| {
+            if height.is_multiple_of(1000) || height == start {
+                info!("Downloading blocks from peer, height {}/{}", height, end);
+            }
+            complete = self.send(remote_pos(*hash, height), Cow::Owned(data), None);
+            height += 1;
+            complete
+        });
+
+        if let Err(e) = result {
+            error!("Unable to download block at height {}: {}", height, e);
+            let _ = self.tx.send(BlockMessage::OnError(ParseError::Eof));
+            return false;
+        }
+        complete
+    }
+
+    /// Queues a block for decoding, false once the receiving side hung up.
+    fn send(&mut self, pos: BlockPos, data: Cow<'a, [u8]>, undo: Option<Cow<'a, [u8]>>) -> bool {
+        let (tx, rx) = channel::bounded(1);
+        self.jobs.send(BlockJob { data, undo, tx }).is_ok()
+            && self.tx.send(BlockMessage::OnBlock(pos, rx)).is_ok()
+    }
+}
+
+/// Worker loop decoding the transactions of each received block.
+pub fn decode_blocks(jobs: Receiver<BlockJob>, params: &'static ChainParams) {
+    for job in jobs.iter() {
+        let _ = job.tx.send(DecodedBlock::decode(
+            &Block(&job.data),
+            job.undo.as_ref().map(|u| &u[..]),
+            params,
+        ));
+    }
+}
+
+impl DecodedBlock {
+    /// Decodes the transactions of a block. With undo data, fails unless it
+    /// holds the spent outputs of every transaction, as the inputs could not be
+    /// resolved otherwise.
+    pub fn decode(
+        block: &Block,
+        undo: Option<&[u8]>,
+        params: &ChainParams,
+    ) -> Result<DecodedBlock, DecodeError> {
+        let timestamp = block.header().timestamp();
+        let transactions = block.transactions();
+        let mut slice = transactions.slice;
+        let mut decoded = Vec::with_capacity(capacity(transactions.count, slice, 60));
+
+        for n in 0..transactions.count {
+            if !slice.is_empty() {
+                match TransactionView::read(&mut slice, timestamp) {
+                    Ok(transaction) => decoded.push(transaction.decode(params)),
+                    // The undo data could not be matched up without it
+                    Err(err) if undo.is_some() => {
+                        return Err(DecodeError::Transaction(n as usize, err))
                     }
-                };
-
-                if *block.header().prev_hash() != goal_prev_hash {
-                    skipped.insert(*block.header().prev_hash(), block);
-
-                    if last_block.is_some()
-                        && block.header().prev_hash() == last_block.unwrap().header().prev_hash()
-                    {
-                        let first_orphan = last_block.unwrap().clone();
-                        let second_orphan = block;
-
-                        loop {
-                            let block = match Block::read(mmap_slice) {
-                                Ok(block) => block,
-                                Err(_) => {
-                                    assert_eq!(mmap_slice.len(), 0);
-                                    break;
-                                }
-                            };
-
-                            skipped.insert(*block.header().prev_hash(), block);
-                            if block.header().prev_hash() == &first_orphan.header().cur_hash() {
-                                break;
-                            }
-                            if block.header().prev_hash() == &second_orphan.header().cur_hash() {
-                                goal_prev_hash = second_orphan.header().cur_hash();
-                                last_block = Some(second_orphan);
-                                break;
-                            }
-                        }
+                    Err(_) => {
+                        warn!("Error processing transaction");
+                        continue;
                     }
-                    continue;
                 }
+            }
+        }
 
-                if let Some(last_block) = last_block {
-                    self.tx.send(BlockMessage::OnBlock(last_block)).unwrap();
-                    height += 1;
-                }
+        if let Some(mut undo) = undo {
+            let spent = read_block_undo(&mut undo, params).map_err(DecodeError::Undo)?;
+            let matches = spent.len() + 1 == decoded.len()
+                && decoded[1..]
+                    .iter()
+                    .zip(spent.iter())
+                    .all(|(transaction, spent)| transaction.inputs.len() == spent.len());
+            if !matches {
+                return Err(DecodeError::UndoMismatch);
+            }
+            let spent = iter::once(Vec::new()).chain(spent);
+            for (transaction, spent) in decoded.iter_mut().zip(spent) {
+                transaction.spent = Some(spent);
+            }
+        }
+
+        // Witness data counts a quarter of the other block data
+        let witnesses_len: usize = decoded.iter().map(|t| t.size - t.stripped_size).sum();
+        let weight = (block.0.len() - witnesses_len) * 3 + block.0.len();
+
+        Ok(DecodedBlock {
+            timestamp,
+            weight,
+            transactions: decoded,
+        })
+    }
+}
+
+/// Blocks fetched from a remote source have no blk file position.
+fn remote_pos(hash: Hash, height: usize) -> BlockPos {
+    BlockPos {
+        hash,
+        height,
+        file: 0,
+        offset: 0,
+        undo: None,
+    }
+}
+
+/// Block sources other than the blocks directory.
+pub enum Remote {
+    Rpc(RpcClient),
+    /// A peer and the block hashes of its best chain
+    Peer(Peer, Vec<Hash>),
+}
 
-                goal_prev_hash = block.header().cur_hash();
-                last_block = Some(block);
+impl Remote {
+    /// Updates the view of the best chain and returns its height.
+    pub fn sync(&mut self) -> io::Result<usize> {
+        match *self {
+            Remote::Rpc(ref mut client) => client.block_count(),
+            Remote::Peer(ref mut peer, ref mut chain) => {
+                peer.sync_headers(chain)?;
+                Ok(chain.len() - 1)
             }
         }
+    }
+
+    /// Height of a block known to the remote, after `sync`.
+    pub fn block_height(&mut self, hash: &Hash) -> io::Result<usize> {
+        match *self {
+            Remote::Rpc(ref mut client) => client.block_height(hash),
+            Remote::Peer(_, ref chain) => chain
+                .iter()
+                .position(|h| h == hash)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "block not found")),
+        }
+    }
+
+    pub fn block_hash(&mut self, height: usize) -> io::Result<Hash> {
+        match *self {
+            Remote::Rpc(ref mut client) => client.block_hash(height),
+            Remote::Peer(_, ref chain) => chain.get(height).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "block height out of range")
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::REGTEST;
+    use rustc_serialize::hex::FromHex;
+
+    /// Coinbase with an `OP_1` scriptSig and an `OP_1` output
+    const COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000\
+                            000000ffffffff0151ffffffff0100f2052a01000000015100000000";
 
-        self.tx.send(BlockMessage::OnComplete(true)).unwrap();
-        return;
+    fn block(transactions: &[&str]) -> Vec<u8> {
+        let mut data = vec![0; 80];
+        data.push(transactions.len() as u8);
+        for transaction in transactions {
+            data.extend(transaction.from_hex().unwrap());
+        }
+        data
+    }
+
+    #[test]
+    fn decode_with_undo() {
+        let data = block(&[COINBASE]);
+        let decoded = DecodedBlock::decode(&Block(&data), Some(&[0]), &REGTEST).unwrap();
+        assert_eq!(decoded.transactions.len(), 1);
+        assert_eq!(decoded.transactions[0].spent, Some(Vec::new()));
+        assert_eq!(decoded.weight, data.len() * 4);
+    }
+
+    #[test]
+    fn decode_errors() {
+        let data = block(&[COINBASE]);
+        // Spent outputs of a second transaction
+        match DecodedBlock::decode(&Block(&data), Some(&[1, 0]), &REGTEST) {
+            Err(DecodeError::UndoMismatch) => {}
+            _ => panic!("undo data of a missing transaction accepted"),
+        }
+        match DecodedBlock::decode(&Block(&data), Some(&[1]), &REGTEST) {
+            Err(DecodeError::Undo(ParseError::Eof)) => {}
+            _ => panic!("truncated undo data accepted"),
+        }
+
+        let mut data = block(&[COINBASE, COINBASE]);
+        data.truncate(data.len() - 4);
+        match DecodedBlock::decode(&Block(&data), Some(&[1, 1]), &REGTEST) {
+            Err(DecodeError::Transaction(1, ParseError::Eof)) => {}
+            _ => panic!("truncated transaction accepted"),
+        }
+        // Skipped without undo data
+        let decoded = DecodedBlock::decode(&Block(&data), None, &REGTEST).unwrap();
+        assert_eq!(decoded.transactions.len(), 1);
     }
 }
@@ -0,0 +1,197 @@
+use fasthash::{xx, RandomState};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use blockchain::address::Address;
+use blockchain::buffer::*;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::undo::read_compressed_output;
+use blockchain::utxo::OutputItems;
+use parser::leveldb::LevelDb;
+use parser::ParseResult;
+
+const OBFUSCATE_KEY: &[u8] = b"\x0e\x00obfuscate_key";
+const COIN_PREFIX: u8 = b'C';
+const BEST_BLOCK_KEY: &[u8] = b"B";
+/// Present while bitcoind is in the middle of flushing the database
+const HEAD_BLOCKS_KEY: &[u8] = b"H";
+
+/// Unspent outputs loaded from Bitcoin Core's `chainstate` database.
+pub struct Chainstate {
+    /// Last block included in the unspent outputs, its height is looked up in
+    /// the chain of the block source
+    pub hash: Hash,
+    pub output_items: OutputItems,
+}
+
+impl Chainstate {
+    /// Reads all coins of the database, which must not be in use by bitcoind.
+    pub fn load(dir: &Path, params: &ChainParams) -> io::Result<Chainstate> {
+        let mut output_items: OutputItems = HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+        let mut obfuscate_key = Vec::new();
+        let mut hash = None;
+        let mut coins = 0;
+        let mut invalid = 0;
+
+        let db = LevelDb::open(dir)?;
+        for (key, mut value) in db.iter()? {
+            // The obfuscation key sorts before all other entries
+            if &key[..] == OBFUSCATE_KEY {
+                obfuscate_key = read_var_bytes(&mut &value[..])
+                    .map_err(|_| invalid_data("invalid obfuscation key"))?
+                    .to_vec();
+                continue;
+            }
+            if !obfuscate_key.is_empty() {
+                for (i, byte) in value.iter_mut().enumerate() {
+                    *byte ^= obfuscate_key[i % obfuscate_key.len()];
+                }
+            }
+
+            if &key[..] == HEAD_BLOCKS_KEY {
+                return Err(invalid_data("chainstate flush was interrupted"));
+            }
+            if &key[..] == BEST_BLOCK_KEY && value.len() == 32 {
+                hash = Some(*Hash::from_slice(array_ref!(value, 0, 32)));
+                continue;
+            }
+            if key.len() < 34 || key[0] != COIN_PREFIX {
+                continue;
+            }
+
+            let txid = *Hash::from_slice(array_ref!(key, 1, 32));
+            let (vout, (amount, addresses)) = match read_coin(&key, &value, params) {
+                Ok(coin) => coin,
+                Err(_) => {
+                    invalid += 1;
+                    continue;
+                }
+            };
+
+            coins += 1;
+            output_items
+                .entry(txid)
+                .or_default()
+                .insert(vout, addresses.into_iter().map(|a| (a, amount)).collect());
+            if coins % 10_000_000 == 0 {
+                info!("Loaded {} unspent outputs", coins);
+            }
+        }
+
+        if invalid > 0 {
+            warn!("Skipped {} invalid chainstate records", invalid);
+        }
+        let hash = hash.ok_or_else(|| invalid_data("no best block in chainstate"))?;
+        info!("Loaded {} unspent outputs up to block {}", coins, hash);
+
+        Ok(Chainstate { hash, output_items })
+    }
+}
+
+/// Output index and output of a coin record.
+fn read_coin(
+    key: &[u8],
+    value: &[u8],
+    params: &ChainParams,
+) -> ParseResult<(usize, (u64, Vec<Address>))> {
+    let vout = read_msb_varint(&mut &key[33..])? as usize;
+    let slice = &mut &value[..];
+    // Height and coinbase flag
+    let _code = read_msb_varint(slice)?;
+    let output = read_compressed_output(slice, params)?;
+    Ok((vout, output))
+}
+
+fn read_var_bytes<'a>(slice: &mut &'a [u8]) -> ParseResult<&'a [u8]> {
+    let len = read_var_int(slice)? as usize;
+    Ok(read_slice(slice, len)?)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::MAINNET;
+    use std::path::PathBuf;
+
+    /// Values are obfuscated with the key 8b2d1a4e009735c1. Transaction aa..aa
+    /// has a P2PKH and a P2SH coin, bb..bb a P2WPKH coin at output 200 and a
+    /// coin with a cut off script at output 201. The best block is 00..1f.
+    /// `chainstate-flushing` also has the head blocks of an interrupted flush
+    /// in its log.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn addresses(chainstate: &Chainstate, txid: u8, vout: usize) -> Vec<(String, u64)> {
+        chainstate.output_items[Hash::from_slice(&[txid; 32])][vout]
+            .iter()
+            .map(|(address, amount)| (address.to_string(), *amount))
+            .collect()
+    }
+
+    #[test]
+    fn load_obfuscated() {
+        let chainstate = Chainstate::load(&fixture("chainstate"), &MAINNET).unwrap();
+        let best: Vec<u8> = (0..32).collect();
+        assert_eq!(chainstate.hash, *Hash::from_slice(array_ref!(best, 0, 32)));
+        assert_eq!(chainstate.output_items.len(), 2);
+        assert_eq!(
+            addresses(&chainstate, 0xaa, 0),
+            [(
+                "12ZEw5Hcv1hTb6YUQJ69y1V7uhcoDz92PH".to_string(),
+                50_0000_0000
+            )]
+        );
+        assert_eq!(
+            addresses(&chainstate, 0xaa, 1),
+            [("34oVnh4gNviJGMnNvgquMeLAxvXJuaRVMZ".to_string(), 1234)]
+        );
+        // Witness addresses are kept as their bech32 string
+        let outputs = &chainstate.output_items[Hash::from_slice(&[0xbb; 32])];
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), [200]);
+        let (address, amount) = &outputs[200][0];
+        assert_eq!(
+            &address.addr[..],
+            &b"bc1qxvenxvenxvenxvenxvenxvenxvenxven2ymjt8"[..]
+        );
+        assert_eq!(*amount, 10_000_000);
+    }
+
+    #[test]
+    fn abort_interrupted_flush() {
+        let err = Chainstate::load(&fixture("chainstate-flushing"), &MAINNET)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "chainstate flush was interrupted");
+    }
+
+    #[test]
+    fn read_coins() {
+        // Output 300 of a coinbase at height 10, P2PKH of 0.5 BTC
+        let mut key = vec![COIN_PREFIX];
+        key.extend_from_slice(&[0; 32]);
+        key.extend_from_slice(&[0x81, 0x2c]);
+        let mut value = vec![21, 0x30, 0];
+        value.extend_from_slice(&[0x11; 20]);
+        let (vout, (amount, addresses)) = read_coin(&key, &value, &MAINNET).unwrap();
+        assert_eq!((vout, amount), (300, 50_000_000));
+        assert_eq!(
+            addresses[0].to_string(),
+            "12ZEw5Hcv1hTb6YUQJ69y1V7uhcoDz92PH"
+        );
+
+        for len in 0..value.len() {
+            assert!(read_coin(&key, &value[..len], &MAINNET).is_err());
+        }
+        assert!(read_coin(&key[..33], &value, &MAINNET).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use fasthash::{xx, RandomState};
+use memmap::Mmap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use vec_map::VecMap;
+
+use blockchain::address::{Address, Taint};
+use blockchain::buffer::*;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::utxo::OutputItems;
+use parser::union::{Data, UnionFind};
+use parser::{ParseError, ParseResult};
+
+const CHECKPOINT_FILE: &str = "checkpoint.dat";
+const CHECKPOINT_MAGIC: u32 = 0x70636662;
+const CHECKPOINT_VERSION: u32 = 3;
+
+/// How the unspent outputs of a checkpoint were built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// From all blocks since genesis
+    Blocks,
+    /// From the undo data, only the outputs of processed blocks are known
+    Undo,
+    /// From a bitcoind chainstate and the blocks after it
+    Chainstate,
+}
+
+/// Full parser state after processing the block `hash` at `height`.
+pub struct Checkpoint {
+    pub hash: Hash,
+    pub height: usize,
+    pub labels: HashMap<String, u8>,
+    pub start_txs: HashMap<Hash, VecDeque<Taint>>,
+    pub output_items: OutputItems,
+    pub clusters: UnionFind<Address, RandomState<xx::Hash64>>,
+    pub key_addresses: HashSet<Address, RandomState<xx::Hash64>>,
+}
+
+/// Borrowed parser state written to a checkpoint.
+pub struct CheckpointState<'a> {
+    pub params: &'static ChainParams,
+    pub mode: Mode,
+    pub hash: &'a Hash,
+    pub height: usize,
+    pub labels: &'a HashMap<String, u8>,
+    pub start_txs: &'a HashMap<Hash, VecDeque<Taint>>,
+    pub output_items: &'a OutputItems,
+    pub clusters: &'a UnionFind<Address, RandomState<xx::Hash64>>,
+    pub key_addresses: &'a HashSet<Address, RandomState<xx::Hash64>>,
+}
+
+impl<'a> CheckpointState<'a> {
+    /// Writes the state next to the previous checkpoint and then replaces it,
+    /// so an interrupted write or a crash never leaves a broken checkpoint
+    /// behind.
+    pub fn save(&self, dir: &str) -> io::Result<()> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let tmp_path = dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+
+        {
+            let mut w = BufWriter::new(File::create(&tmp_path)?);
+            w.write_u32::<LittleEndian>(CHECKPOINT_MAGIC)?;
+            w.write_u32::<LittleEndian>(CHECKPOINT_VERSION)?;
+            w.write_u32::<LittleEndian>(self.params.magic)?;
+            w.write_u8(self.mode as u8)?;
+            w.write_all(self.hash)?;
+            w.write_u64::<LittleEndian>(self.height as u64)?;
+
+            w.write_u64::<LittleEndian>(self.labels.len() as u64)?;
+            for (tag, label) in self.labels {
+                write_bytes(&mut w, tag.as_bytes())?;
+                w.write_u8(*label)?;
+            }
+
+            w.write_u64::<LittleEndian>(self.start_txs.len() as u64)?;
+            for (txid, taints) in self.start_txs {
+                w.write_all(txid)?;
+                write_taints(&mut w, taints)?;
+            }
+
+            w.write_u64::<LittleEndian>(self.output_items.len() as u64)?;
+            for (txid, outputs) in self.output_items {
+                w.write_all(txid)?;
+                w.write_u64::<LittleEndian>(outputs.len() as u64)?;
+                for (n, output) in outputs {
+                    w.write_u64::<LittleEndian>(n as u64)?;
+                    w.write_u64::<LittleEndian>(output.len() as u64)?;
+                    for (address, amount) in output {
+                        write_address(&mut w, address)?;
+                        w.write_u64::<LittleEndian>(*amount)?;
+                    }
+                }
+            }
+
+            w.write_u64::<LittleEndian>(self.clusters.ids.len() as u64)?;
+            for (address, id) in &self.clusters.ids {
+                write_address(&mut w, address)?;
+                w.write_u64::<LittleEndian>(*id as u64)?;
+            }
+            for data in &self.clusters.data_by_id {
+                w.write_u64::<LittleEndian>(data.parent as u64)?;
+                w.write_u32::<LittleEndian>(data.rank)?;
+            }
+
+            w.write_u64::<LittleEndian>(self.key_addresses.len() as u64)?;
+            for address in self.key_addresses {
+                write_address(&mut w, address)?;
+            }
+            w.flush()?;
+            w.get_ref().sync_all()?;
+        }
+
+        fs::rename(&tmp_path, dir.join(CHECKPOINT_FILE))?;
+        File::open(&dir)?.sync_all()
+    }
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint of a run on the `params` network that built its
+    /// unspent outputs in a way compatible with `mode`.
+    pub fn load(dir: &str, params: &ChainParams, mode: Mode) -> io::Result<Checkpoint> {
+        let file = File::open(PathBuf::from(dir).join(CHECKPOINT_FILE))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let slice = &mut &mmap[..];
+        let (magic, saved_mode) =
+            Checkpoint::read_header(slice).map_err(|_| invalid_data("corrupted checkpoint"))?;
+        if magic != params.magic {
+            return Err(invalid_data(&format!(
+                "checkpoint is not for {}",
+                params.name
+            )));
+        }
+        // The outputs of a chainstate are complete, only undo data lacks them
+        let compatible = match (saved_mode, mode) {
+            (Mode::Chainstate, Mode::Blocks) => true,
+            (saved_mode, mode) => saved_mode == mode,
+        };
+        if !compatible {
+            return Err(invalid_data(&format!(
+                "checkpoint unspent outputs were built from {:?}, not {:?}",
+                saved_mode, mode
+            )));
+        }
+
+        Checkpoint::read(slice).map_err(|_| invalid_data("corrupted checkpoint"))
+    }
+
+    fn read_header(slice: &mut &[u8]) -> ParseResult<(u32, Mode)> {
+        if read_u32(slice)? != CHECKPOINT_MAGIC || read_u32(slice)? != CHECKPOINT_VERSION {
+            return Err(ParseError::Invalid);
+        }
+        let magic = read_u32(slice)?;
+        let mode = match read_u8(slice)? {
+            0 => Mode::Blocks,
+            1 => Mode::Undo,
+            2 => Mode::Chainstate,
+            _ => return Err(ParseError::Invalid),
+        };
+        Ok((magic, mode))
+    }
+
+    fn read(slice: &mut &[u8]) -> ParseResult<Checkpoint> {
+        let hash = read_hash(slice)?;
+        let height = read_u64(slice)? as usize;
+
+        let mut labels = HashMap::new();
+        for _ in 0..read_u64(slice)? {
+            let tag =
+                String::from_utf8(read_bytes(slice)?.to_vec()).map_err(|_| ParseError::Invalid)?;
+            labels.insert(tag, read_u8(slice)?);
+        }
+
+        let mut start_txs = HashMap::new();
+        for _ in 0..read_u64(slice)? {
+            let txid = read_hash(slice)?;
+            start_txs.insert(txid, read_taints(slice)?);
+        }
+
+        let mut output_items: OutputItems = HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+        for _ in 0..read_u64(slice)? {
+            let txid = read_hash(slice)?;
+            let mut outputs = VecMap::new();
+            for _ in 0..read_u64(slice)? {
+                let n = read_u64(slice)? as usize;
+                let mut output = Vec::new();
+                for _ in 0..read_u64(slice)? {
+                    let address = read_address(slice)?;
+                    output.push((address, read_u64(slice)?));
+                }
+                outputs.insert(n, output);
+            }
+            output_items.insert(txid, outputs);
+        }
+
+        let mut clusters: UnionFind<Address, RandomState<xx::Hash64>> =
+            UnionFind::with_hasher(RandomState::<xx::Hash64>::new());
+        let count = read_u64(slice)? as usize;
+        for _ in 0..count {
+            let address = read_address(slice)?;
+            clusters.ids.insert(address, read_u64(slice)? as usize);
+        }
+        for _ in 0..count {
+            let parent = read_u64(slice)? as usize;
+            let rank = read_u32(slice)?;
+            clusters.data_by_id.push(Data { parent, rank });
+        }
+
+        let mut key_addresses = HashSet::with_hasher(RandomState::<xx::Hash64>::new());
+        for _ in 0..read_u64(slice)? {
+            key_addresses.insert(read_address(slice)?);
+        }
+
+        Ok(Checkpoint {
+            hash,
+            height,
+            labels,
+            start_txs,
+            output_items,
+            clusters,
+            key_addresses,
+        })
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+fn write_taints<W: Write>(w: &mut W, taints: &VecDeque<Taint>) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(taints.len() as u32)?;
+    for taint in taints {
+        w.write_u8(taint.label)?;
+        w.write_u64::<LittleEndian>(taint.amount)?;
+    }
+    Ok(())
+}
+
+fn write_address<W: Write>(w: &mut W, address: &Address) -> io::Result<()> {
+    write_bytes(w, &address.addr)?;
+    match address.taints {
+        Some(ref taints) => {
+            w.write_u8(1)?;
+            write_taints(w, taints)
+        }
+        None => w.write_u8(0),
+    }
+}
+
+fn read_hash(slice: &mut &[u8]) -> ParseResult<Hash> {
+    Ok(*Hash::from_slice(array_ref!(read_slice(slice, 32)?, 0, 32)))
+}
+
+fn read_bytes<'a>(slice: &mut &'a [u8]) -> ParseResult<&'a [u8]> {
+    let len = read_u32(slice)? as usize;
+    Ok(read_slice(slice, len)?)
+}
+
+fn read_taints(slice: &mut &[u8]) -> ParseResult<VecDeque<Taint>> {
+    let mut taints = VecDeque::new();
+    for _ in 0..read_u32(slice)? {
+        let label = read_u8(slice)?;
+        let amount = read_u64(slice)?;
+        taints.push_back(Taint { label, amount });
+    }
+    Ok(taints)
+}
+
+fn read_address(slice: &mut &[u8]) -> ParseResult<Address> {
+    let addr = read_bytes(slice)?.to_vec();
+    let taints = match read_u8(slice)? {
+        0 => None,
+        _ => Some(read_taints(slice)?),
+    };
+    Ok(Address { addr, taints })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::{REGTEST, TESTNET};
+    use std::env;
+    use std::path::Path;
+
+    fn address(n: u8, taints: Option<VecDeque<Taint>>) -> Address {
+        Address::new(&[n; 33], taints)
+    }
+
+    fn taints(label: u8, amount: u64) -> VecDeque<Taint> {
+        vec![Taint { label, amount }].into_iter().collect()
+    }
+
+    /// Saves a small state to a new directory and returns its path.
+    fn save(name: &str, mode: Mode) -> String {
+        let dir = env::temp_dir().join(format!("blockfast-checkpoint-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().into_owned();
+
+        let mut labels = HashMap::new();
+        labels.insert("stolen".to_string(), 1);
+        let mut start_txs = HashMap::new();
+        start_txs.insert(Hash([1; 32]), taints(1, 5000));
+        let mut output_items: OutputItems = HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+        let mut outputs = VecMap::new();
+        outputs.insert(0, vec![(address(2, Some(taints(1, 3000))), 3000)]);
+        outputs.insert(3, vec![(address(3, None), 1), (address(4, None), 1)]);
+        output_items.insert(Hash([5; 32]), outputs);
+        let mut clusters = UnionFind::with_hasher(RandomState::<xx::Hash64>::new());
+        clusters.union(address(2, None), address(3, None));
+        clusters.make_set(address(4, None));
+        let mut key_addresses = HashSet::with_hasher(RandomState::<xx::Hash64>::new());
+        key_addresses.insert(address(4, None));
+
+        let state = CheckpointState {
+            params: &REGTEST,
+            mode,
+            hash: &Hash([9; 32]),
+            height: 120,
+            labels: &labels,
+            start_txs: &start_txs,
+            output_items: &output_items,
+            clusters: &clusters,
+            key_addresses: &key_addresses,
+        };
+        state.save(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = save("round-trip", Mode::Blocks);
+        assert!(!Path::new(&dir).join("checkpoint.dat.tmp").exists());
+        let mut checkpoint = Checkpoint::load(&dir, &REGTEST, Mode::Blocks).unwrap();
+
+        assert_eq!(checkpoint.hash, Hash([9; 32]));
+        assert_eq!(checkpoint.height, 120);
+        assert_eq!(checkpoint.labels["stolen"], 1);
+        let start_taints = &checkpoint.start_txs[&Hash([1; 32])];
+        assert_eq!((start_taints[0].label, start_taints[0].amount), (1, 5000));
+
+        let outputs = &checkpoint.output_items[&Hash([5; 32])];
+        assert_eq!(outputs.len(), 2);
+        let (ref tainted, amount) = outputs[0][0];
+        assert_eq!(
+            (tainted.addr.clone(), amount),
+            (address(2, None).addr, 3000)
+        );
+        assert_eq!(tainted.taints.as_ref().unwrap()[0].amount, 3000);
+        assert_eq!(outputs[3].len(), 2);
+        assert!(outputs[3][1].0 == address(4, None));
+
+        assert_eq!(checkpoint.clusters.len(), 3);
+        assert!(checkpoint
+            .clusters
+            .in_union(&address(2, None), &address(3, None)));
+        assert!(!checkpoint
+            .clusters
+            .in_union(&address(2, None), &address(4, None)));
+        assert_eq!(checkpoint.key_addresses.len(), 1);
+        assert!(checkpoint.key_addresses.contains(&address(4, None)));
+    }
+
+    #[test]
+    fn rejects_other_network_and_mode() {
+        let dir = save("mode", Mode::Undo);
+        assert!(Checkpoint::load(&dir, &REGTEST, Mode::Undo).is_ok());
+        assert!(Checkpoint::load(&dir, &TESTNET, Mode::Undo).is_err());
+        assert!(Checkpoint::load(&dir, &REGTEST, Mode::Blocks).is_err());
+
+        let dir = save("chainstate", Mode::Chainstate);
+        assert!(Checkpoint::load(&dir, &REGTEST, Mode::Blocks).is_ok());
+        assert!(Checkpoint::load(&dir, &REGTEST, Mode::Undo).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_file() {
+        let dir = save("corrupted", Mode::Blocks);
+        let path = Path::new(&dir).join(CHECKPOINT_FILE);
+        let data = fs::read(&path).unwrap();
+
+        fs::write(&path, b"").unwrap();
+        assert!(Checkpoint::load(&dir, &REGTEST, Mode::Blocks).is_err());
+        for len in 1..data.len() {
+            fs::write(&path, &data[..len]).unwrap();
+            let err = Checkpoint::load(&dir, &REGTEST, Mode::Blocks)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        for &n in [0, 4, 12].iter() {
+            let mut corrupted = data.clone();
+            corrupted[n] ^= 0xff;
+            fs::write(&path, &corrupted).unwrap();
+            assert!(Checkpoint::load(&dir, &REGTEST, Mode::Blocks).is_err());
+        }
+    }
+}
@@ -2,43 +2,76 @@ use crypto::digest::Digest;
 use crypto::md5::Md5;
 use fasthash::{xx, RandomState};
 use rustc_serialize::hex::ToHex;
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::io::{LineWriter, Write};
 
 use blockchain::address::Address;
-use blockchain::transaction::Transaction;
+use blockchain::params::ChainParams;
+use blockchain::script::{is_valid_pubkey, ScriptKind};
+use blockchain::transaction::{Output, Transaction};
+use blockchain::undo::{compress_pubkey, decompress_pubkey};
 use parser::union::UnionFind;
 use parser::Config;
 
 pub struct Clusters {
-    writer: LineWriter<File>,
+    output: String,
     clusters: UnionFind<Address, RandomState<xx::Hash64>>,
+    /// P2PKH addresses of the keys linked to multisig and P2PK outputs
+    key_addresses: HashSet<Address, RandomState<xx::Hash64>>,
+    params: &'static ChainParams,
+    link_multisig_keys: bool,
+    link_p2pk: bool,
 }
 
 impl Clusters {
     pub fn new(config: &Config) -> Self {
         let output = &config.output;
-        let writer = LineWriter::new(File::create(output).expect("Unable to create output file!"));
+        File::create(output).expect("Unable to create output file!");
         let clusters: UnionFind<Address, RandomState<xx::Hash64>> =
             UnionFind::with_hasher(RandomState::<xx::Hash64>::new());
 
         Self {
-            writer: writer,
-            clusters: clusters,
+            output: output.to_string(),
+            clusters,
+            key_addresses: HashSet::with_hasher(RandomState::<xx::Hash64>::new()),
+            params: config.params,
+            link_multisig_keys: config.link_multisig_keys,
+            link_p2pk: config.link_p2pk,
         }
     }
 
+    pub fn union_find(&self) -> &UnionFind<Address, RandomState<xx::Hash64>> {
+        &self.clusters
+    }
+
+    pub fn key_addresses(&self) -> &HashSet<Address, RandomState<xx::Hash64>> {
+        &self.key_addresses
+    }
+
+    pub fn restore(
+        &mut self,
+        clusters: UnionFind<Address, RandomState<xx::Hash64>>,
+        key_addresses: HashSet<Address, RandomState<xx::Hash64>>,
+    ) {
+        self.clusters = clusters;
+        self.key_addresses = key_addresses;
+    }
+
     pub fn on_transaction(&mut self, transaction: &mut Transaction) {
+        let inputs = transaction.input_addresses();
+        let outputs = transaction.output_addresses();
+
         // Inputs
-        if transaction.inputs.len() > 0 {
-            let mut tx_inputs = transaction.inputs.iter();
+        if !inputs.is_empty() {
+            let mut tx_inputs = inputs.iter();
             let (mut last_address, _) = tx_inputs.next().unwrap();
             let mut is_cluster = false;
 
-            if transaction.outputs.len() > 0 {
-                let (output1, amount) = transaction.outputs.iter().next().unwrap();
-                if transaction.outputs.len() == 2 && transaction.inputs.len() != 2 {
-                    let (output2, _) = &transaction.outputs.iter().next().unwrap();
+            if !outputs.is_empty() {
+                let (output1, amount) = outputs.iter().next().unwrap();
+                if outputs.len() == 2 && inputs.len() != 2 {
+                    let (output2, _) = &outputs.iter().next().unwrap();
 
                     if !self.clusters.contains(&output1) && self.clusters.contains(&output2) {
                         let amount = *amount / 100_000_000;
@@ -52,7 +85,7 @@ impl Clusters {
                         }
                     }
 
-                    for (address, _) in transaction.inputs.iter() {
+                    for (address, _) in inputs.iter() {
                         if *address == *output1 || address == *output2 {
                             is_cluster = false;
                             break;
@@ -60,7 +93,7 @@ impl Clusters {
                     }
                 }
 
-                if is_cluster || transaction.outputs.len() == 1 {
+                if is_cluster || outputs.len() == 1 {
                     if !self.clusters.contains(&last_address) {
                         self.clusters.make_set(last_address.to_owned());
                     }
@@ -72,7 +105,7 @@ impl Clusters {
             }
 
             for (address, _) in tx_inputs {
-                if transaction.outputs.len() == 1 {
+                if outputs.len() == 1 {
                     if !self.clusters.contains(&address) {
                         self.clusters.make_set(address.to_owned());
                     }
@@ -85,36 +118,68 @@ impl Clusters {
         }
 
         // Outputs
-        if transaction.outputs.len() == 1 {
-            for (address, _) in transaction.outputs.iter() {
+        if outputs.len() == 1 {
+            for (address, _) in outputs.iter() {
                 if !self.clusters.contains(&address) {
                     self.clusters.make_set(address.to_owned());
                 }
             }
         }
+
+        for output in transaction.outputs.iter() {
+            match output.script_type {
+                ScriptKind::Multisig if self.link_multisig_keys => self.link_keys(output, false),
+                ScriptKind::Pubkey if self.link_p2pk => self.link_keys(output, true),
+                _ => {}
+            }
+        }
     }
 
+    /// Joins the addresses of an output with the P2PKH addresses of its keys,
+    /// also of the other form of each key when `both_forms` is set.
+    fn link_keys(&mut self, output: &Output, both_forms: bool) {
+        for address in output.addresses.iter() {
+            for pubkey in output.pubkeys.iter().filter(|pk| is_valid_pubkey(pk)) {
+                let mut keys = vec![pubkey.clone()];
+                if both_forms {
+                    keys.extend(other_form(pubkey));
+                }
+                for key in keys {
+                    let key_address =
+                        Address::from_pubkey(&key, self.params.pubkey_address_version, None);
+                    self.clusters.union(address.clone(), key_address.clone());
+                    self.key_addresses.insert(key_address);
+                }
+            }
+        }
+    }
+
+    /// Writes all clusters to the output file, replacing its previous contents.
     pub fn done(&mut self) {
         info!("Done");
         info!("Found {} addresses", self.clusters.len());
 
+        let tmp_output = format!("{}.tmp", self.output);
+        let mut writer =
+            LineWriter::new(File::create(&tmp_output).expect("Unable to create output file!"));
+
         let prefix = "kyblsoft.cz".to_string();
         let mut cache: Vec<(Address, [u8; 32])> = Default::default();
         let mut pos = 0;
         let mut count = 0;
 
         for set in self.clusters.into_iter() {
+            // Linked key addresses are never tainted themselves, they are
+            // written along with the tainted addresses of their cluster
+            let tainted = set.as_slice().iter().any(|a| a.taints.is_some());
             for address in set.into_iter() {
-                match &address.taints {
-                    Some(_) => {
-                        let mut hasher = Md5::new();
-                        let mut hash = [0u8; 32];
-                        hasher.input(format!("{}:{}", prefix, address).as_bytes());
-                        hasher.result(&mut hash);
-                        cache.push((address.clone(), hash));
-                        count = count + 1;
-                    }
-                    None => {}
+                if address.taints.is_some() || tainted && self.key_addresses.contains(address) {
+                    let mut hasher = Md5::new();
+                    let mut hash = [0u8; 32];
+                    hasher.input(format!("{}:{}", prefix, address).as_bytes());
+                    hasher.result(&mut hash);
+                    cache.push((address.clone(), hash));
+                    count += 1;
                 }
                 if count % 1000000 == 0 && count != 0 {
                     info!("Processed {} addresses, {} self.clusters", count, pos);
@@ -125,23 +190,242 @@ impl Clusters {
             if cache.len() > 0 {
                 let (address, hash) = cache.pop().unwrap();
                 let digest = array_ref!(hash, 0, 8);
-                self.writer
-                    .write(&format!("{},{},{}\n", pos, digest.to_hex(), address.clone()).as_bytes())
+                writer
+                    .write_all(format!("{},{},{}\n", pos, digest.to_hex(), address).as_bytes())
                     .expect("Unable to write to output file!");
 
                 for (address, _) in &cache {
-                    self.writer
-                        .write(
-                            &format!("{},{},{}\n", pos, digest.to_hex(), address.clone())
-                                .as_bytes(),
-                        )
+                    writer
+                        .write_all(format!("{},{},{}\n", pos, digest.to_hex(), address).as_bytes())
                         .expect("Unable to write to output file!");
                 }
                 pos = pos + 1;
                 cache.clear();
             }
         }
+        writer.flush().expect("Unable to write to output file!");
+        fs::rename(&tmp_output, &self.output).expect("Unable to write to output file!");
+
         info!("Done");
         info!("Found {} clusters", pos);
     }
 }
+
+/// Uncompressed form of a compressed key and the other way round, `None` for
+/// keys not on the curve.
+fn other_form(pubkey: &[u8]) -> Option<Vec<u8>> {
+    match pubkey.len() {
+        33 => decompress_pubkey(array_ref!(pubkey, 0, 33)).map(|pk| pk.to_vec()),
+        65 => Some(compress_pubkey(array_ref!(pubkey, 0, 65)).to_vec()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::address::{Taint, MULTISIG_ADDRESS_VERSION, P2PK_ADDRESS_VERSION};
+    use blockchain::params::MAINNET;
+    use blockchain::taint::TaintTracker;
+    use blockchain::transaction::TransactionView;
+    use blockchain::utxo::UtxoSet;
+    use rustc_serialize::hex::FromHex;
+    use std::collections::VecDeque;
+    use std::env;
+
+    /// Generator point, its y is even
+    const G: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_UNCOMPRESSED: &str =
+        "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                                  483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+    /// Negated generator point, its y is odd
+    const NEG_G: &str = "0379be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const NEG_G_UNCOMPRESSED: &str =
+        "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+         b7c52588d95c3b9aa25b0403f1eef75702e84bb7597aabe663b82f6f04ef2777";
+
+    fn clusters(name: &str) -> Clusters {
+        Clusters {
+            output: env::temp_dir()
+                .join(format!("blockfast-{}.csv", name))
+                .to_string_lossy()
+                .into_owned(),
+            clusters: UnionFind::with_hasher(RandomState::<xx::Hash64>::new()),
+            key_addresses: HashSet::with_hasher(RandomState::<xx::Hash64>::new()),
+            params: &MAINNET,
+            link_multisig_keys: true,
+            link_p2pk: true,
+        }
+    }
+
+    fn output(script_type: ScriptKind, address: Address, pubkeys: &[&str]) -> Output {
+        Output {
+            index: 0,
+            amount: 1000,
+            script_type,
+            addresses: vec![address],
+            pubkeys: pubkeys.iter().map(|pk| pk.from_hex().unwrap()).collect(),
+        }
+    }
+
+    fn key_address(pubkey: &str) -> Address {
+        Address::from_pubkey(
+            &pubkey.from_hex().unwrap(),
+            MAINNET.pubkey_address_version,
+            None,
+        )
+    }
+
+    #[test]
+    fn other_form_of_keys() {
+        for &(compressed, uncompressed) in [(G, G_UNCOMPRESSED), (NEG_G, NEG_G_UNCOMPRESSED)].iter()
+        {
+            let compressed = compressed.from_hex().unwrap();
+            let uncompressed = uncompressed.from_hex().unwrap();
+            assert_eq!(other_form(&compressed), Some(uncompressed.clone()));
+            assert_eq!(other_form(&uncompressed), Some(compressed));
+        }
+        // No point has an x of 5
+        let mut off_curve = vec![0x02];
+        off_curve.extend_from_slice(&[0; 31]);
+        off_curve.push(5);
+        assert_eq!(other_form(&off_curve), None);
+        assert_eq!(other_form(&[0x02; 20]), None);
+    }
+
+    #[test]
+    fn link_p2pk_keys() {
+        let mut clusters = clusters("link-p2pk");
+        let pubkey = G.from_hex().unwrap();
+        let p2pk = Address::from_pubkey(&pubkey, P2PK_ADDRESS_VERSION, None);
+        clusters.link_keys(&output(ScriptKind::Pubkey, p2pk.clone(), &[G]), true);
+
+        assert_eq!(clusters.clusters.len(), 3);
+        assert!(clusters.clusters.in_union(&p2pk, &key_address(G)));
+        assert!(clusters
+            .clusters
+            .in_union(&p2pk, &key_address(G_UNCOMPRESSED)));
+        assert_eq!(clusters.key_addresses.len(), 2);
+        assert!(!clusters.key_addresses.contains(&p2pk));
+
+        // The same from the uncompressed key
+        let mut clusters = self::clusters("link-p2pk-uncompressed");
+        let pubkey = NEG_G_UNCOMPRESSED.from_hex().unwrap();
+        let p2pk = Address::from_pubkey(&pubkey, P2PK_ADDRESS_VERSION, None);
+        let output = output(ScriptKind::Pubkey, p2pk.clone(), &[NEG_G_UNCOMPRESSED]);
+        clusters.link_keys(&output, true);
+        assert!(clusters.clusters.in_union(&p2pk, &key_address(NEG_G)));
+        assert!(clusters
+            .clusters
+            .in_union(&p2pk, &key_address(NEG_G_UNCOMPRESSED)));
+    }
+
+    /// Transaction paying to a P2PK output of `pubkey`.
+    fn p2pk_transaction(pubkey: &str) -> Transaction {
+        let pubkey = pubkey.from_hex().unwrap();
+        let mut data = vec![1, 0, 0, 0, 1];
+        data.extend_from_slice(&[1; 32]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1]);
+        data.extend_from_slice(&[0xe8, 0x03, 0, 0, 0, 0, 0, 0]);
+        data.push(pubkey.len() as u8 + 2);
+        data.push(pubkey.len() as u8);
+        data.extend(pubkey);
+        data.extend_from_slice(&[0xac, 0, 0, 0, 0]);
+
+        let mut slice = &data[..];
+        let decoded = TransactionView::read(&mut slice, 0)
+            .unwrap()
+            .decode(&MAINNET);
+        Transaction::apply(decoded, &mut UtxoSet::new(), &mut TaintTracker::new())
+    }
+
+    #[test]
+    fn p2pk_outputs_linked_on_request() {
+        let mut transaction = p2pk_transaction(G_UNCOMPRESSED);
+        let output = &transaction.outputs[0];
+        assert_eq!(output.script_type, ScriptKind::Pubkey);
+        let p2pk = output.addresses[0].clone();
+        assert_eq!(p2pk.addr[0], P2PK_ADDRESS_VERSION);
+        assert_ne!(p2pk, key_address(G_UNCOMPRESSED));
+
+        // Kept apart from the P2PKH addresses of the key by default
+        let mut clusters = clusters("p2pk-unlinked");
+        clusters.link_p2pk = false;
+        clusters.on_transaction(&mut transaction);
+        assert!(clusters.clusters.contains(&p2pk));
+        assert_eq!(clusters.clusters.len(), 1);
+        assert!(clusters.key_addresses.is_empty());
+
+        // Joined with the P2PKH addresses of both forms of the key
+        let mut clusters = self::clusters("p2pk-linked");
+        clusters.on_transaction(&mut transaction);
+        assert_eq!(clusters.clusters.len(), 3);
+        assert!(clusters
+            .clusters
+            .in_union(&p2pk, &key_address(G_UNCOMPRESSED)));
+        assert!(clusters.clusters.in_union(&p2pk, &key_address(G)));
+        assert_eq!(clusters.key_addresses.len(), 2);
+    }
+
+    #[test]
+    fn link_multisig_keys() {
+        let mut clusters = clusters("link-multisig");
+        let keys = [
+            G.from_hex().unwrap(),
+            NEG_G_UNCOMPRESSED.from_hex().unwrap(),
+        ];
+        let multisig = Address::from_multisig(1, &[&keys[0], &keys[1]], None);
+        assert_eq!(multisig.addr[0], MULTISIG_ADDRESS_VERSION);
+        let output = output(
+            ScriptKind::Multisig,
+            multisig.clone(),
+            &[G, NEG_G_UNCOMPRESSED],
+        );
+        clusters.link_keys(&output, false);
+
+        // Only the listed form of each key
+        assert_eq!(clusters.clusters.len(), 3);
+        assert!(clusters.clusters.in_union(&multisig, &key_address(G)));
+        assert!(clusters
+            .clusters
+            .in_union(&multisig, &key_address(NEG_G_UNCOMPRESSED)));
+        assert!(!clusters.clusters.contains(&key_address(G_UNCOMPRESSED)));
+    }
+
+    #[test]
+    fn write_tainted_and_linked_addresses() {
+        let mut clusters = clusters("done");
+        let mut taints = VecDeque::new();
+        taints.push_back(Taint {
+            label: 1,
+            amount: 1000,
+        });
+        let pubkey = G.from_hex().unwrap();
+        let p2pk = Address::from_pubkey(&pubkey, P2PK_ADDRESS_VERSION, Some(taints));
+        clusters.link_keys(&output(ScriptKind::Pubkey, p2pk.clone(), &[G]), true);
+        // Untainted address joined to the cluster by a heuristic
+        let other = key_address(NEG_G);
+        clusters.clusters.union(p2pk.clone(), other.clone());
+        // Linked key of an untainted cluster
+        let untainted = Address::from_pubkey(&[0x04; 65], P2PK_ADDRESS_VERSION, None);
+        let output = output(ScriptKind::Pubkey, untainted, &[NEG_G_UNCOMPRESSED]);
+        clusters.link_keys(&output, false);
+
+        clusters.done();
+        let written = fs::read_to_string(&clusters.output).unwrap();
+        fs::remove_file(&clusters.output).unwrap();
+        let mut addresses: Vec<_> = written
+            .lines()
+            .map(|line| line.split(',').nth(2).unwrap().to_string())
+            .collect();
+        addresses.sort();
+        let mut expected = vec![
+            p2pk.to_string(),
+            key_address(G).to_string(),
+            key_address(G_UNCOMPRESSED).to_string(),
+        ];
+        expected.sort();
+        assert_eq!(addresses, expected);
+        assert!(written.lines().all(|line| line.starts_with("0,")));
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::transaction::Transaction;
+
+/// Coinbase reward of a block and the fees of its other transactions.
+pub struct BlockReward {
+    pub hash: Hash,
+    pub height: usize,
+    pub subsidy: u64,
+    /// Total amount of the coinbase outputs
+    pub reward: u64,
+    /// Sum of the fees, `None` when the fee of a transaction is not known
+    pub fees: Option<u64>,
+}
+
+impl BlockReward {
+    pub fn new(hash: Hash, height: usize, params: &ChainParams) -> Self {
+        BlockReward {
+            hash,
+            height,
+            subsidy: params.block_subsidy(height),
+            reward: 0,
+            fees: Some(0),
+        }
+    }
+
+    pub fn on_transaction(&mut self, transaction: &Transaction) {
+        if transaction.is_coinbase() {
+            self.reward += transaction.output_value();
+        } else {
+            self.fees = match (self.fees, transaction.fee()) {
+                (Some(fees), Some(fee)) => Some(fees + fee),
+                _ => None,
+            };
+        }
+    }
+
+    /// Whether the coinbase claims at most the subsidy and the fees, also
+    /// true when the fees are not known.
+    pub fn is_valid(&self) -> bool {
+        self.fees
+            .is_none_or(|fees| self.reward <= self.subsidy + fees)
+    }
+}
+
+/// Fee and feerate of every transaction, written as CSV. The first input
+/// address joins the rows with the cluster output.
+pub struct FeeReport {
+    path: String,
+    writer: LineWriter<File>,
+}
+
+impl FeeReport {
+    /// Creates the report, or appends to it when resuming.
+    pub fn new(path: &str, append: bool) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .expect("Unable to create fee report!");
+        let is_empty = file.metadata().map(|m| m.len() == 0).unwrap_or(true);
+
+        let mut writer = LineWriter::new(file);
+        if is_empty {
+            writeln!(writer, "txid,height,fee,vsize,feerate,address")
+                .expect("Unable to write to fee report!");
+        }
+        FeeReport {
+            path: path.to_string(),
+            writer,
+        }
+    }
+
+    /// Drops the rows after `height`, written after the checkpoint that is
+    /// resumed from, so no transaction is reported twice.
+    pub fn truncate(&mut self, height: usize) {
+        self.writer.flush().expect("Unable to write to fee report!");
+        let mut reader =
+            BufReader::new(File::open(&self.path).expect("Unable to read fee report!"));
+
+        let mut len = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader
+                .read_line(&mut line)
+                .expect("Unable to read fee report!");
+            // The header has no height and is always kept
+            let row_height = line.split(',').nth(1).and_then(|h| h.parse::<usize>().ok());
+            if n == 0 || !line.ends_with('\n') || row_height.is_some_and(|h| h > height) {
+                break;
+            }
+            len += n;
+        }
+        self.writer
+            .get_ref()
+            .set_len(len as u64)
+            .expect("Unable to write to fee report!");
+    }
+
+    /// Writes a transaction, fee and feerate are left empty when not known.
+    pub fn on_transaction(&mut self, height: usize, transaction: &Transaction) {
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{}",
+            transaction.txid,
+            height,
+            transaction.fee().map(|f| f.to_string()).unwrap_or_default(),
+            transaction.vsize(),
+            transaction
+                .feerate()
+                .map(|f| format!("{:.3}", f))
+                .unwrap_or_default(),
+            transaction
+                .inputs
+                .iter()
+                .flat_map(|input| input.addresses.first())
+                .next()
+                .map(|address| address.to_string())
+                .unwrap_or_default()
+        )
+        .expect("Unable to write to fee report!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::{MAINNET, REGTEST};
+    use blockchain::taint::TaintTracker;
+    use blockchain::transaction::TransactionView;
+    use blockchain::utxo::UtxoSet;
+    use rustc_serialize::hex::FromHex;
+    use std::env;
+    use std::fs;
+
+    /// Coinbase of mainnet block 9, paying 50 BTC to the public key of
+    /// 12cbQLTFMXRnSzktFkuoG3eHoMeFtpTu3S
+    const BLOCK_9_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000\
+                                    000000000000ffffffff0704ffff001d0134ffffffff0100f2052a0100000043\
+                                    410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a690\
+                                    9a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b4\
+                                    12a3ac00000000";
+
+    /// First transaction between two people, mainnet block 170, spending the
+    /// coinbase of block 9 without a fee
+    const BLOCK_170_TX: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce2585\
+                                7fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f77\
+                                32e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831c\
+                                c56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a\
+                                62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab3\
+                                7397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286b\
+                                ee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482eca\
+                                d7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b86\
+                                43f656b412a3ac00000000";
+
+    /// Native P2WPKH example of BIP143, spending 6.25 and 6 BTC
+    const BIP143_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541d\
+                             b4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd\
+                             3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc\
+                             22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa8\
+                             9e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000\
+                             001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000\
+                             001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203\
+                             609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a02205\
+                             73a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01210\
+                             25476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711\
+                             000000";
+
+    fn apply(hex: &str, utxos: &mut UtxoSet) -> Transaction {
+        let data = hex.from_hex().unwrap();
+        let decoded = TransactionView::read(&mut &data[..], 0)
+            .unwrap()
+            .decode(&MAINNET);
+        Transaction::apply(decoded, utxos, &mut TaintTracker::new())
+    }
+
+    #[test]
+    fn fee_and_feerate() {
+        let mut utxos = UtxoSet::new();
+        let coinbase = apply(BLOCK_9_COINBASE, &mut utxos);
+        assert_eq!(coinbase.fee(), None);
+        assert_eq!(coinbase.feerate(), None);
+
+        let tx = apply(BLOCK_170_TX, &mut utxos);
+        assert_eq!(tx.inputs[0].amount, Some(5_000_000_000));
+        assert_eq!((tx.fee(), tx.feerate()), (Some(0), Some(0.0)));
+        assert_eq!(tx.vsize(), 275);
+
+        // Spent outputs not in the unspent outputs
+        let mut tx = apply(BIP143_TX, &mut UtxoSet::new());
+        assert_eq!(tx.fee(), None);
+        tx.inputs[0].amount = Some(625_000_000);
+        assert_eq!(tx.fee(), None);
+        tx.inputs[1].amount = Some(600_000_000);
+        assert_eq!(tx.fee(), Some(889_210_000));
+        assert_eq!(tx.vsize(), 261);
+        assert_eq!(tx.feerate(), Some(889_210_000.0 / 261.0));
+
+        // More paid out than spent
+        tx.inputs[0].amount = Some(1);
+        tx.inputs[1].amount = Some(1);
+        assert_eq!(tx.fee(), None);
+    }
+
+    #[test]
+    fn block_subsidy() {
+        let heights = [
+            (0, 5_000_000_000),
+            (209_999, 5_000_000_000),
+            (210_000, 2_500_000_000),
+            (420_000, 1_250_000_000),
+            (630_000, 625_000_000),
+            (839_999, 625_000_000),
+            (840_000, 312_500_000),
+            (32 * 210_000, 1),
+            (33 * 210_000, 0),
+            // Shifting by 64 or more would overflow
+            (64 * 210_000, 0),
+            (usize::MAX, 0),
+        ];
+        for &(height, subsidy) in heights.iter() {
+            assert_eq!(MAINNET.block_subsidy(height), subsidy, "height {}", height);
+        }
+        assert_eq!(REGTEST.block_subsidy(149), 5_000_000_000);
+        assert_eq!(REGTEST.block_subsidy(150), 2_500_000_000);
+    }
+
+    #[test]
+    fn block_reward() {
+        let mut utxos = UtxoSet::new();
+        let coinbase = apply(BLOCK_9_COINBASE, &mut utxos);
+        let tx = apply(BLOCK_170_TX, &mut utxos);
+
+        let mut reward = BlockReward::new(coinbase.txid, 9, &MAINNET);
+        reward.on_transaction(&coinbase);
+        reward.on_transaction(&tx);
+        assert_eq!((reward.reward, reward.fees), (5_000_000_000, Some(0)));
+        assert!(reward.is_valid());
+
+        // Claiming the initial subsidy after the first halving
+        let mut reward = BlockReward::new(coinbase.txid, 210_000, &MAINNET);
+        reward.on_transaction(&coinbase);
+        assert!(!reward.is_valid());
+        // Unless unknown fees make up for it
+        reward.on_transaction(&apply(BIP143_TX, &mut UtxoSet::new()));
+        assert_eq!(reward.fees, None);
+        assert!(reward.is_valid());
+    }
+
+    #[test]
+    fn report_and_truncate() {
+        let path = env::temp_dir().join("blockfast-fees.csv");
+        let path = path.to_str().unwrap();
+        let mut utxos = UtxoSet::new();
+        let coinbase = apply(BLOCK_9_COINBASE, &mut utxos);
+        let tx = apply(BLOCK_170_TX, &mut utxos);
+
+        let mut report = FeeReport::new(path, false);
+        report.on_transaction(9, &coinbase);
+        report.on_transaction(170, &tx);
+        report.on_transaction(171, &tx);
+        drop(report);
+        let rows = [
+            "txid,height,fee,vsize,feerate,address",
+            "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9,9,,134,,",
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16,170,0,275,0.000,\
+             PACmZJr6QSuyRqRebqEKvwcZR6p8yPgCaV",
+        ];
+        let contents = fs::read_to_string(path).unwrap();
+        assert_eq!(contents.lines().take(3).collect::<Vec<_>>(), rows);
+        assert_eq!(contents.lines().count(), 4);
+
+        // Resuming from a checkpoint at height 170 after a partial write
+        fs::write(path, format!("{}f4184fc5,172", contents)).unwrap();
+        let mut report = FeeReport::new(path, true);
+        report.truncate(170);
+        report.on_transaction(171, &tx);
+        drop(report);
+        let contents = fs::read_to_string(path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[..3], rows);
+        assert!(lines[3].starts_with("f4184fc5") && lines[3].contains(",171,"));
+    }
+}
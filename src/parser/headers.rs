@@ -0,0 +1,426 @@
+use fasthash::{xx, RandomState};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use vec_map::VecMap;
+
+use blockchain::block::Block;
+use blockchain::hash::{Hash, ZERO_HASH};
+use blockchain::params::ChainParams;
+use parser::blockchain::{BlockPos, Blockchain, DamagedRange, Scanned};
+use parser::ParseError;
+
+/// A block header found in the blk files.
+#[derive(Clone, Debug)]
+pub struct HeaderEntry {
+    pub hash: Hash,
+    pub prev_hash: Hash,
+    pub timestamp: u32,
+    pub work: u128,
+    pub file: usize,
+    pub offset: usize,
+    /// Height and cumulative chainwork, set once the header connects to genesis
+    pub height: Option<usize>,
+    pub chainwork: u128,
+    seq: usize,
+}
+
+/// Header tree built from a first pass over all blk files, used to select
+/// the best chain independently of the order blocks were written in.
+pub struct HeaderTree {
+    pub entries: HashMap<Hash, HeaderEntry, RandomState<xx::Hash64>>,
+    /// Blocks building on each block
+    children: HashMap<Hash, Vec<Hash>, RandomState<xx::Hash64>>,
+    /// Headers inserted since the tree was last connected
+    pending: Vec<Hash>,
+    /// Number of headers with a height
+    connected: usize,
+    tip: Option<Hash>,
+    /// Offset up to which each blk file has been scanned
+    scanned: VecMap<usize>,
+    /// Regions of the blk files without a readable block
+    pub damaged: Vec<DamagedRange>,
+}
+
+impl HeaderTree {
+    pub fn new() -> Self {
+        HeaderTree {
+            entries: HashMap::with_hasher(RandomState::<xx::Hash64>::new()),
+            children: HashMap::with_hasher(RandomState::<xx::Hash64>::new()),
+            pending: Vec::new(),
+            connected: 0,
+            tip: None,
+            scanned: VecMap::new(),
+            damaged: Vec::new(),
+        }
+    }
+
+    /// Reads the headers of all blocks appended to the blk files since the
+    /// last scan and reselects the best chain tip.
+    pub fn scan(&mut self, blockchain: &Blockchain, params: &ChainParams) {
+        let count = self.entries.len();
+
+        let last_file = blockchain.last_file().unwrap_or(0);
+        for (n, file) in blockchain.maps.iter() {
+            let scanned = *self.scanned.entry(n).or_insert(0);
+            if scanned >= file.len() {
+                continue;
+            }
+
+            debug!("Scanning block file {}/{}", n, last_file);
+            let (end, error) = file.scan(scanned, params, |scanned| match scanned {
+                Scanned::Block(offset, block) => self.insert(&block, n, offset),
+                Scanned::Damaged(start, next) | Scanned::Overlapping(start, next) => {
+                    self.skip(n, start, next)
+                }
+            });
+            match error {
+                // Zero padding or an incomplete block, read again on the next
+                // scan as bitcoind only appends to the last file
+                None | Some(ParseError::Eof) if n == last_file => {
+                    self.scanned.insert(n, end);
+                }
+                error => {
+                    if error.is_some() {
+                        self.skip(n, end, file.len());
+                    }
+                    self.scanned.insert(n, file.len());
+                }
+            }
+        }
+
+        if self.entries.len() == count {
+            return;
+        }
+
+        self.connect(blockchain, params);
+        if let Some(tip) = self.tip.and_then(|hash| self.entries.get(&hash)) {
+            info!(
+                "Found {} block headers, best chain tip {} at height {}",
+                self.entries.len(),
+                tip.hash,
+                tip.height.unwrap()
+            );
+        }
+    }
+
+    /// Logs a summary of all regions skipped while scanning.
+    pub fn report_damaged(&self) {
+        if self.damaged.is_empty() {
+            return;
+        }
+        let skipped: usize = self.damaged.iter().map(|r| r.end - r.start).sum();
+        warn!(
+            "Skipped {} damaged regions in the block files, {} bytes in total:",
+            self.damaged.len(),
+            skipped
+        );
+        for range in &self.damaged {
+            warn!("  {}", range);
+        }
+    }
+
+    fn skip(&mut self, file: usize, start: usize, end: usize) {
+        let range = DamagedRange { file, start, end };
+        warn!(
+            "Skipping {} bytes of invalid data in {}",
+            end - start,
+            range
+        );
+        self.damaged.push(range);
+    }
+
+    pub fn insert(&mut self, block: &Block, file: usize, offset: usize) {
+        let header = block.header();
+        let hash = header.cur_hash();
+        if self.entries.contains_key(&hash) {
+            return;
+        }
+
+        let seq = self.entries.len();
+        let prev_hash = *header.prev_hash();
+        if prev_hash != ZERO_HASH {
+            self.children.entry(prev_hash).or_default().push(hash);
+        }
+        self.pending.push(hash);
+        self.entries.insert(
+            hash,
+            HeaderEntry {
+                hash,
+                prev_hash,
+                timestamp: header.timestamp(),
+                work: header.work(),
+                file,
+                offset,
+                height: None,
+                chainwork: 0,
+                seq,
+            },
+        );
+    }
+
+    /// Computes heights and chainwork from genesis of the headers inserted
+    /// since the last call and of the earlier ones they connect, and selects
+    /// the tip with the most work, the first seen header wins a tie. Without a
+    /// genesis block (pruned node) the blocks with missing parents are used
+    /// as roots, at the height found in their coinbase.
+    pub fn connect(&mut self, blockchain: &Blockchain, params: &ChainParams) {
+        let pending = mem::take(&mut self.pending);
+        let mut queue: VecDeque<(Hash, usize, u128)> = VecDeque::new();
+
+        for hash in &pending {
+            let entry = &self.entries[hash];
+            if entry.prev_hash == ZERO_HASH {
+                queue.push_back((entry.hash, 0, 0));
+            } else if let Some(parent) = self.entries.get(&entry.prev_hash) {
+                if let Some(height) = parent.height {
+                    queue.push_back((entry.hash, height + 1, parent.chainwork));
+                }
+            }
+        }
+
+        if queue.is_empty() && self.connected == 0 {
+            let mut roots: Vec<&HeaderEntry> = pending
+                .iter()
+                .map(|hash| &self.entries[hash])
+                .filter(|e| !self.entries.contains_key(&e.prev_hash))
+                .collect();
+            roots.sort_by_key(|e| e.seq);
+
+            for entry in roots {
+                let pos = BlockPos {
+                    hash: entry.hash,
+                    height: 0,
+                    file: entry.file,
+                    offset: entry.offset,
+                    undo: None,
+                };
+                match blockchain
+                    .block_at(&pos, params)
+                    .ok()
+                    .and_then(|data| Block(&data).coinbase_height())
+                {
+                    Some(height) => queue.push_back((entry.hash, height, 0)),
+                    None => debug!("Unable to find the height of block {}", entry.hash),
+                }
+            }
+        }
+
+        while let Some((hash, height, parent_work)) = queue.pop_front() {
+            let (chainwork, seq) = {
+                let entry = self.entries.get_mut(&hash).unwrap();
+                if entry.height.is_none() {
+                    self.connected += 1;
+                }
+                entry.height = Some(height);
+                entry.chainwork = parent_work.saturating_add(entry.work);
+                (entry.chainwork, entry.seq)
+            };
+
+            let better = match self.tip.map(|tip| &self.entries[&tip]) {
+                Some(tip) => {
+                    chainwork > tip.chainwork || (chainwork == tip.chainwork && seq < tip.seq)
+                }
+                None => true,
+            };
+            if better {
+                self.tip = Some(hash);
+            }
+
+            if let Some(next) = self.children.get(&hash) {
+                for child in next {
+                    queue.push_back((*child, height + 1, chainwork));
+                }
+            }
+        }
+
+        if self.connected < self.entries.len() {
+            warn!(
+                "{} block headers do not connect to genesis",
+                self.entries.len() - self.connected
+            );
+        }
+    }
+
+    /// Blocks of the chain with the most work in height order.
+    pub fn best_chain(&self) -> Vec<BlockPos> {
+        let mut chain = Vec::new();
+        let mut cur = self.tip.and_then(|hash| self.entries.get(&hash));
+
+        while let Some(entry) = cur {
+            chain.push(BlockPos {
+                hash: entry.hash,
+                height: entry.height.unwrap(),
+                file: entry.file,
+                offset: entry.offset,
+                undo: None,
+            });
+            cur = self.entries.get(&entry.prev_hash);
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+impl Default for HeaderTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block::work;
+    use blockchain::params::REGTEST;
+    use std::env;
+    use std::fs;
+
+    const EASY_BITS: u32 = 0x207fffff;
+    const HARD_BITS: u32 = 0x1d00ffff;
+
+    /// Version 2 block with a coinbase committing to `height`.
+    fn block(prev_hash: &Hash, bits: u32, nonce: u8, height: u8) -> Vec<u8> {
+        let mut data = vec![2, 0, 0, 0];
+        data.extend_from_slice(&prev_hash[..]);
+        data.extend_from_slice(&[0; 36]);
+        data.extend_from_slice(&[
+            bits as u8,
+            (bits >> 8) as u8,
+            (bits >> 16) as u8,
+            (bits >> 24) as u8,
+        ]);
+        data.extend_from_slice(&[nonce, 0, 0, 0]);
+        // One coinbase pushing the height, with an empty output
+        data.extend_from_slice(&[1, 1, 0, 0, 0, 1]);
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 2, 1, height]);
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 1]);
+        data.extend_from_slice(&[0; 13]);
+        data
+    }
+
+    fn insert(tree: &mut HeaderTree, data: &[u8], offset: usize) -> Hash {
+        let block = Block(data);
+        tree.insert(&block, 0, offset);
+        block.header().cur_hash()
+    }
+
+    /// Blocks directory with the `blocks` in blk00000.dat.
+    fn blockchain(name: &str, blocks: &[&[u8]]) -> Blockchain {
+        let dir = env::temp_dir().join(format!("blockfast-headers-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = Vec::new();
+        for block in blocks {
+            file.extend_from_slice(&[0xfa, 0xbf, 0xb5, 0xda]);
+            file.extend_from_slice(&[block.len() as u8, 0, 0, 0]);
+            file.extend_from_slice(block);
+        }
+        fs::write(dir.join("blk00000.dat"), file).unwrap();
+        Blockchain::new(&dir.to_string_lossy(), 0, false)
+    }
+
+    fn heights(tree: &HeaderTree) -> Vec<(Hash, usize)> {
+        tree.best_chain()
+            .iter()
+            .map(|pos| (pos.hash, pos.height))
+            .collect()
+    }
+
+    #[test]
+    fn work_from_bits() {
+        // Genesis difficulty on mainnet and regtest
+        assert_eq!(work(HARD_BITS), 0x0100010001);
+        assert_eq!(work(EASY_BITS), 2);
+        // Mainnet block 100000, difficulty 14484.16
+        assert_eq!(work(0x1b04864c), 62_209_952_899_966);
+        // Zero and negative targets
+        assert_eq!(work(0x1d000000), 0);
+        assert_eq!(work(0x1d80ffff), 0);
+        // Work saturates for a target of 1 and is zero beyond 2^256
+        assert_eq!(work(0x03000001), u128::MAX);
+        assert_eq!(work(0x2100ffff), 1);
+        assert_eq!(work(0x2300ffff), 0);
+    }
+
+    #[test]
+    fn most_work_wins_over_length() {
+        let chain = blockchain("fork", &[]);
+        let mut tree = HeaderTree::new();
+        let genesis = insert(&mut tree, &block(&ZERO_HASH, EASY_BITS, 0, 0), 0);
+        let a1 = insert(&mut tree, &block(&genesis, EASY_BITS, 1, 1), 100);
+        let a2 = insert(&mut tree, &block(&a1, EASY_BITS, 1, 2), 200);
+        let b1 = insert(&mut tree, &block(&genesis, HARD_BITS, 2, 1), 300);
+        tree.connect(&chain, &REGTEST);
+
+        assert_eq!(heights(&tree), [(genesis, 0), (b1, 1)]);
+        assert_eq!(tree.entries[&a2].height, Some(2));
+        assert_eq!(tree.entries[&a2].chainwork, 6);
+        assert_eq!(tree.entries[&b1].chainwork, 2 + 0x0100010001);
+    }
+
+    #[test]
+    fn tie_goes_to_first_seen() {
+        let chain = blockchain("tie", &[]);
+        let genesis = block(&ZERO_HASH, EASY_BITS, 0, 0);
+        let genesis_hash = Block(&genesis).header().cur_hash();
+        let a1 = block(&genesis_hash, EASY_BITS, 1, 1);
+        let b1 = block(&genesis_hash, EASY_BITS, 2, 1);
+
+        for &(first, second) in [(&a1, &b1), (&b1, &a1)].iter() {
+            let mut tree = HeaderTree::new();
+            insert(&mut tree, &genesis, 0);
+            let first = insert(&mut tree, first, 100);
+            insert(&mut tree, second, 200);
+            tree.connect(&chain, &REGTEST);
+            assert_eq!(tree.best_chain().last().unwrap().hash, first);
+        }
+    }
+
+    #[test]
+    fn connects_new_headers_and_orphans() {
+        let chain = blockchain("incremental", &[]);
+        let mut tree = HeaderTree::new();
+        let genesis = insert(&mut tree, &block(&ZERO_HASH, EASY_BITS, 0, 0), 0);
+        let a1 = insert(&mut tree, &block(&genesis, EASY_BITS, 0, 1), 100);
+        tree.connect(&chain, &REGTEST);
+        assert_eq!(heights(&tree), [(genesis, 0), (a1, 1)]);
+
+        // Written before its parent
+        let a2_data = block(&a1, EASY_BITS, 0, 2);
+        let a2 = Block(&a2_data).header().cur_hash();
+        let a3 = insert(&mut tree, &block(&a2, EASY_BITS, 0, 3), 300);
+        tree.connect(&chain, &REGTEST);
+        assert_eq!(heights(&tree), [(genesis, 0), (a1, 1)]);
+        assert_eq!(tree.entries[&a3].height, None);
+
+        insert(&mut tree, &a2_data, 200);
+        // A later fork with the same work does not replace the tip
+        let b1 = insert(&mut tree, &block(&genesis, EASY_BITS, 1, 1), 400);
+        let b2 = insert(&mut tree, &block(&b1, EASY_BITS, 1, 2), 500);
+        insert(&mut tree, &block(&b2, EASY_BITS, 1, 3), 600);
+        tree.connect(&chain, &REGTEST);
+        assert_eq!(heights(&tree), [(genesis, 0), (a1, 1), (a2, 2), (a3, 3)]);
+        assert_eq!(tree.entries[&a3].chainwork, 8);
+        assert_eq!(tree.connected, 7);
+    }
+
+    #[test]
+    fn pruned_roots_use_coinbase_height() {
+        let root = block(&Hash([7; 32]), EASY_BITS, 0, 200);
+        let root_hash = Block(&root).header().cur_hash();
+        let child = block(&root_hash, EASY_BITS, 0, 201);
+        // Root without a height in its coinbase
+        let mut stale = block(&Hash([8; 32]), EASY_BITS, 0, 0);
+        stale[0] = 1;
+        let chain = blockchain("pruned", &[&stale, &root, &child]);
+
+        let mut tree = HeaderTree::new();
+        tree.scan(&chain, &REGTEST);
+        let stale_hash = Block(&stale).header().cur_hash();
+        let child_hash = Block(&child).header().cur_hash();
+        assert_eq!(heights(&tree), [(root_hash, 200), (child_hash, 201)]);
+        assert_eq!(tree.entries[&stale_hash].height, None);
+        assert_eq!(tree.best_chain()[0].offset, stale.len() + 8);
+    }
+}
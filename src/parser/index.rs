@@ -0,0 +1,314 @@
+use fasthash::{xx, RandomState};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use blockchain::block::work;
+use blockchain::buffer::*;
+use blockchain::hash::{Hash, ZERO_HASH};
+use parser::blockchain::BlockPos;
+use parser::leveldb::LevelDb;
+use parser::{ParseError, ParseResult};
+
+const BLOCK_VALID_MASK: u64 = 0x07;
+const BLOCK_VALID_SCRIPTS: u64 = 5;
+const BLOCK_HAVE_DATA: u64 = 0x08;
+const BLOCK_HAVE_UNDO: u64 = 0x10;
+const BLOCK_FAILED_MASK: u64 = 0x60;
+
+/// A block index record of Bitcoin Core's `blocks/index` database.
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub hash: Hash,
+    pub prev_hash: Hash,
+    pub height: usize,
+    pub status: u64,
+    pub tx_count: u64,
+    pub file: Option<usize>,
+    pub data_pos: Option<usize>,
+    pub undo_pos: Option<usize>,
+    pub timestamp: u32,
+    pub bits: u32,
+}
+
+pub struct BlockIndex {
+    pub entries: HashMap<Hash, IndexEntry, RandomState<xx::Hash64>>,
+}
+
+impl BlockIndex {
+    pub fn new(index_dir: &Path) -> io::Result<BlockIndex> {
+        let mut entries: HashMap<Hash, IndexEntry, RandomState<xx::Hash64>> =
+            HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+
+        let db = LevelDb::open(index_dir)?;
+        for (key, value) in db.iter()? {
+            if key.len() != 33 || key[0] != b'b' {
+                continue;
+            }
+            let hash = *Hash::from_slice(array_ref!(key, 1, 32));
+            match IndexEntry::read(hash, &mut &value[..]) {
+                Ok(entry) => {
+                    entries.insert(hash, entry);
+                }
+                Err(_) => warn!("Invalid block index record for block {}", hash),
+            }
+        }
+
+        info!("Loaded {} block index records", entries.len());
+        Ok(BlockIndex { entries })
+    }
+
+    /// Walks back from the fully validated block with the most work and
+    /// returns the stored blocks of the best chain in height order. Like
+    /// bitcoind, the block received first wins a tie, which is the one
+    /// written first to the blk files.
+    pub fn best_chain(&self) -> Vec<BlockPos> {
+        let chainwork = self.chainwork();
+        let tip = self
+            .entries
+            .values()
+            .filter(|e| {
+                e.status & BLOCK_FAILED_MASK == 0
+                    && e.status & BLOCK_VALID_MASK >= BLOCK_VALID_SCRIPTS
+            })
+            .max_by_key(|e| {
+                let pos = (
+                    e.file.unwrap_or(usize::MAX),
+                    e.data_pos.unwrap_or(usize::MAX),
+                );
+                (chainwork[&e.hash], Reverse(pos), Reverse(e.hash))
+            });
+
+        let mut chain = Vec::new();
+        let mut missing = 0;
+        let mut cur = tip;
+        while let Some(entry) = cur {
+            // The data position points past the magic and block length
+            match (
+                entry.file,
+                entry.data_pos.and_then(|pos| pos.checked_sub(8)),
+            ) {
+                (Some(file), Some(offset)) if entry.status & BLOCK_HAVE_DATA != 0 => {
+                    chain.push(BlockPos {
+                        hash: entry.hash,
+                        height: entry.height,
+                        file,
+                        offset,
+                        undo: entry.undo_pos.and_then(|pos| pos.checked_sub(8)),
+                    })
+                }
+                _ => missing += 1,
+            }
+            if entry.prev_hash == ZERO_HASH {
+                break;
+            }
+            cur = self.entries.get(&entry.prev_hash);
+        }
+        chain.reverse();
+
+        if missing > 0 {
+            warn!(
+                "{} best chain blocks are not stored (pruned node?)",
+                missing
+            );
+        }
+        chain
+    }
+
+    /// Cumulative work of each block, the records only store the height.
+    fn chainwork(&self) -> HashMap<Hash, u128, RandomState<xx::Hash64>> {
+        let mut entries: Vec<&IndexEntry> = self.entries.values().collect();
+        entries.sort_by_key(|e| e.height);
+
+        let mut chainwork: HashMap<Hash, u128, RandomState<xx::Hash64>> =
+            HashMap::with_capacity_and_hasher(entries.len(), RandomState::<xx::Hash64>::new());
+        for entry in entries {
+            let parent_work = chainwork.get(&entry.prev_hash).cloned().unwrap_or(0);
+            chainwork.insert(entry.hash, parent_work.saturating_add(work(entry.bits)));
+        }
+        chainwork
+    }
+}
+
+impl IndexEntry {
+    pub fn read(hash: Hash, slice: &mut &[u8]) -> ParseResult<IndexEntry> {
+        let _version = read_msb_varint(slice)?;
+        let height = read_msb_varint(slice)? as usize;
+        let status = read_msb_varint(slice)?;
+        let tx_count = read_msb_varint(slice)?;
+
+        let mut file = None;
+        if status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) != 0 {
+            file = Some(read_msb_varint(slice)? as usize);
+        }
+        let mut data_pos = None;
+        if status & BLOCK_HAVE_DATA != 0 {
+            data_pos = Some(read_msb_varint(slice)? as usize);
+        }
+        let mut undo_pos = None;
+        if status & BLOCK_HAVE_UNDO != 0 {
+            undo_pos = Some(read_msb_varint(slice)? as usize);
+        }
+
+        // Positions can't point into the magic and length of the first record
+        if data_pos.into_iter().chain(undo_pos).any(|pos| pos < 8) {
+            return Err(ParseError::Invalid);
+        }
+
+        // Block header
+        let _version = read_u32(slice)?;
+        let prev_hash = *Hash::from_slice(array_ref!(read_slice(slice, 32)?, 0, 32));
+        let _merkle_root = read_slice(slice, 32)?;
+        let timestamp = read_u32(slice)?;
+        let bits = read_u32(slice)?;
+
+        Ok(IndexEntry {
+            hash,
+            prev_hash,
+            height,
+            status,
+            tx_count,
+            file,
+            data_pos,
+            undo_pos,
+            timestamp,
+            bits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASY_BITS: u32 = 0x207fffff;
+    const HARD_BITS: u32 = 0x1d00ffff;
+
+    fn header(prev_hash: Hash, bits: u32) -> Vec<u8> {
+        let mut header = vec![2, 0, 0, 0];
+        header.extend_from_slice(&prev_hash[..]);
+        header.extend_from_slice(&[0; 32]);
+        header.extend_from_slice(&[0x00, 0x10, 0x5e, 0x5f]);
+        header.extend_from_slice(&[
+            bits as u8,
+            (bits >> 8) as u8,
+            (bits >> 16) as u8,
+            (bits >> 24) as u8,
+        ]);
+        header.extend_from_slice(&[0; 4]);
+        header
+    }
+
+    fn entry(hash: u8, prev_hash: u8, height: usize, bits: u32, data_pos: usize) -> IndexEntry {
+        IndexEntry {
+            hash: Hash([hash; 32]),
+            prev_hash: if height == 0 {
+                ZERO_HASH
+            } else {
+                Hash([prev_hash; 32])
+            },
+            height,
+            status: BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA,
+            tx_count: 1,
+            file: Some(0),
+            data_pos: Some(data_pos),
+            undo_pos: None,
+            timestamp: 0,
+            bits,
+        }
+    }
+
+    fn best_chain(entries: Vec<IndexEntry>) -> Vec<u8> {
+        let index = BlockIndex {
+            entries: entries.into_iter().map(|e| (e.hash, e)).collect(),
+        };
+        index.best_chain().iter().map(|pos| pos.hash[0]).collect()
+    }
+
+    #[test]
+    fn read_record() {
+        let mut data = vec![
+            0x8e, 0xee, 0x20, // version 260000
+            0x81, 0x2c, // height 300
+            0x1d, // valid scripts, data and undo stored
+            0x80, 0x00, // 128 transactions
+            0xff, 0x7f, // file 16511
+            0x80, 0x80, 0x00, // data position 16512
+            0x7f, // undo position 127
+        ];
+        data.extend(header(Hash([1; 32]), EASY_BITS));
+
+        let entry = IndexEntry::read(ZERO_HASH, &mut &data[..]).unwrap();
+        assert_eq!(entry.height, 300);
+        assert_eq!(entry.status, 0x1d);
+        assert_eq!(entry.tx_count, 128);
+        assert_eq!(entry.file, Some(16511));
+        assert_eq!(entry.data_pos, Some(16512));
+        assert_eq!(entry.undo_pos, Some(127));
+        assert_eq!(entry.prev_hash, Hash([1; 32]));
+        assert_eq!(entry.timestamp, 0x5f5e1000);
+        assert_eq!(entry.bits, EASY_BITS);
+
+        for len in 0..data.len() - 4 {
+            assert!(IndexEntry::read(ZERO_HASH, &mut &data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_positions_before_first_record() {
+        for &(data_pos, undo_pos) in [(7, 8), (8, 7), (0, 100)].iter() {
+            let mut data = vec![0x8e, 0xee, 0x20, 0x81, 0x2c, 0x1d, 0x01, 0x00];
+            data.extend_from_slice(&[data_pos, undo_pos]);
+            data.extend(header(Hash([1; 32]), EASY_BITS));
+            assert!(IndexEntry::read(ZERO_HASH, &mut &data[..]).is_err());
+        }
+
+        let mut data = vec![0x8e, 0xee, 0x20, 0x81, 0x2c, 0x1d, 0x01, 0x00, 0x08, 0x08];
+        data.extend(header(Hash([1; 32]), EASY_BITS));
+        let entry = IndexEntry::read(ZERO_HASH, &mut &data[..]).unwrap();
+        assert_eq!((entry.data_pos, entry.undo_pos), (Some(8), Some(8)));
+    }
+
+    #[test]
+    fn read_header_only_record() {
+        // Valid tree without data or undo, so no file and positions
+        let mut data = vec![0x8e, 0xee, 0x20, 0x7f, 0x03, 0x00];
+        data.extend(header(Hash([2; 32]), HARD_BITS));
+
+        let entry = IndexEntry::read(ZERO_HASH, &mut &data[..]).unwrap();
+        assert_eq!(entry.height, 127);
+        assert_eq!(entry.tx_count, 0);
+        assert_eq!(
+            (entry.file, entry.data_pos, entry.undo_pos),
+            (None, None, None)
+        );
+        assert_eq!(entry.prev_hash, Hash([2; 32]));
+        assert_eq!(entry.bits, HARD_BITS);
+    }
+
+    #[test]
+    fn best_chain_has_most_work() {
+        let chain = best_chain(vec![
+            entry(1, 0, 0, EASY_BITS, 8),
+            entry(2, 1, 1, EASY_BITS, 100),
+            entry(3, 2, 2, EASY_BITS, 200),
+            entry(4, 3, 3, EASY_BITS, 300),
+            entry(5, 1, 1, HARD_BITS, 400),
+        ]);
+        assert_eq!(chain, [1, 5]);
+    }
+
+    #[test]
+    fn best_chain_tie_goes_to_first_written() {
+        for _ in 0..10 {
+            let chain = best_chain(vec![
+                entry(1, 0, 0, EASY_BITS, 8),
+                entry(2, 1, 1, EASY_BITS, 300),
+                entry(3, 1, 1, EASY_BITS, 200),
+                entry(4, 1, 1, EASY_BITS, 400),
+            ]);
+            assert_eq!(chain, [1, 3]);
+        }
+    }
+}
@@ -0,0 +1,612 @@
+use memmap::Mmap;
+use snap::raw::Decoder;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use blockchain::buffer::*;
+use parser::{ParseError, ParseResult};
+
+const LOG_BLOCK_SIZE: usize = 32768;
+const LOG_HEADER_SIZE: usize = 7;
+const TABLE_FOOTER_SIZE: usize = 48;
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+const CRC_MASK_DELTA: u32 = 0xa282ead8;
+
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+
+/// Entries of a table or of the logs, by internal key.
+type Entries = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)>>;
+
+/// Read-only access to a LevelDB database as written by Bitcoin Core.
+///
+/// Only the files referenced by the current MANIFEST are read, and entries
+/// are merged by sequence number, so deleted and overwritten keys never
+/// show up while iterating.
+pub struct LevelDb {
+    tables: Vec<PathBuf>,
+    logs: Vec<PathBuf>,
+}
+
+/// Iterator over the live entries of a database in key order.
+pub struct DbIter {
+    heap: BinaryHeap<HeapEntry>,
+    sources: Vec<Entries>,
+    last_key: Option<Vec<u8>>,
+}
+
+struct HeapEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    source: usize,
+}
+
+struct TableIter {
+    mmap: Mmap,
+    index: BlockIter,
+    block: Option<BlockIter>,
+}
+
+struct BlockIter {
+    data: Vec<u8>,
+    pos: usize,
+    end: usize,
+    key: Vec<u8>,
+}
+
+impl LevelDb {
+    pub fn open(path: &Path) -> io::Result<LevelDb> {
+        let mut current = String::new();
+        File::open(path.join("CURRENT"))?.read_to_string(&mut current)?;
+        let manifest = read_file(&path.join(current.trim()))?;
+
+        let mut files: BTreeSet<(u64, u64)> = BTreeSet::new();
+        let mut log_number = 0;
+        let mut prev_log_number = 0;
+
+        for record in read_log_records(&manifest) {
+            let mut slice = &record[..];
+            read_version_edit(
+                &mut slice,
+                &mut files,
+                &mut log_number,
+                &mut prev_log_number,
+            )
+            .map_err(|_| invalid_data("corrupted MANIFEST"))?;
+        }
+
+        let mut tables = Vec::new();
+        for (_, number) in files {
+            let ldb = path.join(format!("{:06}.ldb", number));
+            let sst = path.join(format!("{:06}.sst", number));
+            tables.push(if ldb.exists() { ldb } else { sst });
+        }
+
+        let mut logs: Vec<(u64, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".log") {
+                continue;
+            }
+            if let Ok(number) = name[..name.len() - 4].parse::<u64>() {
+                if number >= log_number || number == prev_log_number {
+                    logs.push((number, entry.path()));
+                }
+            }
+        }
+        logs.sort();
+
+        Ok(LevelDb {
+            tables,
+            logs: logs.into_iter().map(|(_, path)| path).collect(),
+        })
+    }
+
+    pub fn iter(&self) -> io::Result<DbIter> {
+        let mut sources: Vec<Entries> = Vec::new();
+
+        for path in &self.tables {
+            let file = File::open(path)?;
+            let mmap = unsafe { Mmap::map(&file)? };
+            let table = TableIter::new(mmap)
+                .map_err(|_| invalid_data(&format!("corrupted table {}", path.display())))?;
+            sources.push(Box::new(table));
+        }
+
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for path in &self.logs {
+            for record in read_log_records(&read_file(path)?) {
+                if read_write_batch(&mut &record[..], &mut entries).is_err() {
+                    warn!("Skipping corrupted batch in {}", path.display());
+                }
+            }
+        }
+        entries.sort_by(|a, b| compare_internal_keys(&a.0, &b.0));
+        sources.push(Box::new(entries.into_iter()));
+
+        let mut heap = BinaryHeap::new();
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some((key, value)) = iter.next() {
+                heap.push(HeapEntry { key, value, source });
+            }
+        }
+
+        Ok(DbIter {
+            heap,
+            sources,
+            last_key: None,
+        })
+    }
+}
+
+impl Iterator for DbIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        while let Some(entry) = self.heap.pop() {
+            if let Some((key, value)) = self.sources[entry.source].next() {
+                self.heap.push(HeapEntry {
+                    key,
+                    value,
+                    source: entry.source,
+                });
+            }
+
+            if entry.key.len() < 8 {
+                continue;
+            }
+            let (user_key, trailer) = entry.key.split_at(entry.key.len() - 8);
+
+            // Newer entries of a key come first, older ones are shadowed
+            if self.last_key.as_ref().is_some_and(|k| k[..] == *user_key) {
+                continue;
+            }
+            self.last_key = Some(user_key.to_vec());
+
+            if trailer[0] == TYPE_VALUE {
+                let mut key = entry.key;
+                key.truncate(key.len() - 8);
+                return Some((key, entry.value));
+            }
+        }
+        None
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &HeapEntry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    // BinaryHeap is a max-heap, the smallest internal key has to come out first
+    fn cmp(&self, other: &HeapEntry) -> Ordering {
+        compare_internal_keys(&other.key, &self.key)
+    }
+}
+
+impl TableIter {
+    fn new(mmap: Mmap) -> ParseResult<TableIter> {
+        if mmap.len() < TABLE_FOOTER_SIZE {
+            return Err(ParseError::Invalid);
+        }
+        let mut footer = &mmap[mmap.len() - TABLE_FOOTER_SIZE..];
+        let _metaindex = read_block_handle(&mut footer)?;
+        let index_handle = read_block_handle(&mut footer)?;
+        let mut magic = &mmap[mmap.len() - 8..];
+        if read_u64(&mut magic)? != TABLE_MAGIC {
+            return Err(ParseError::Invalid);
+        }
+
+        let index = BlockIter::new(read_block(&mmap, index_handle)?)?;
+        Ok(TableIter {
+            mmap,
+            index,
+            block: None,
+        })
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        loop {
+            if let Some(entry) = self.block.as_mut().and_then(|block| block.next()) {
+                return Some(entry);
+            }
+
+            let (_, handle) = self.index.next()?;
+            let block = read_block_handle(&mut &handle[..])
+                .and_then(|handle| read_block(&self.mmap, handle))
+                .and_then(BlockIter::new);
+            match block {
+                Ok(block) => self.block = Some(block),
+                Err(_) => {
+                    warn!("Skipping corrupted table block");
+                    self.block = None;
+                }
+            }
+        }
+    }
+}
+
+impl BlockIter {
+    fn new(data: Vec<u8>) -> ParseResult<BlockIter> {
+        if data.len() < 4 {
+            return Err(ParseError::Invalid);
+        }
+        let num_restarts = read_u32(&mut &data[data.len() - 4..])? as usize;
+        let end = data
+            .len()
+            .checked_sub(4 + 4 * num_restarts)
+            .ok_or(ParseError::Invalid)?;
+
+        Ok(BlockIter {
+            data,
+            pos: 0,
+            end,
+            key: Vec::new(),
+        })
+    }
+
+    fn read_entry(&mut self) -> ParseResult<(Vec<u8>, Vec<u8>)> {
+        let slice = &mut &self.data[self.pos..self.end];
+        let shared = read_leb128(slice)? as usize;
+        let non_shared = read_leb128(slice)? as usize;
+        let value_len = read_leb128(slice)? as usize;
+        if shared > self.key.len() {
+            return Err(ParseError::Invalid);
+        }
+
+        self.key.truncate(shared);
+        self.key.extend_from_slice(read_slice(slice, non_shared)?);
+        let value = read_slice(slice, value_len)?.to_vec();
+        self.pos = self.end - slice.len();
+
+        Ok((self.key.clone(), value))
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.pos >= self.end {
+            return None;
+        }
+        match self.read_entry() {
+            Ok(entry) => Some(entry),
+            Err(_) => {
+                self.pos = self.end;
+                None
+            }
+        }
+    }
+}
+
+/// Orders internal keys by user key ascending, then by sequence number descending.
+fn compare_internal_keys(a: &[u8], b: &[u8]) -> Ordering {
+    if a.len() < 8 || b.len() < 8 {
+        return a.cmp(b);
+    }
+    let (a_key, mut a_trailer) = a.split_at(a.len() - 8);
+    let (b_key, mut b_trailer) = b.split_at(b.len() - 8);
+    a_key.cmp(b_key).then_with(|| {
+        let a_seq = read_u64(&mut a_trailer).unwrap_or(0);
+        let b_seq = read_u64(&mut b_trailer).unwrap_or(0);
+        b_seq.cmp(&a_seq)
+    })
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reassembles the records of a log file (used by both .log and MANIFEST files).
+fn read_log_records(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut record: Vec<u8> = Vec::new();
+    let mut pos = 0;
+
+    while pos + LOG_HEADER_SIZE <= data.len() {
+        let block_end = pos + LOG_BLOCK_SIZE - pos % LOG_BLOCK_SIZE;
+        if block_end - pos < LOG_HEADER_SIZE {
+            pos = block_end;
+            continue;
+        }
+
+        let len = data[pos + 4] as usize | (data[pos + 5] as usize) << 8;
+        let kind = data[pos + 6];
+        let start = pos + LOG_HEADER_SIZE;
+        if start + len > data.len() {
+            break;
+        }
+        let fragment = &data[start..start + len];
+        pos = start + len;
+
+        match kind {
+            // Full
+            1 => records.push(fragment.to_vec()),
+            // First
+            2 => record = fragment.to_vec(),
+            // Middle
+            3 => record.extend_from_slice(fragment),
+            // Last
+            4 => {
+                record.extend_from_slice(fragment);
+                records.push(mem::take(&mut record));
+            }
+            // Zero padding of preallocated files
+            _ => pos = block_end,
+        }
+    }
+    records
+}
+
+fn read_write_batch(slice: &mut &[u8], entries: &mut Vec<(Vec<u8>, Vec<u8>)>) -> ParseResult<()> {
+    let sequence = read_u64(slice)?;
+    let count = read_u32(slice)?;
+
+    for n in 0..count as u64 {
+        let kind = read_u8(slice)?;
+        let key_len = read_leb128(slice)? as usize;
+        let mut key = read_slice(slice, key_len)?.to_vec();
+        let value = match kind {
+            TYPE_VALUE => {
+                let value_len = read_leb128(slice)? as usize;
+                read_slice(slice, value_len)?.to_vec()
+            }
+            TYPE_DELETION => Vec::new(),
+            _ => return Err(ParseError::Invalid),
+        };
+
+        let trailer = ((sequence + n) << 8) | kind as u64;
+        for i in 0..8 {
+            key.push((trailer >> (8 * i)) as u8);
+        }
+        entries.push((key, value));
+    }
+    Ok(())
+}
+
+fn read_version_edit(
+    slice: &mut &[u8],
+    files: &mut BTreeSet<(u64, u64)>,
+    log_number: &mut u64,
+    prev_log_number: &mut u64,
+) -> ParseResult<()> {
+    while !slice.is_empty() {
+        match read_leb128(slice)? {
+            // Comparator name
+            1 => {
+                let len = read_leb128(slice)? as usize;
+                read_slice(slice, len)?;
+            }
+            2 => *log_number = read_leb128(slice)?,
+            // Next file number, last sequence
+            3 | 4 => {
+                read_leb128(slice)?;
+            }
+            // Compact pointer
+            5 => {
+                read_leb128(slice)?;
+                let len = read_leb128(slice)? as usize;
+                read_slice(slice, len)?;
+            }
+            // Deleted file
+            6 => {
+                let level = read_leb128(slice)?;
+                let number = read_leb128(slice)?;
+                files.remove(&(level, number));
+            }
+            // New file
+            7 => {
+                let level = read_leb128(slice)?;
+                let number = read_leb128(slice)?;
+                let _size = read_leb128(slice)?;
+                for _ in 0..2 {
+                    let len = read_leb128(slice)? as usize;
+                    read_slice(slice, len)?;
+                }
+                files.insert((level, number));
+            }
+            9 => *prev_log_number = read_leb128(slice)?,
+            _ => return Err(ParseError::Invalid),
+        }
+    }
+    Ok(())
+}
+
+fn read_block_handle(slice: &mut &[u8]) -> ParseResult<(usize, usize)> {
+    let offset = read_leb128(slice)? as usize;
+    let size = read_leb128(slice)? as usize;
+    Ok((offset, size))
+}
+
+fn read_block(data: &[u8], handle: (usize, usize)) -> ParseResult<Vec<u8>> {
+    let (offset, size) = handle;
+    // Block contents are followed by their type byte and checksum
+    let end = offset
+        .checked_add(size)
+        .filter(|end| end.saturating_add(5) <= data.len())
+        .ok_or(ParseError::Eof)?;
+    let contents = &data[offset..end];
+    let crc = read_u32(&mut &data[end + 1..end + 5])?;
+    if crc != mask_crc(crc32c(&data[offset..end + 1])) {
+        return Err(ParseError::Invalid);
+    }
+    match data[end] {
+        0 => Ok(contents.to_vec()),
+        1 => Decoder::new()
+            .decompress_vec(contents)
+            .map_err(|_| ParseError::Invalid),
+        _ => Err(ParseError::Invalid),
+    }
+}
+
+/// Masks a checksum the way LevelDB stores it.
+fn mask_crc(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(CRC_MASK_DELTA)
+}
+
+/// CRC-32C (Castagnoli) of the data.
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Table 000004.ldb holds k01 to k08 in a snappy compressed and a raw
+    /// data block, 000005.log overwrites k02, deletes k05 and adds k09.
+    /// 000003.ldb is not in the MANIFEST and must be ignored.
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/leveldb")
+    }
+
+    #[test]
+    fn merges_tables_and_logs() {
+        let db = LevelDb::open(&fixture()).unwrap();
+        let entries: Vec<(String, String)> = db
+            .iter()
+            .unwrap()
+            .map(|(key, value)| {
+                (
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap(),
+                )
+            })
+            .collect();
+        let expected = [
+            ("k01", "v01"),
+            ("k02", "new"),
+            ("k03", "v03"),
+            ("k04", "v04"),
+            ("k06", "v06"),
+            ("k07", "v07"),
+            ("k08", "v08"),
+            ("k09", "v09"),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for (entry, &(key, value)) in entries.iter().zip(expected.iter()) {
+            assert_eq!((&entry.0[..], &entry.1[..]), (key, value));
+        }
+    }
+
+    #[test]
+    fn reads_raw_and_snappy_blocks() {
+        let data = read_file(&fixture().join("000004.ldb")).unwrap();
+        let footer = &mut &data[data.len() - TABLE_FOOTER_SIZE..];
+        let _meta = read_block_handle(footer).unwrap();
+        let index = read_block(&data, read_block_handle(footer).unwrap()).unwrap();
+
+        let mut kinds = Vec::new();
+        let mut keys = Vec::new();
+        for (_, handle) in BlockIter::new(index).unwrap() {
+            let handle = read_block_handle(&mut &handle[..]).unwrap();
+            kinds.push(data[handle.0 + handle.1]);
+            for (key, _) in BlockIter::new(read_block(&data, handle).unwrap()).unwrap() {
+                keys.push(key[..key.len() - 8].to_vec());
+            }
+        }
+        assert_eq!(kinds, [1, 0]);
+        assert_eq!(keys.len(), 8);
+        assert_eq!(keys[0], b"k01");
+        assert_eq!(keys[7], b"k08");
+    }
+
+    #[test]
+    fn rejects_bad_block_handles_and_checksums() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a9136aa);
+
+        let mut data = read_file(&fixture().join("000004.ldb")).unwrap();
+        let footer = &mut &data[data.len() - TABLE_FOOTER_SIZE..];
+        let _meta = read_block_handle(footer).unwrap();
+        let (offset, size) = read_block_handle(footer).unwrap();
+        assert!(read_block(&data, (offset, size)).is_ok());
+
+        assert!(read_block(&data, (offset, data.len())).is_err());
+        assert!(read_block(&data, (offset, usize::MAX)).is_err());
+        assert!(read_block(&data, (usize::MAX, size)).is_err());
+        assert!(read_block(&data, (usize::MAX - 4, 0)).is_err());
+
+        data[offset] ^= 1;
+        assert!(read_block(&data, (offset, size)).is_err());
+        data[offset] ^= 1;
+        data[offset + size + 4] ^= 1;
+        assert!(read_block(&data, (offset, size)).is_err());
+    }
+
+    #[test]
+    fn reassembles_fragmented_log_records() {
+        let record: Vec<u8> = (0..LOG_BLOCK_SIZE + 100).map(|n| n as u8).collect();
+        let mut data = Vec::new();
+        let first = LOG_BLOCK_SIZE - LOG_HEADER_SIZE;
+        for &(kind, fragment) in [(2u8, &record[..first]), (4, &record[first..])].iter() {
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.push(fragment.len() as u8);
+            data.push((fragment.len() >> 8) as u8);
+            data.push(kind);
+            data.extend_from_slice(fragment);
+        }
+        // Short record and a block tail too small for a header
+        let tail = LOG_BLOCK_SIZE - data.len() % LOG_BLOCK_SIZE - LOG_HEADER_SIZE - 3;
+        data.extend_from_slice(&[0, 0, 0, 0, tail as u8, (tail >> 8) as u8, 1]);
+        data.extend(vec![7u8; tail]);
+        data.extend_from_slice(&[0, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 9]);
+
+        let records = read_log_records(&data);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], record);
+        assert_eq!(records[1], vec![7u8; tail]);
+        assert_eq!(records[2], [9]);
+    }
+}
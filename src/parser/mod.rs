@@ -1,15 +1,33 @@
 use clap::{App, Arg};
 use std::result;
+use std::thread;
+use time;
+
+use blockchain::params::{ChainParams, NETWORKS};
 
 pub mod blockchain;
+pub mod blocks;
+pub mod chainstate;
+pub mod checkpoint;
 pub mod clusters;
+pub mod fees;
+pub mod headers;
+pub mod index;
+pub mod leveldb;
+pub mod p2p;
 pub mod parser;
+pub mod rpc;
+pub mod stale;
 pub mod union;
+pub mod verify;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const BLOCKS_DIR: &'static str = "~/.bitcoin/blocks";
-const OUTPUT: &'static str = "clusters.csv";
-const QUEUE_SIZE: usize = 1000;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const BLOCKS_DIR: &str = "~/.bitcoin/blocks";
+const OUTPUT: &str = "clusters.csv";
+const NETWORK: &str = "mainnet";
+const QUEUE_SIZE: &str = "1000";
+const CHECKPOINT_INTERVAL: &str = "10000";
+const POLL_INTERVAL: &str = "10";
 
 pub type Result<T> = result::Result<T, EofError>;
 
@@ -18,7 +36,7 @@ pub type ParseResult<T> = result::Result<T, ParseError>;
 #[derive(Debug)]
 pub struct EofError;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ParseError {
     Eof,
     Invalid,
@@ -36,6 +54,30 @@ pub struct Config {
     pub output: String,
     pub max_block: usize,
     pub queue_size: usize,
+    pub threads: usize,
+    pub params: &'static ChainParams,
+    pub block_index: bool,
+    pub undo: bool,
+    pub stale_report: Option<String>,
+    pub fee_report: Option<String>,
+    pub checkpoint_dir: Option<String>,
+    pub checkpoint_interval: usize,
+    pub resume: bool,
+    pub chainstate: Option<String>,
+    pub follow: bool,
+    pub poll_interval: u64,
+    pub start_height: usize,
+    pub end_height: Option<usize>,
+    pub since: Option<u32>,
+    pub until: Option<u32>,
+    pub rpc_url: Option<String>,
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<String>,
+    pub rpc_cookie: Option<String>,
+    pub peer: Option<String>,
+    pub verify: Option<String>,
+    pub link_multisig_keys: bool,
+    pub link_p2pk: bool,
 }
 
 impl Config {
@@ -73,6 +115,170 @@ impl Config {
                     .short("m")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("network")
+                    .help("Sets the network the blocks belong to")
+                    .long("network")
+                    .short("n")
+                    .takes_value(true)
+                    .possible_values(&NETWORKS.iter().map(|p| p.name).collect::<Vec<_>>())
+                    .default_value(NETWORK),
+            )
+            .arg(
+                Arg::with_name("rpc_url")
+                    .help("Fetches blocks from a bitcoind JSON-RPC server instead of the blocks directory")
+                    .long("rpc-url")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("rpc_user")
+                    .help("Sets the JSON-RPC user name")
+                    .long("rpc-user")
+                    .takes_value(true)
+                    .requires("rpc_url"),
+            )
+            .arg(
+                Arg::with_name("rpc_password")
+                    .help("Sets the JSON-RPC password")
+                    .long("rpc-password")
+                    .takes_value(true)
+                    .requires("rpc_user"),
+            )
+            .arg(
+                Arg::with_name("rpc_cookie")
+                    .help("Reads the JSON-RPC credentials from a bitcoind .cookie file")
+                    .long("rpc-cookie")
+                    .takes_value(true)
+                    .requires("rpc_url")
+                    .conflicts_with("rpc_user"),
+            )
+            .arg(
+                Arg::with_name("peer")
+                    .help("Downloads blocks from a node over the P2P protocol (host[:port])")
+                    .long("peer")
+                    .takes_value(true)
+                    .conflicts_with("rpc_url"),
+            )
+            .arg(
+                Arg::with_name("threads")
+                    .help("Number of block decoding threads (defaults to the number of CPUs)")
+                    .long("threads")
+                    .short("t")
+                    .takes_value(true)
+                    .validator(is_positive),
+            )
+            .arg(
+                Arg::with_name("queue_size")
+                    .help("Maximum number of blocks queued between the processing threads")
+                    .long("queue-size")
+                    .takes_value(true)
+                    .default_value(QUEUE_SIZE),
+            )
+            .arg(
+                Arg::with_name("verify")
+                    .help("Verifies the blk files and writes a per-file report instead of clustering")
+                    .long("verify")
+                    .takes_value(true)
+                    .conflicts_with_all(&["rpc_url", "peer"]),
+            )
+            .arg(
+                Arg::with_name("block_index")
+                    .help("Orders blocks using the bitcoind block index (blocks/index)")
+                    .long("block-index"),
+            )
+            .arg(
+                Arg::with_name("undo")
+                    .help("Resolves inputs from the rev*.dat undo files instead of keeping all unspent outputs in memory")
+                    .long("undo")
+                    .requires("block_index")
+                    .conflicts_with_all(&["rpc_url", "peer"]),
+            )
+            .arg(
+                Arg::with_name("stale_report")
+                    .help("Writes stale blocks found in the blk files to a CSV or JSON file")
+                    .long("stale-report")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("fee_report")
+                    .help("Writes the fee, feerate and first input address of every transaction to a CSV file")
+                    .long("fee-report")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("link_multisig_keys")
+                    .help("Clusters bare multisig outputs with the P2PKH addresses of their keys")
+                    .long("link-multisig-keys"),
+            )
+            .arg(
+                Arg::with_name("link_p2pk")
+                    .help("Clusters P2PK outputs with the P2PKH addresses of the compressed and uncompressed key")
+                    .long("link-p2pk"),
+            )
+            .arg(
+                Arg::with_name("checkpoint_dir")
+                    .help("Saves the parser state to this directory periodically")
+                    .long("checkpoint-dir")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("checkpoint_interval")
+                    .help("Number of blocks between checkpoints")
+                    .long("checkpoint-interval")
+                    .takes_value(true)
+                    .validator(is_positive)
+                    .default_value(CHECKPOINT_INTERVAL),
+            )
+            .arg(
+                Arg::with_name("resume")
+                    .help("Continues from the last checkpoint")
+                    .long("resume")
+                    .requires("checkpoint_dir"),
+            )
+            .arg(
+                Arg::with_name("chainstate")
+                    .help("Starts after the best block of a bitcoind chainstate directory, with its unspent outputs")
+                    .long("chainstate")
+                    .takes_value(true)
+                    .conflicts_with_all(&["resume", "undo"]),
+            )
+            .arg(
+                Arg::with_name("follow")
+                    .help("Keeps running and processes blocks appended by bitcoind")
+                    .long("follow")
+                    .short("f"),
+            )
+            .arg(
+                Arg::with_name("poll_interval")
+                    .help("Seconds between checks for new blocks in follow mode")
+                    .long("poll-interval")
+                    .takes_value(true)
+                    .default_value(POLL_INTERVAL),
+            )
+            .arg(
+                Arg::with_name("start_height")
+                    .help("Applies clustering and taints from this block height")
+                    .long("start-height")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("end_height")
+                    .help("Stops after this block height")
+                    .long("end-height")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("since")
+                    .help("Applies clustering and taints to blocks from this date (YYYY-MM-DD [HH:MM:SS] UTC or unix time)")
+                    .long("since")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("until")
+                    .help("Stops before blocks from this date (YYYY-MM-DD [HH:MM:SS] UTC or unix time)")
+                    .long("until")
+                    .takes_value(true),
+            )
             .get_matches();
 
         let blocks_dir = matches.value_of("blocks_dir").unwrap().as_bytes().to_vec();
@@ -81,7 +287,7 @@ impl Config {
         let output = matches.value_of("output").unwrap().as_bytes().to_vec();
         let output = String::from_utf8(output).expect("Found invalid UTF-8");
 
-        let input = matches.value_of("input").unwrap().as_bytes().to_vec();
+        let input = matches.value_of("input").unwrap_or("").as_bytes().to_vec();
         let input = String::from_utf8(input).expect("Found invalid UTF-8");
 
         let max_block = match matches.value_of("max_block") {
@@ -89,12 +295,89 @@ impl Config {
             None => (0),
         };
 
+        let threads = match matches.value_of("threads") {
+            Some(threads) => threads.parse().expect("Invalid number of threads"),
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+
+        let queue_size = matches
+            .value_of("queue_size")
+            .unwrap()
+            .parse()
+            .expect("Invalid queue size");
+
+        let checkpoint_interval = matches
+            .value_of("checkpoint_interval")
+            .unwrap()
+            .parse()
+            .expect("Invalid checkpoint interval");
+
+        let poll_interval = matches
+            .value_of("poll_interval")
+            .unwrap()
+            .parse()
+            .expect("Invalid poll interval");
+
+        let start_height = match matches.value_of("start_height") {
+            Some(height) => height.parse().expect("Invalid start height"),
+            None => 0,
+        };
+        let end_height = matches
+            .value_of("end_height")
+            .map(|height| height.parse().expect("Invalid end height"));
+        let since = matches.value_of("since").map(parse_date);
+        let until = matches.value_of("until").map(parse_date);
+
+        let params = ChainParams::from_name(matches.value_of("network").unwrap()).unwrap();
+
         Config {
-            blocks_dir: blocks_dir,
-            input: input,
-            output: output,
-            max_block: max_block,
-            queue_size: QUEUE_SIZE,
+            blocks_dir,
+            input,
+            output,
+            max_block,
+            queue_size,
+            threads,
+            params,
+            block_index: matches.is_present("block_index"),
+            undo: matches.is_present("undo"),
+            stale_report: matches.value_of("stale_report").map(|s| s.to_string()),
+            fee_report: matches.value_of("fee_report").map(|s| s.to_string()),
+            checkpoint_dir: matches.value_of("checkpoint_dir").map(|s| s.to_string()),
+            checkpoint_interval,
+            resume: matches.is_present("resume"),
+            chainstate: matches.value_of("chainstate").map(|s| s.to_string()),
+            follow: matches.is_present("follow"),
+            poll_interval,
+            start_height,
+            end_height,
+            since,
+            until,
+            rpc_url: matches.value_of("rpc_url").map(|s| s.to_string()),
+            rpc_user: matches.value_of("rpc_user").map(|s| s.to_string()),
+            rpc_password: matches.value_of("rpc_password").map(|s| s.to_string()),
+            rpc_cookie: matches.value_of("rpc_cookie").map(|s| s.to_string()),
+            peer: matches.value_of("peer").map(|s| s.to_string()),
+            verify: matches.value_of("verify").map(|s| s.to_string()),
+            link_multisig_keys: matches.is_present("link_multisig_keys"),
+            link_p2pk: matches.is_present("link_p2pk"),
         }
     }
 }
+
+fn is_positive(value: String) -> result::Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
+/// Parses a UTC date or a unix timestamp.
+fn parse_date(date: &str) -> u32 {
+    if let Ok(timestamp) = date.parse() {
+        return timestamp;
+    }
+    let tm = time::strptime(date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| time::strptime(date, "%Y-%m-%d"))
+        .expect("Invalid date, expected YYYY-MM-DD [HH:MM:SS]");
+    tm.to_timespec().sec as u32
+}
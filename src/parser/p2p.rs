@@ -0,0 +1,464 @@
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use blockchain::block::Block;
+use blockchain::buffer::*;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+
+const PROTOCOL_VERSION: i32 = 70016;
+const USER_AGENT: &str = concat!("/blockfast:", env!("CARGO_PKG_VERSION"), "/");
+const MAX_PAYLOAD: usize = 32 * 1024 * 1024;
+const MSG_WITNESS_BLOCK: u32 = 0x4000_0002;
+/// Headers sent at most in reply to `getheaders`
+const MAX_HEADERS: u64 = 2000;
+/// Blocks requested with a single `getdata` message
+const BLOCKS_PER_REQUEST: usize = 16;
+const TIMEOUT: u64 = 120;
+
+/// A connection to a single Bitcoin node speaking the P2P protocol.
+pub struct Peer {
+    stream: TcpStream,
+    params: &'static ChainParams,
+}
+
+impl Peer {
+    /// Connects to `host[:port]` and performs the version handshake.
+    pub fn connect(address: &str, params: &'static ChainParams) -> io::Result<Peer> {
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, params.p2p_port)
+        };
+        let stream = TcpStream::connect(&address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+
+        let mut peer = Peer { stream, params };
+        peer.send("version", &version_payload())?;
+
+        let mut version = false;
+        let mut verack = false;
+        while !(version && verack) {
+            let (command, payload) = peer.receive()?;
+            match command.as_str() {
+                "version" => {
+                    let mut slice = &payload[..];
+                    let peer_version = read_u32(&mut slice).map_err(|_| invalid_data("version"))?;
+                    info!(
+                        "Connected to peer {}, protocol version {}",
+                        address, peer_version
+                    );
+                    version = true;
+                    peer.send("verack", &[])?;
+                }
+                "verack" => verack = true,
+                _ => {}
+            }
+        }
+        Ok(peer)
+    }
+
+    /// Extends `chain` with the headers of the peer's best chain, replacing
+    /// blocks the peer no longer considers part of it. Returns the number of
+    /// headers received.
+    pub fn sync_headers(&mut self, chain: &mut Vec<Hash>) -> io::Result<usize> {
+        let mut received = 0;
+
+        loop {
+            let mut payload = Vec::new();
+            payload.write_i32::<LittleEndian>(PROTOCOL_VERSION)?;
+            let locator = locator(chain, &self.params.genesis_hash);
+            write_var_int(&mut payload, locator.len() as u64)?;
+            for hash in locator {
+                payload.write_all(&hash)?;
+            }
+            payload.write_all(&[0u8; 32])?;
+            self.send("getheaders", &payload)?;
+
+            let headers = self.wait_for("headers")?;
+            let mut slice = &headers[..];
+            let count = read_var_int(&mut slice).map_err(|_| invalid_data("headers"))?;
+            if count == 0 {
+                if chain.is_empty() {
+                    chain.push(self.params.genesis_hash);
+                }
+                return Ok(received);
+            }
+
+            for _ in 0..count {
+                let header = read_slice(&mut slice, 81).map_err(|_| invalid_data("headers"))?;
+                let block = Block(&header[..80]);
+                let header = block.header();
+                let prev_hash = *header.prev_hash();
+
+                if chain.is_empty() {
+                    if prev_hash != self.params.genesis_hash {
+                        warn!(
+                            "Peer genesis block {} differs from the {} genesis block",
+                            prev_hash, self.params.name
+                        );
+                    }
+                    chain.push(prev_hash);
+                }
+                if chain.last() != Some(&prev_hash) {
+                    match chain.iter().rposition(|hash| *hash == prev_hash) {
+                        Some(n) => {
+                            warn!("Peer switched to a different chain at height {}", n + 1);
+                            chain.truncate(n + 1);
+                        }
+                        None => return Err(invalid_data("headers not connecting to the chain")),
+                    }
+                }
+                chain.push(header.cur_hash());
+                received += 1;
+            }
+            debug!("Synced block headers up to height {}", chain.len() - 1);
+            if count < MAX_HEADERS {
+                return Ok(received);
+            }
+        }
+    }
+
+    /// Downloads the blocks with the given hashes, calling `f` with each
+    /// serialized block in the same order.
+    pub fn blocks<F>(&mut self, hashes: &[Hash], mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&Hash, Vec<u8>) -> bool,
+    {
+        for batch in hashes.chunks(BLOCKS_PER_REQUEST) {
+            let mut payload = Vec::new();
+            write_var_int(&mut payload, batch.len() as u64)?;
+            for hash in batch {
+                payload.write_u32::<LittleEndian>(MSG_WITNESS_BLOCK)?;
+                payload.write_all(hash)?;
+            }
+            self.send("getdata", &payload)?;
+
+            let mut received: HashMap<Hash, Vec<u8>> = HashMap::new();
+            for hash in batch {
+                while !received.contains_key(hash) {
+                    let (command, payload) = self.receive()?;
+                    match command.as_str() {
+                        "block" if payload.len() >= 80 => {
+                            let hash = Block(&payload).header().cur_hash();
+                            received.insert(hash, payload);
+                        }
+                        "notfound" => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("peer does not have block {} (pruned?)", hash),
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+                if !f(hash, received.remove(hash).unwrap()) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_for(&mut self, expected: &str) -> io::Result<Vec<u8>> {
+        loop {
+            let (command, payload) = self.receive()?;
+            if command == expected {
+                return Ok(payload);
+            }
+        }
+    }
+
+    /// Reads the next message, answering pings on the way.
+    fn receive(&mut self) -> io::Result<(String, Vec<u8>)> {
+        loop {
+            let mut header = [0u8; 24];
+            self.stream.read_exact(&mut header)?;
+            let mut slice = &header[..];
+            let magic = read_u32(&mut slice).unwrap();
+            if magic != self.params.magic {
+                return Err(invalid_data("message magic"));
+            }
+            let command = read_slice(&mut slice, 12).unwrap();
+            let command: String = command
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as char)
+                .collect();
+            let len = read_u32(&mut slice).unwrap() as usize;
+            let checksum = read_slice(&mut slice, 4).unwrap();
+            if len > MAX_PAYLOAD {
+                return Err(invalid_data("message size"));
+            }
+
+            let mut payload = vec![0u8; len];
+            self.stream.read_exact(&mut payload)?;
+            if checksum != &Hash::from_data(&payload)[..4] {
+                return Err(invalid_data("message checksum"));
+            }
+
+            if command == "ping" {
+                self.send("pong", &payload)?;
+                continue;
+            }
+            return Ok((command, payload));
+        }
+    }
+
+    fn send(&mut self, command: &str, payload: &[u8]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(24 + payload.len());
+        message.write_u32::<LittleEndian>(self.params.magic)?;
+        let mut name = [0u8; 12];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+        message.write_all(&name)?;
+        message.write_u32::<LittleEndian>(payload.len() as u32)?;
+        message.write_all(&Hash::from_data(payload)[..4])?;
+        message.write_all(payload)?;
+        self.stream.write_all(&message)
+    }
+}
+
+fn version_payload() -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    // Only used by the peer to detect connections to itself
+    let nonce = (now.subsec_nanos() as u64) << 32 | process::id() as u64;
+
+    let mut payload = Vec::new();
+    payload.write_i32::<LittleEndian>(PROTOCOL_VERSION).unwrap();
+    // No services offered
+    payload.write_u64::<LittleEndian>(0).unwrap();
+    payload
+        .write_i64::<LittleEndian>(now.as_secs() as i64)
+        .unwrap();
+    for _ in 0..2 {
+        // Receiving and sending network addresses, left empty
+        payload.write_u64::<LittleEndian>(0).unwrap();
+        payload.write_all(&[0u8; 16]).unwrap();
+        payload.write_u16::<BigEndian>(0).unwrap();
+    }
+    payload.write_u64::<LittleEndian>(nonce).unwrap();
+    write_var_int(&mut payload, USER_AGENT.len() as u64).unwrap();
+    payload.write_all(USER_AGENT.as_bytes()).unwrap();
+    // Start height and no transaction relay
+    payload.write_i32::<LittleEndian>(0).unwrap();
+    payload.write_u8(0).unwrap();
+    payload
+}
+
+/// Block locator with the last ten blocks, then exponentially fewer back
+/// to genesis.
+fn locator(chain: &[Hash], genesis_hash: &Hash) -> Vec<Hash> {
+    let mut locator = Vec::new();
+    let mut step = 1;
+    let mut n = chain.len() as isize - 1;
+    while n > 0 {
+        locator.push(chain[n as usize]);
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        n -= step;
+    }
+    locator.push(chain.first().cloned().unwrap_or(*genesis_hash));
+    locator
+}
+
+fn write_var_int<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    match n {
+        0..=0xfc => w.write_u8(n as u8),
+        0xfd..=0xffff => {
+            w.write_u8(0xfd)?;
+            w.write_u16::<LittleEndian>(n as u16)
+        }
+        0x10000..=0xffff_ffff => {
+            w.write_u8(0xfe)?;
+            w.write_u32::<LittleEndian>(n as u32)
+        }
+        _ => {
+            w.write_u8(0xff)?;
+            w.write_u64::<LittleEndian>(n)
+        }
+    }
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {} from peer", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::{MAINNET, REGTEST};
+    use byteorder::ByteOrder;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// Runs `script` as the peer of the first incoming connection.
+    fn scripted_peer<F>(script: F) -> (String, JoinHandle<()>)
+    where
+        F: FnOnce(&mut TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            script(&mut stream);
+        });
+        (address, handle)
+    }
+
+    fn message(magic: u32, command: &str, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.write_u32::<LittleEndian>(magic).unwrap();
+        let mut name = [0u8; 12];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+        message.write_all(&name).unwrap();
+        message
+            .write_u32::<LittleEndian>(payload.len() as u32)
+            .unwrap();
+        message.write_all(&Hash::from_data(payload)[..4]).unwrap();
+        message.write_all(payload).unwrap();
+        message
+    }
+
+    fn read_message(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut header = [0u8; 24];
+        stream.read_exact(&mut header).unwrap();
+        let command = header[4..16]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+        let len = LittleEndian::read_u32(&header[16..20]);
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (command, payload)
+    }
+
+    fn handshake(stream: &mut TcpStream) {
+        let (command, payload) = read_message(stream);
+        assert_eq!(command, "version");
+        assert_eq!(LittleEndian::read_i32(&payload[..4]), PROTOCOL_VERSION);
+        stream
+            .write_all(&message(REGTEST.magic, "version", &version_payload()))
+            .unwrap();
+        stream
+            .write_all(&message(REGTEST.magic, "verack", &[]))
+            .unwrap();
+        assert_eq!(read_message(stream).0, "verack");
+    }
+
+    /// Header on top of `prev_hash`, the nonce makes each one distinct.
+    fn header(prev_hash: &Hash, nonce: u32) -> [u8; 80] {
+        let mut header = [0u8; 80];
+        header[0] = 1;
+        header[4..36].copy_from_slice(prev_hash);
+        (&mut header[76..])
+            .write_u32::<LittleEndian>(nonce)
+            .unwrap();
+        header
+    }
+
+    #[test]
+    fn handshake_headers_and_blocks() {
+        let first = header(&REGTEST.genesis_hash, 1);
+        let second = header(&Hash::from_data(&first), 2);
+        let (address, peer) = scripted_peer(move |stream| {
+            handshake(stream);
+
+            let (command, payload) = read_message(stream);
+            assert_eq!(command, "getheaders");
+            // Version, a locator with the genesis block only and no stop hash
+            assert_eq!(payload[4], 1);
+            assert_eq!(&payload[5..37], &REGTEST.genesis_hash[..]);
+            let mut headers = vec![2];
+            for header in &[first, second] {
+                headers.extend_from_slice(header);
+                headers.push(0);
+            }
+            let ping = message(REGTEST.magic, "ping", &[7; 8]);
+            stream.write_all(&ping).unwrap();
+            stream
+                .write_all(&message(REGTEST.magic, "headers", &headers))
+                .unwrap();
+            assert_eq!(read_message(stream), ("pong".to_string(), vec![7; 8]));
+
+            let (command, payload) = read_message(stream);
+            assert_eq!(command, "getdata");
+            assert_eq!(payload[0], 2);
+            assert_eq!(LittleEndian::read_u32(&payload[1..5]), MSG_WITNESS_BLOCK);
+            // Blocks may arrive in any order
+            for header in &[second, first] {
+                let block = [&header[..], &[0]].concat();
+                stream
+                    .write_all(&message(REGTEST.magic, "block", &block))
+                    .unwrap();
+            }
+        });
+
+        let mut peer_conn = Peer::connect(&address, &REGTEST).unwrap();
+        let mut chain = Vec::new();
+        assert_eq!(peer_conn.sync_headers(&mut chain).unwrap(), 2);
+        assert_eq!(
+            chain,
+            vec![
+                REGTEST.genesis_hash,
+                Hash::from_data(&first),
+                Hash::from_data(&second)
+            ]
+        );
+
+        let mut blocks = Vec::new();
+        peer_conn
+            .blocks(&chain[1..], |hash, block| {
+                blocks.push((*hash, block));
+                true
+            })
+            .unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].0, chain[1]);
+        assert_eq!(&blocks[0].1[..80], &first[..]);
+        assert_eq!(blocks[1].0, chain[2]);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let (address, peer) = scripted_peer(|stream| {
+            handshake(stream);
+            read_message(stream);
+            let mut headers = message(REGTEST.magic, "headers", &[0]);
+            headers[20] ^= 0xff;
+            stream.write_all(&headers).unwrap();
+        });
+
+        let mut peer_conn = Peer::connect(&address, &REGTEST).unwrap();
+        let err = peer_conn.sync_headers(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn wrong_network_magic() {
+        let (address, peer) = scripted_peer(|stream| {
+            read_message(stream);
+            stream
+                .write_all(&message(MAINNET.magic, "version", &version_payload()))
+                .unwrap();
+        });
+
+        let err = Peer::connect(&address, &REGTEST).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("magic"));
+        peer.join().unwrap();
+    }
+}
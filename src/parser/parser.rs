@@ -1,23 +1,61 @@
-use fasthash::{xx, RandomState};
+use crossbeam_channel as channel;
+use crossbeam_utils::thread::scope;
 use rustc_serialize::hex::FromHex;
+use std::cmp;
 use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
-use vec_map::VecMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use blockchain::address::{Address, Taint};
-use blockchain::block::{Block, Transactions};
-use blockchain::hash::{Hash, ZERO_HASH};
+use blockchain::address::Taint;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::taint::TaintTracker;
 use blockchain::transaction::Transaction;
-use parser::blockchain::Blockchain;
+use blockchain::utxo::UtxoSet;
+use parser::blockchain::{BlockPos, Blockchain};
+use parser::blocks::{decode_blocks, BlockMessage, Blocks, DecodeError, DecodedBlock, Remote};
+use parser::chainstate::Chainstate;
+use parser::checkpoint::{Checkpoint, CheckpointState, Mode};
 use parser::clusters::Clusters;
+use parser::fees::{BlockReward, FeeReport};
+use parser::headers::HeaderTree;
+use parser::index::BlockIndex;
+use parser::p2p::Peer;
+use parser::rpc::RpcClient;
+use parser::stale::StaleReport;
 use parser::Config;
 
+/// Number of blocks the median time past is computed over
+const MEDIAN_TIME_SPAN: usize = 11;
+/// Number of processed blocks remembered to locate the fork point of a reorg
+const REORG_HISTORY: usize = 100;
+
 pub struct Parser {
     input_path: String,
     blocks_dir: String,
     max_block: usize,
+    params: &'static ChainParams,
+    block_index: bool,
+    undo: bool,
+    stale_report: Option<String>,
+    fee_report: Option<FeeReport>,
+    checkpoint_dir: Option<String>,
+    checkpoint_interval: usize,
+    resume: bool,
+    chainstate: Option<String>,
+    follow: bool,
+    poll_interval: u64,
+    queue_size: usize,
+    threads: usize,
+    range: BlockRange,
+    /// Last processed blocks, oldest first
+    recent_blocks: VecDeque<BlockPos>,
+    finished: bool,
+    rpc: Option<RpcClient>,
+    peer: Option<String>,
     labels: HashMap<String, u8>,
     clusters: Clusters,
 }
@@ -28,148 +66,500 @@ impl Parser {
         let blocks_dir = &config.blocks_dir;
         let max_block = config.max_block;
 
+        let rpc = config.rpc_url.as_ref().map(|url| match config.rpc_cookie {
+            Some(ref cookie) => RpcClient::with_cookie(url, config.params.rpc_port, cookie)
+                .expect("Unable to read RPC cookie file!"),
+            None => RpcClient::new(
+                url,
+                config.params.rpc_port,
+                config.rpc_user.as_deref(),
+                config.rpc_password.as_deref(),
+            ),
+        });
+
         Self {
             input_path: input_path.to_string(),
             blocks_dir: blocks_dir.to_string(),
             max_block: max_block,
+            params: config.params,
+            block_index: config.block_index,
+            undo: config.undo,
+            stale_report: config.stale_report.clone(),
+            fee_report: config
+                .fee_report
+                .as_ref()
+                .map(|path| FeeReport::new(path, config.resume)),
+            checkpoint_dir: config.checkpoint_dir.clone(),
+            checkpoint_interval: config.checkpoint_interval,
+            resume: config.resume,
+            chainstate: config.chainstate.clone(),
+            follow: config.follow,
+            poll_interval: config.poll_interval,
+            queue_size: config.queue_size,
+            threads: config.threads,
+            range: BlockRange {
+                start_height: config.start_height,
+                end_height: config.end_height,
+                since: config.since,
+                until: config.until,
+                recent_timestamps: VecDeque::new(),
+            },
+            recent_blocks: VecDeque::new(),
+            finished: false,
+            rpc,
+            peer: config.peer.clone(),
             labels: Default::default(),
             clusters: Clusters::new(config),
         }
     }
 
     pub fn run(&mut self) {
-        let mut goal_prev_hash: Hash = ZERO_HASH;
-        let mut last_block: Option<Block> = None;
-        let mut height = 0;
+        let mut taints = TaintTracker::new();
+        let mut utxos = UtxoSet::new();
+
+        let mut resume_hash = None;
+        if self.resume {
+            let checkpoint = Checkpoint::load(
+                self.checkpoint_dir.as_ref().unwrap(),
+                self.params,
+                self.checkpoint_mode(),
+            )
+            .expect("Unable to load checkpoint!");
+            info!(
+                "Resuming after block {} at height {}",
+                checkpoint.hash, checkpoint.height
+            );
+            resume_hash = Some(checkpoint.hash);
+            if let Some(ref mut report) = self.fee_report {
+                report.truncate(checkpoint.height);
+            }
+            self.labels = checkpoint.labels;
+            self.clusters
+                .restore(checkpoint.clusters, checkpoint.key_addresses);
+            taints = TaintTracker::with_start_txs(checkpoint.start_txs);
+            utxos = UtxoSet::with_items(checkpoint.output_items);
+        } else {
+            self.read_input(&mut taints);
+        }
 
-        let mut start_txs: HashMap<Hash, VecDeque<Taint>> = Default::default();
+        if let Some(ref dir) = self.chainstate {
+            let chainstate =
+                Chainstate::load(Path::new(dir), self.params).expect("Unable to load chainstate!");
+            resume_hash = Some(chainstate.hash);
+            utxos = UtxoSet::with_items(chainstate.output_items);
+        }
 
-        let mut output_items: HashMap<Hash, VecMap<Vec<(Address, u64)>>, RandomState<xx::Hash64>> =
-            HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+        let remote = match (self.rpc.take(), self.peer.take()) {
+            (Some(client), _) => Some(Remote::Rpc(client)),
+            (None, Some(address)) => {
+                let peer =
+                    Peer::connect(&address, self.params).expect("Unable to connect to peer!");
+                Some(Remote::Peer(peer, Vec::new()))
+            }
+            (None, None) => None,
+        };
 
-        let mut skipped_blocks: HashMap<Hash, Block, RandomState<xx::Hash64>> =
-            HashMap::with_hasher(RandomState::<xx::Hash64>::new());
+        match remote {
+            Some(remote) => self.run_remote(remote, resume_hash, &mut utxos, &mut taints),
+            None => self.run_blocks_dir(resume_hash, &mut utxos, &mut taints),
+        }
+    }
 
-        let blockchain: Blockchain = Blockchain::new(&self.blocks_dir, self.max_block);
+    fn run_blocks_dir(
+        &mut self,
+        resume_hash: Option<Hash>,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) {
+        let mut blockchain: Blockchain =
+            Blockchain::new(&self.blocks_dir, self.max_block, self.undo);
 
-        self.read_input(&mut start_txs);
+        let mut headers = HeaderTree::new();
+        if self.block_index && self.stale_report.is_some() {
+            headers.scan(&blockchain, self.params);
+        }
+        let chain = self.best_chain(&blockchain, &mut headers);
 
-        for (n, mmap) in blockchain.maps.iter().enumerate() {
-            let mmap_slice = &mut &mmap[..];
+        if let Some(ref path) = self.stale_report {
+            StaleReport::new(&blockchain, self.params, &headers, &chain).write(path);
+        }
 
-            info!(
-                "Processing block file {}/{}, height {}",
-                n,
-                blockchain.maps.len() - 1,
-                height
+        let start = match resume_hash {
+            Some(hash) => match chain.iter().position(|pos| pos.hash == hash) {
+                Some(n) => n + 1,
+                None => panic!("Block {} to continue after is not in the best chain", hash),
+            },
+            // Inputs are resolved from the undo data, earlier blocks are not needed
+            None if self.undo => chain
+                .iter()
+                .position(|pos| pos.height >= self.range.start_height)
+                .unwrap_or(chain.len()),
+            None => 0,
+        };
+
+        let tip = self.process_chain(&blockchain, &chain[start..], utxos, taints);
+
+        self.clusters.done();
+        headers.report_damaged();
+
+        if self.follow && !self.finished {
+            let tip = tip.or_else(|| chain[..start].last().cloned());
+            self.follow(&mut blockchain, &mut headers, tip, utxos, taints);
+        }
+    }
+
+    /// Fetches the blocks from bitcoind over JSON-RPC or P2P. All blocks after
+    /// the checkpoint or chainstate are fetched, as the unspent outputs are
+    /// built from them.
+    fn run_remote(
+        &mut self,
+        mut remote: Remote,
+        resume_hash: Option<Hash>,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) {
+        let mut tip: Option<BlockPos> = None;
+        let mut start = 0;
+        if let Some(hash) = resume_hash {
+            remote.sync().expect("Unable to reach bitcoind!");
+            match remote.block_height(&hash) {
+                Ok(height) if remote.block_hash(height).ok() == Some(hash) => start = height + 1,
+                _ => panic!("Block {} to continue after is not in the best chain", hash),
+            }
+        } else if self.range.start_height > 0 {
+            warn!(
+                "Fetching the {} blocks before the start height to build the unspent outputs, \
+                 use --chainstate to start at a later block",
+                self.range.start_height
             );
+        }
 
-            while mmap_slice.len() > 0 {
-                if skipped_blocks.contains_key(&goal_prev_hash) {
-                    self.on_block(
-                        &mut last_block.unwrap().transactions(),
-                        last_block.unwrap().header().timestamp(),
-                        &mut output_items,
-                        &mut start_txs,
-                    );
-                    height += 1;
-                    while let Some(block) = skipped_blocks.remove(&goal_prev_hash) {
-                        self.on_block(
-                            &mut block.transactions(),
-                            block.header().timestamp(),
-                            &mut output_items,
-                            &mut start_txs,
-                        );
-                        height += 1;
-                        goal_prev_hash = block.header().cur_hash();
-                        last_block = None;
+        let mut first = true;
+        loop {
+            if !first {
+                thread::sleep(Duration::from_secs(self.poll_interval));
+            }
+
+            let count = match remote.sync() {
+                Ok(count) => count,
+                Err(ref e) if !first => {
+                    warn!("Unable to reach bitcoind: {}", e);
+                    continue;
+                }
+                Err(e) => panic!("Unable to reach bitcoind: {}", e),
+            };
+
+            if let Some(tip) = tip {
+                let hash = if tip.height <= count {
+                    match remote.block_hash(tip.height) {
+                        Ok(hash) => Some(hash),
+                        Err(e) => {
+                            warn!("Unable to reach bitcoind: {}", e);
+                            continue;
+                        }
                     }
+                } else {
+                    None
+                };
+                if hash != Some(tip.hash) {
+                    self.reorg(&tip, |height| remote.block_hash(height).ok());
                 }
+            }
+            let end = self
+                .range
+                .end_height
+                .map_or(count, |end| cmp::min(end, count));
 
-                let block = match Block::read(mmap_slice) {
-                    Ok(block) => block,
-                    Err(_) => {
-                        assert_eq!(mmap_slice.len(), 0);
+            let mut processed = None;
+            if start <= end {
+                processed = self.process_blocks(
+                    |blocks| blocks.fetch(&mut remote, start, end),
+                    utxos,
+                    taints,
+                );
+            }
+            if let Some(pos) = processed {
+                start = pos.height + 1;
+                tip = Some(pos);
+            }
+            if first || processed.is_some() {
+                self.clusters.done();
+            }
+
+            if first && self.follow {
+                info!("Following bitcoind for new blocks");
+            }
+            first = false;
+            if !self.follow || self.finished || self.range.end_height.is_some_and(|end| start > end)
+            {
+                break;
+            }
+        }
+    }
+
+    /// Waits for bitcoind to append blocks to the blk files and processes
+    /// the new best chain blocks, rewriting the output after each batch.
+    fn follow(
+        &mut self,
+        blockchain: &mut Blockchain,
+        headers: &mut HeaderTree,
+        mut tip: Option<BlockPos>,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) {
+        info!("Following {} for new blocks", self.blocks_dir);
+
+        while !self.finished {
+            thread::sleep(Duration::from_secs(self.poll_interval));
+            blockchain.refresh();
+            let chain = self.best_chain(blockchain, headers);
+
+            let start = match tip {
+                Some(tip) => match chain.iter().position(|pos| pos.hash == tip.hash) {
+                    Some(n) => n + 1,
+                    None => self.reorg(&tip, |height| {
+                        chain
+                            .binary_search_by_key(&height, |pos| pos.height)
+                            .ok()
+                            .map(|n| chain[n].hash)
+                    }),
+                },
+                None => 0,
+            };
+            if start > 0 {
+                tip = Some(chain[start - 1]);
+            }
+
+            if let Some(pos) = self.process_chain(blockchain, &chain[start..], utxos, taints) {
+                tip = Some(pos);
+                self.clusters.done();
+            }
+        }
+    }
+
+    /// Stops after `tip` left the best chain. The unspent outputs, taints and
+    /// clusters of the orphaned blocks can not be rolled back, so continuing
+    /// would mix both chains. `best_hash` gives the best chain block at a
+    /// height.
+    fn reorg<F>(&self, tip: &BlockPos, mut best_hash: F) -> !
+    where
+        F: FnMut(usize) -> Option<Hash>,
+    {
+        let mut fork = tip.height;
+        let mut found = false;
+        for pos in self.recent_blocks.iter().rev() {
+            if best_hash(pos.height) == Some(pos.hash) {
+                found = true;
+                break;
+            }
+            fork = pos.height;
+        }
+
+        error!(
+            "Block {} at height {} left the best chain, which now differs from height {}{}",
+            tip.hash,
+            tip.height,
+            fork,
+            if found { "" } else { " or below" }
+        );
+        panic!(
+            "Unable to roll back blocks after a reorg, restart from a checkpoint before the fork"
+        );
+    }
+
+    /// Blocks of the best chain in height order, either from the bitcoind block
+    /// index or from the headers of all blocks found in the blk files.
+    fn best_chain(&self, blockchain: &Blockchain, headers: &mut HeaderTree) -> Vec<BlockPos> {
+        if self.block_index {
+            let index_dir = PathBuf::from(&self.blocks_dir).join("index");
+            let index = BlockIndex::new(&index_dir).expect("Unable to read block index!");
+            index.best_chain()
+        } else {
+            headers.scan(blockchain, self.params);
+            headers.best_chain()
+        }
+    }
+
+    fn process_chain(
+        &mut self,
+        blockchain: &Blockchain,
+        chain: &[BlockPos],
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) -> Option<BlockPos> {
+        self.process_blocks(|blocks| blocks.run(blockchain, chain), utxos, taints)
+    }
+
+    /// Reads the blocks from `source` on one thread, decodes them on a pool of
+    /// workers and applies them here in chain order.
+    fn process_blocks<'a, F>(
+        &mut self,
+        source: F,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+    ) -> Option<BlockPos>
+    where
+        F: FnOnce(&mut Blocks<'a>) + Send,
+    {
+        let params = self.params;
+        let (tx, rx) = channel::bounded(self.queue_size);
+        let (jobs_tx, jobs_rx) = channel::bounded(self.queue_size);
+        let mut last_block = None;
+        let mut error = None;
+
+        scope(|s| {
+            s.spawn(move |_| source(&mut Blocks::new(tx, jobs_tx, params)));
+            for _ in 0..self.threads {
+                let jobs_rx = jobs_rx.clone();
+                s.spawn(move |_| decode_blocks(jobs_rx, params));
+            }
+            drop(jobs_rx);
+
+            while let Ok(message) = rx.recv() {
+                let (pos, decoded) = match message {
+                    BlockMessage::OnBlock(pos, decoded) => (pos, decoded),
+                    BlockMessage::OnComplete(_) => break,
+                    BlockMessage::OnError(err) => {
+                        error = Some(err);
                         break;
                     }
                 };
 
-                if *block.header().prev_hash() != goal_prev_hash {
-                    skipped_blocks.insert(*block.header().prev_hash(), block);
-
-                    if last_block.is_some()
-                        && block.header().prev_hash() == last_block.unwrap().header().prev_hash()
-                    {
-                        let first_orphan = last_block.unwrap().clone();
-                        let second_orphan = block;
-
-                        loop {
-                            let block = match Block::read(mmap_slice) {
-                                Ok(block) => block,
-                                Err(_) => {
-                                    assert_eq!(mmap_slice.len(), 0);
-                                    break;
-                                }
-                            };
-
-                            skipped_blocks.insert(*block.header().prev_hash(), block);
-                            if block.header().prev_hash() == &first_orphan.header().cur_hash() {
-                                break;
-                            }
-                            if block.header().prev_hash() == &second_orphan.header().cur_hash() {
-                                goal_prev_hash = second_orphan.header().cur_hash();
-                                last_block = Some(second_orphan);
-                                break;
-                            }
-                        }
-                    }
-                    continue;
+                if self.range.is_past(pos.height) {
+                    self.finished = true;
+                    info!(
+                        "Reached the end of the selected range before height {}",
+                        pos.height
+                    );
+                    break;
                 }
 
-                if let Some(last_block) = last_block {
-                    self.on_block(
-                        &mut last_block.transactions(),
-                        last_block.header().timestamp(),
-                        &mut output_items,
-                        &mut start_txs,
-                    );
-                    height += 1;
+                let block = match decoded.recv().expect("Block decoding worker failed!") {
+                    Ok(block) => block,
+                    Err(DecodeError::UndoMismatch) => panic!(
+                        "Undo data of block {} at height {} does not match its transactions",
+                        pos.hash, pos.height
+                    ),
+                    Err(err) => panic!(
+                        "Unable to decode block {} at height {}: {:?}",
+                        pos.hash, pos.height, err
+                    ),
+                };
+                debug!(
+                    "Block {} at height {}: {} transactions, weight {}",
+                    pos.hash,
+                    pos.height,
+                    block.transactions.len(),
+                    block.weight
+                );
+                let timestamp = block.timestamp;
+                let in_range = self.range.contains(pos.height, timestamp);
+
+                self.on_block(&pos, block, utxos, taints, in_range);
+
+                self.range.on_block(timestamp);
+                self.recent_blocks.push_back(pos);
+                if self.recent_blocks.len() > REORG_HISTORY {
+                    self.recent_blocks.pop_front();
                 }
 
-                goal_prev_hash = block.header().cur_hash();
-                last_block = Some(block);
+                if self.checkpoint_interval > 0
+                    && pos.height > 0
+                    && pos.height % self.checkpoint_interval == 0
+                {
+                    self.save_checkpoint(&pos, utxos, taints);
+                }
+                last_block = Some(pos);
             }
+            // Lets the reading thread stop early
+            drop(rx);
+        })
+        .expect("Block processing thread failed!");
+
+        if let Some(ref pos) = last_block {
+            self.save_checkpoint(pos, utxos, taints);
+        }
+        // The analysis would be incomplete without the missing blocks
+        if let Some(err) = error {
+            panic!(
+                "Unable to read the best chain blocks after height {}: {:?}",
+                last_block.map_or("none".to_string(), |pos| pos.height.to_string()),
+                err
+            );
         }
+        last_block
+    }
 
-        self.clusters.done();
+    fn save_checkpoint(&self, pos: &BlockPos, utxos: &UtxoSet, taints: &TaintTracker) {
+        let dir = match self.checkpoint_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+
+        info!("Saving checkpoint at height {}", pos.height);
+        let state = CheckpointState {
+            params: self.params,
+            mode: self.checkpoint_mode(),
+            hash: &pos.hash,
+            height: pos.height,
+            labels: &self.labels,
+            start_txs: taints.start_txs(),
+            output_items: utxos.items(),
+            clusters: self.clusters.union_find(),
+            key_addresses: self.clusters.key_addresses(),
+        };
+        if let Err(e) = state.save(dir) {
+            error!("Unable to save checkpoint: {}", e);
+        }
+    }
+
+    fn checkpoint_mode(&self) -> Mode {
+        if self.undo {
+            Mode::Undo
+        } else if self.chainstate.is_some() {
+            Mode::Chainstate
+        } else {
+            Mode::Blocks
+        }
     }
 
     fn on_block(
         &mut self,
-        transactions: &mut Transactions,
-        timestamp: u32,
-        output_items: &mut HashMap<Hash, VecMap<Vec<(Address, u64)>>, RandomState<xx::Hash64>>,
-        start_txs: &mut HashMap<Hash, VecDeque<Taint>>,
+        pos: &BlockPos,
+        block: DecodedBlock,
+        utxos: &mut UtxoSet,
+        taints: &mut TaintTracker,
+        in_range: bool,
     ) {
-        let mut slice = transactions.slice;
-
-        for _ in 0..transactions.count {
-            if slice.len() > 0 {
-                let mut transaction: Transaction =
-                    match Transaction::read(&mut slice, timestamp, output_items, start_txs) {
-                        Ok(transaction) => (transaction),
-                        Err(_) => {
-                            warn!("Error processing transaction");
-                            continue;
-                        }
-                    };
+        // Outside of the selected range only the unspent outputs are updated
+        let mut no_taints = TaintTracker::new();
+        let taints = if in_range { taints } else { &mut no_taints };
 
+        let mut reward = BlockReward::new(pos.hash, pos.height, self.params);
+        for decoded in block.transactions {
+            let mut transaction = Transaction::apply(decoded, utxos, taints);
+            reward.on_transaction(&transaction);
+            if in_range {
+                if let Some(ref mut report) = self.fee_report {
+                    report.on_transaction(pos.height, &transaction);
+                }
                 self.clusters.on_transaction(&mut transaction);
             }
         }
+
+        if !reward.is_valid() {
+            warn!(
+                "Coinbase of block {} at height {} claims {} with a subsidy of {} and {} in fees",
+                reward.hash,
+                reward.height,
+                reward.reward,
+                reward.subsidy,
+                reward.fees.unwrap_or(0)
+            );
+        }
     }
 
-    fn read_input(&mut self, start_txs: &mut HashMap<Hash, VecDeque<Taint>>) {
+    fn read_input(&mut self, taints: &mut TaintTracker) {
         if self.input_path.len() == 0 {
             return;
         }
@@ -189,19 +579,120 @@ impl Parser {
             txid.reverse();
             let hash = Hash::from_slice(array_ref!(txid, 0, 32));
 
-            if !start_txs.contains_key(&hash) {
+            let amount = parts.next().unwrap().parse::<u64>().unwrap();
+            let mut start_taints: VecDeque<Taint> = VecDeque::new();
+            start_taints.push_back(Taint { label, amount });
+            if taints.add_start_tx(*hash, start_taints) {
                 self.labels.insert(tag, label);
-
-                let mut taints: VecDeque<Taint> = VecDeque::new();
-                let amount = parts.next().unwrap().parse::<u64>().unwrap();
-                taints.push_back(Taint {
-                    label: label,
-                    amount: amount,
-                });
                 label += 1;
-
-                start_txs.insert(*hash, taints);
             }
         }
     }
 }
+
+/// Heights and times of the blocks the clustering and taints are applied to.
+struct BlockRange {
+    start_height: usize,
+    end_height: Option<usize>,
+    since: Option<u32>,
+    until: Option<u32>,
+    /// Timestamps of the last processed blocks, used for their median time
+    recent_timestamps: VecDeque<u32>,
+}
+
+impl BlockRange {
+    /// Whether the block at `height`, following the processed blocks, and
+    /// all later blocks are past the end of the range.
+    fn is_past(&self, height: usize) -> bool {
+        if self.end_height.is_some_and(|end| height > end) {
+            return true;
+        }
+        // Later blocks can not be older than the median time past
+        self.until.is_some_and(|until| {
+            self.recent_timestamps.len() == MEDIAN_TIME_SPAN
+                && median(&self.recent_timestamps) >= until
+        })
+    }
+
+    fn contains(&self, height: usize, timestamp: u32) -> bool {
+        height >= self.start_height
+            && self.end_height.is_none_or(|end| height <= end)
+            && self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp < until)
+    }
+
+    fn on_block(&mut self, timestamp: u32) {
+        self.recent_timestamps.push_back(timestamp);
+        if self.recent_timestamps.len() > MEDIAN_TIME_SPAN {
+            self.recent_timestamps.pop_front();
+        }
+    }
+}
+
+fn median(timestamps: &VecDeque<u32>) -> u32 {
+    let mut sorted: Vec<u32> = timestamps.iter().cloned().collect();
+    sorted.sort();
+    sorted[sorted.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(
+        start_height: usize,
+        end_height: Option<usize>,
+        since: Option<u32>,
+        until: Option<u32>,
+    ) -> BlockRange {
+        BlockRange {
+            start_height,
+            end_height,
+            since,
+            until,
+            recent_timestamps: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn height_range() {
+        let range = range(10, Some(20), None, None);
+        assert!(!range.contains(9, 0));
+        assert!(range.contains(10, 0));
+        assert!(range.contains(20, u32::MAX));
+        assert!(!range.contains(21, 0));
+
+        assert!(!range.is_past(9));
+        assert!(!range.is_past(20));
+        assert!(range.is_past(21));
+    }
+
+    #[test]
+    fn since_filters_older_blocks() {
+        let mut range = range(0, None, Some(1000), None);
+        // Timestamps are not monotonic, so blocks after a match can be older
+        for &(timestamp, contained) in
+            [(999, false), (1000, true), (998, false), (2000, true)].iter()
+        {
+            assert_eq!(range.contains(5, timestamp), contained);
+            assert!(!range.is_past(5));
+            range.on_block(timestamp);
+        }
+    }
+
+    #[test]
+    fn until_stops_at_median_time_past() {
+        let mut range = range(0, None, None, Some(1000));
+        // Five of the last eleven blocks are at or past the limit, the median is not
+        for &timestamp in [990, 1000, 1001, 991, 1000, 992, 2000, 993, 1005, 994, 995].iter() {
+            assert!(!range.is_past(0));
+            range.on_block(timestamp);
+        }
+        assert!(!range.is_past(11));
+        assert!(!range.contains(11, 1000));
+        assert!(range.contains(11, 999));
+
+        range.on_block(1000);
+        assert!(range.is_past(12));
+    }
+}
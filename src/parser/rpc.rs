@@ -0,0 +1,448 @@
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::{self, Json};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use blockchain::hash::Hash;
+
+/// Largest reply accepted, enough for the hex of a 4 MB block
+const MAX_RESPONSE: usize = 32 * 1024 * 1024;
+const TIMEOUT: u64 = 120;
+
+/// Minimal bitcoind JSON-RPC client over a keep-alive HTTP connection.
+pub struct RpcClient {
+    address: String,
+    path: String,
+    auth: Option<String>,
+    stream: Option<BufReader<TcpStream>>,
+}
+
+impl RpcClient {
+    /// Accepts `[http://]host[:port][/path]`, the port defaults to `default_port`.
+    pub fn new(url: &str, default_port: u16, user: Option<&str>, password: Option<&str>) -> Self {
+        let url = url.trim_start_matches("http://");
+        let (address, path) = match url.find('/') {
+            Some(n) => (&url[..n], &url[n..]),
+            None => (url, "/"),
+        };
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, default_port)
+        };
+        let auth = user.map(|user| {
+            format!("{}:{}", user, password.unwrap_or(""))
+                .as_bytes()
+                .to_base64(STANDARD)
+        });
+
+        RpcClient {
+            address,
+            path: path.to_string(),
+            auth,
+            stream: None,
+        }
+    }
+
+    /// Reads the `user:password` pair bitcoind writes to its `.cookie` file.
+    pub fn with_cookie(url: &str, default_port: u16, cookie: &str) -> io::Result<Self> {
+        let mut contents = String::new();
+        File::open(cookie)?.read_to_string(&mut contents)?;
+        let mut parts = contents.trim().splitn(2, ':');
+        let user = parts.next();
+        let password = parts.next();
+        Ok(RpcClient::new(url, default_port, user, password))
+    }
+
+    pub fn block_count(&mut self) -> io::Result<usize> {
+        match self.call("getblockcount", vec![])? {
+            Json::U64(count) => Ok(count as usize),
+            Json::I64(count) if count >= 0 => Ok(count as usize),
+            _ => Err(invalid_data("invalid getblockcount result")),
+        }
+    }
+
+    pub fn block_hash(&mut self, height: usize) -> io::Result<Hash> {
+        match self.call("getblockhash", vec![Json::U64(height as u64)])? {
+            Json::String(ref hash) => parse_hash(hash),
+            _ => Err(invalid_data("invalid getblockhash result")),
+        }
+    }
+
+    /// Height of a block, also for blocks not in the best chain.
+    pub fn block_height(&mut self, hash: &Hash) -> io::Result<usize> {
+        let params = vec![Json::String(hash.to_string()), Json::Boolean(true)];
+        match self.call("getblockheader", params)?.find("height") {
+            Some(&Json::U64(height)) => Ok(height as usize),
+            Some(&Json::I64(height)) if height >= 0 => Ok(height as usize),
+            _ => Err(invalid_data("invalid getblockheader result")),
+        }
+    }
+
+    /// Serialized block, as stored in blk files without magic and length.
+    pub fn block(&mut self, hash: &Hash) -> io::Result<Vec<u8>> {
+        let params = vec![Json::String(hash.to_string()), Json::U64(0)];
+        match self.call("getblock", params)? {
+            Json::String(ref hex) => hex
+                .from_hex()
+                .map_err(|_| invalid_data("invalid getblock result")),
+            _ => Err(invalid_data("invalid getblock result")),
+        }
+    }
+
+    fn call(&mut self, method: &str, params: Vec<Json>) -> io::Result<Json> {
+        let body = format!(
+            "{{\"jsonrpc\":\"1.0\",\"id\":\"blockfast\",\"method\":{},\"params\":{}}}",
+            Json::String(method.to_string()),
+            Json::Array(params)
+        );
+
+        // A kept alive connection may have been closed by the server meanwhile
+        let reused = self.stream.is_some();
+        let response = match self.request(&body) {
+            Err(_) if reused => {
+                self.stream = None;
+                self.request(&body)?
+            }
+            response => response?,
+        };
+
+        let response = Json::from_str(&String::from_utf8_lossy(&response))
+            .map_err(|e| invalid_data(&format!("{} returned invalid JSON: {}", method, e)))?;
+        match response.find("error") {
+            Some(&Json::Null) | None => {}
+            Some(error) => {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    method,
+                    json::encode(error).unwrap()
+                )))
+            }
+        }
+        response
+            .find("result")
+            .cloned()
+            .ok_or_else(|| invalid_data(&format!("{} returned no result", method)))
+    }
+
+    fn request(&mut self, body: &str) -> io::Result<Vec<u8>> {
+        if self.stream.is_none() {
+            self.stream = Some(BufReader::new(connect(&self.address)?));
+        }
+        let stream = self.stream.as_mut().unwrap();
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.path,
+            self.address,
+            body.len()
+        );
+        if let Some(ref auth) = self.auth {
+            request.push_str(&format!("Authorization: Basic {}\r\n", auth));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.get_mut().write_all(request.as_bytes())?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let status: u32 = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid_data("invalid HTTP response"))?;
+
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(invalid_data("unexpected end of HTTP response"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap().trim().to_lowercase();
+            let value = parts.next().unwrap_or("").trim().to_lowercase();
+            match name.as_str() {
+                "content-length" => content_length = value.parse().ok(),
+                "transfer-encoding" => chunked = value.contains("chunked"),
+                "connection" => close = value == "close",
+                _ => {}
+            }
+        }
+
+        let mut response = Vec::new();
+        if chunked {
+            loop {
+                line.clear();
+                stream.read_line(&mut line)?;
+                let size = usize::from_str_radix(line.trim().split(';').next().unwrap(), 16)
+                    .map_err(|_| invalid_data("invalid HTTP chunk"))?;
+                if size == 0 {
+                    // Trailer
+                    line.clear();
+                    stream.read_line(&mut line)?;
+                    break;
+                }
+                let start = response.len();
+                if start + size > MAX_RESPONSE {
+                    return Err(invalid_data("HTTP response too large"));
+                }
+                response.resize(start + size, 0);
+                stream.read_exact(&mut response[start..])?;
+                line.clear();
+                stream.read_line(&mut line)?;
+            }
+        } else if let Some(len) = content_length {
+            if len > MAX_RESPONSE {
+                return Err(invalid_data("HTTP response too large"));
+            }
+            response.resize(len, 0);
+            stream.read_exact(&mut response)?;
+        } else {
+            stream
+                .take(MAX_RESPONSE as u64 + 1)
+                .read_to_end(&mut response)?;
+            if response.len() > MAX_RESPONSE {
+                return Err(invalid_data("HTTP response too large"));
+            }
+            close = true;
+        }
+
+        if close {
+            self.stream = None;
+        }
+        if status == 401 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "RPC authorization failed",
+            ));
+        }
+        // bitcoind reports failed calls with an error status and a JSON body
+        if status != 200 && response.first() != Some(&b'{') {
+            return Err(io::Error::other(format!(
+                "RPC request failed with HTTP status {}",
+                status
+            )));
+        }
+        Ok(response)
+    }
+}
+
+/// Connects to the first reachable address `host:port` resolves to.
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let timeout = Duration::from_secs(TIMEOUT);
+    let mut error = None;
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => error = Some(err),
+        }
+    }
+    Err(error.unwrap_or_else(|| invalid_data("RPC address does not resolve")))
+}
+
+fn parse_hash(hex: &str) -> io::Result<Hash> {
+    let mut bytes = hex
+        .from_hex()
+        .map_err(|_| invalid_data("invalid block hash"))?;
+    if bytes.len() != 32 {
+        return Err(invalid_data("invalid block hash"));
+    }
+    bytes.reverse();
+    Ok(*Hash::from_slice(array_ref!(bytes, 0, 32)))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    const HASH: &str = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+    /// Serves one connection, answering each request with the next reply.
+    /// Returns the address and the received requests.
+    fn mock_server(replies: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut requests = Vec::new();
+            for reply in replies {
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.to_lowercase().starts_with("content-length:") {
+                        content_length = line[15..].trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+                reader.get_mut().write_all(&reply).unwrap();
+            }
+            requests
+        });
+        (address, handle)
+    }
+
+    fn reply(status: &str, body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    fn chunked(chunks: &[&str]) -> Vec<u8> {
+        let mut response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_string();
+        for chunk in chunks {
+            response.push_str(&format!("{:x}\r\n{}\r\n", chunk.len(), chunk));
+        }
+        response.push_str("0\r\n\r\n");
+        response.into_bytes()
+    }
+
+    #[test]
+    fn block_hash_and_block() {
+        let (address, server) = mock_server(vec![
+            reply(
+                "200 OK",
+                &format!(
+                    "{{\"result\":\"{}\",\"error\":null,\"id\":\"blockfast\"}}",
+                    HASH
+                ),
+            ),
+            // Chunked, as sent by bitcoind for large replies
+            chunked(&["{\"result\":\"0100ff\"", ",\"error\":null}"]),
+        ]);
+        let mut client = RpcClient::new(&format!("http://{}/", address), 0, None, None);
+
+        let hash = client.block_hash(0).unwrap();
+        assert_eq!(hash.to_string(), HASH);
+        assert_eq!(client.block(&hash).unwrap(), vec![0x01, 0x00, 0xff]);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST / HTTP/1.1\r\n"));
+        assert!(requests[0].contains("\"method\":\"getblockhash\",\"params\":[0]"));
+        assert!(requests[1].contains(&format!(
+            "\"method\":\"getblock\",\"params\":[\"{}\",0]",
+            HASH
+        )));
+        assert!(!requests[0].contains("Authorization"));
+    }
+
+    #[test]
+    fn block_height() {
+        let (address, server) = mock_server(vec![reply(
+            "200 OK",
+            &format!(
+                "{{\"result\":{{\"hash\":\"{}\",\"height\":123}},\"error\":null}}",
+                HASH
+            ),
+        )]);
+        let mut client = RpcClient::new(&address, 0, None, None);
+
+        let hash = parse_hash(HASH).unwrap();
+        assert_eq!(client.block_height(&hash).unwrap(), 123);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(&format!(
+            "\"method\":\"getblockheader\",\"params\":[\"{}\",true]",
+            HASH
+        )));
+    }
+
+    #[test]
+    fn sends_basic_auth() {
+        let (address, server) = mock_server(vec![reply("200 OK", "{\"result\":7,\"error\":null}")]);
+        let mut client = RpcClient::new(&address, 0, Some("user"), Some("pass"));
+
+        assert_eq!(client.block_count().unwrap(), 7);
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[test]
+    fn unauthorized() {
+        let (address, server) = mock_server(vec![
+            b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let mut client = RpcClient::new(&address, 0, Some("user"), Some("wrong"));
+
+        let err = client.block_count().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn json_rpc_error() {
+        let body =
+            "{\"result\":null,\"error\":{\"code\":-8,\"message\":\"Block height out of range\"}}";
+        let (address, server) = mock_server(vec![reply("500 Internal Server Error", body)]);
+        let mut client = RpcClient::new(&address, 0, None, None);
+
+        let err = client.block_hash(1_000_000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().contains("getblockhash failed"));
+        assert!(err.to_string().contains("Block height out of range"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn response_too_large() {
+        let (address, server) = mock_server(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        )
+        .into_bytes()]);
+        let mut client = RpcClient::new(&address, 0, None, None);
+
+        let err = client.block_count().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("too large"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connection_dropped_mid_reply() {
+        let mut response = reply(
+            "200 OK",
+            &format!("{{\"result\":\"{}\",\"error\":null}}", HASH),
+        );
+        response.truncate(response.len() - 20);
+        let (address, server) = mock_server(vec![response]);
+        let mut client = RpcClient::new(&address, 0, None, None);
+
+        let err = client.block_hash(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        server.join().unwrap();
+    }
+}
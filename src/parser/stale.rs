@@ -0,0 +1,384 @@
+use fasthash::{xx, RandomState};
+use rustc_serialize::json::{self, Json};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+
+use blockchain::block::Block;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::transaction::Transaction;
+use parser::blockchain::{BlockPos, Blockchain};
+use parser::headers::{HeaderEntry, HeaderTree};
+use parser::ParseResult;
+
+/// Number of best chain blocks after a stale block searched for its transactions
+const MAIN_CHAIN_WINDOW: usize = 10;
+
+/// A block found in the blk files which lost a fork race.
+pub struct StaleBlock {
+    pub hash: Hash,
+    pub height: usize,
+    pub main_hash: Option<Hash>,
+    pub timestamp: u32,
+    pub tx_count: u64,
+    /// Non-coinbase transactions not confirmed in the best chain between the
+    /// fork point and `MAIN_CHAIN_WINDOW` blocks after the stale block
+    pub stale_txids: Vec<Hash>,
+}
+
+pub struct StaleReport<'a> {
+    blockchain: &'a Blockchain,
+    params: &'static ChainParams,
+    headers: &'a HeaderTree,
+    chain: &'a [BlockPos],
+    main_txids: HashMap<usize, HashSet<Hash>>,
+}
+
+impl<'a> StaleReport<'a> {
+    pub fn new(
+        blockchain: &'a Blockchain,
+        params: &'static ChainParams,
+        headers: &'a HeaderTree,
+        chain: &'a [BlockPos],
+    ) -> Self {
+        StaleReport {
+            blockchain,
+            params,
+            headers,
+            chain,
+            main_txids: Default::default(),
+        }
+    }
+
+    pub fn stale_blocks(&mut self) -> Vec<StaleBlock> {
+        let main: HashSet<Hash, RandomState<xx::Hash64>> =
+            self.chain.iter().map(|p| p.hash).collect();
+
+        let mut entries: Vec<&HeaderEntry> = self
+            .headers
+            .entries
+            .values()
+            .filter(|e| e.height.is_some() && !main.contains(&e.hash))
+            .collect();
+        entries.sort_by_key(|e| (e.height, e.file, e.offset));
+
+        let mut stale = Vec::new();
+        for entry in entries {
+            match self.stale_block(entry, &main) {
+                Ok(block) => stale.push(block),
+                Err(_) => warn!("Unable to read stale block {}", entry.hash),
+            }
+        }
+        stale
+    }
+
+    pub fn write(&mut self, path: &str) {
+        let stale = self.stale_blocks();
+        let mut writer =
+            LineWriter::new(File::create(path).expect("Unable to create stale report!"));
+
+        if path.ends_with(".json") {
+            let blocks: Vec<Json> = stale.iter().map(|block| block.to_json()).collect();
+            writeln!(writer, "{}", json::as_pretty_json(&Json::Array(blocks)))
+                .expect("Unable to write to stale report!");
+        } else {
+            writeln!(
+                writer,
+                "hash,height,main_hash,timestamp,tx_count,stale_txids"
+            )
+            .expect("Unable to write to stale report!");
+            for block in &stale {
+                let txids: Vec<String> = block.stale_txids.iter().map(|h| h.to_string()).collect();
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{}",
+                    block.hash,
+                    block.height,
+                    block.main_hash.map(|h| h.to_string()).unwrap_or_default(),
+                    block.timestamp,
+                    block.tx_count,
+                    txids.join(";")
+                )
+                .expect("Unable to write to stale report!");
+            }
+        }
+        info!("Found {} stale blocks", stale.len());
+    }
+
+    fn stale_block(
+        &mut self,
+        entry: &HeaderEntry,
+        main: &HashSet<Hash, RandomState<xx::Hash64>>,
+    ) -> ParseResult<StaleBlock> {
+        let height = entry.height.unwrap();
+        let pos = BlockPos {
+            hash: entry.hash,
+            height,
+            file: entry.file,
+            offset: entry.offset,
+            undo: None,
+        };
+        let data = self.blockchain.block_at(&pos, self.params)?;
+        let block = Block(&data);
+        let transactions = block.transactions();
+
+        let mut txids = Vec::new();
+        let mut slice = transactions.slice;
+        for _ in 0..transactions.count {
+            txids.push(Transaction::read_txid(&mut slice)?);
+        }
+
+        // Last block shared with the best chain, or its first available block
+        let mut fork_height = self.chain.first().map_or(0, |p| p.height);
+        let mut cur = self.headers.entries.get(&entry.prev_hash);
+        while let Some(ancestor) = cur {
+            if main.contains(&ancestor.hash) {
+                fork_height = ancestor.height.unwrap();
+                break;
+            }
+            cur = self.headers.entries.get(&ancestor.prev_hash);
+        }
+
+        let mut stale_txids = Vec::new();
+        for txid in txids.iter().skip(1) {
+            let mut confirmed = false;
+            for h in fork_height + 1..=height + MAIN_CHAIN_WINDOW {
+                if self.main_txids(h).contains(txid) {
+                    confirmed = true;
+                    break;
+                }
+            }
+            if !confirmed {
+                stale_txids.push(*txid);
+            }
+        }
+
+        Ok(StaleBlock {
+            hash: entry.hash,
+            height,
+            main_hash: self.main_pos(height).map(|p| p.hash),
+            timestamp: entry.timestamp,
+            tx_count: transactions.count,
+            stale_txids,
+        })
+    }
+
+    /// Best chain block at `height`, the chain has gaps where blocks are not
+    /// stored.
+    fn main_pos(&self, height: usize) -> Option<&'a BlockPos> {
+        let chain = self.chain;
+        chain
+            .binary_search_by_key(&height, |pos| pos.height)
+            .ok()
+            .map(|n| &chain[n])
+    }
+
+    fn main_txids(&mut self, height: usize) -> &HashSet<Hash> {
+        if !self.main_txids.contains_key(&height) {
+            let mut txids = HashSet::new();
+            if let Some(pos) = self.main_pos(height) {
+                if let Ok(data) = self.blockchain.block_at(pos, self.params) {
+                    let block = Block(&data);
+                    let transactions = block.transactions();
+                    let mut slice = transactions.slice;
+                    for _ in 0..transactions.count {
+                        match Transaction::read_txid(&mut slice) {
+                            Ok(txid) => txids.insert(txid),
+                            Err(_) => break,
+                        };
+                    }
+                }
+            }
+            self.main_txids.insert(height, txids);
+        }
+        &self.main_txids[&height]
+    }
+}
+
+impl StaleBlock {
+    pub fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("hash".to_string(), Json::String(self.hash.to_string()));
+        obj.insert("height".to_string(), Json::U64(self.height as u64));
+        obj.insert(
+            "main_hash".to_string(),
+            match self.main_hash {
+                Some(hash) => Json::String(hash.to_string()),
+                None => Json::Null,
+            },
+        );
+        obj.insert("timestamp".to_string(), Json::U64(self.timestamp as u64));
+        obj.insert("tx_count".to_string(), Json::U64(self.tx_count));
+        obj.insert(
+            "stale_txids".to_string(),
+            Json::Array(
+                self.stale_txids
+                    .iter()
+                    .map(|h| Json::String(h.to_string()))
+                    .collect(),
+            ),
+        );
+        Json::Object(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block::merkle_root;
+    use blockchain::hash::ZERO_HASH;
+    use blockchain::params::REGTEST;
+    use std::env;
+    use std::fs;
+
+    /// Transaction spending output 0 of transaction `n`.
+    fn transaction(n: u8) -> Vec<u8> {
+        let mut data = vec![1, 0, 0, 0, 1];
+        data.extend_from_slice(&[n; 32]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1]);
+        data.extend_from_slice(&[0; 13]);
+        data
+    }
+
+    /// Regtest block at `height` with a coinbase and `transactions`.
+    fn block(prev_hash: &Hash, height: u8, transactions: &[u8]) -> Vec<u8> {
+        let mut coinbase = vec![1, 0, 0, 0, 1];
+        coinbase.extend_from_slice(&[0; 32]);
+        coinbase.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 2, 1, height]);
+        coinbase.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 1]);
+        coinbase.extend_from_slice(&[0; 13]);
+        let mut txs = vec![coinbase];
+        txs.extend(transactions.iter().map(|&n| transaction(n)));
+        let txids = txs.iter().map(|tx| Hash::from_data(tx)).collect();
+
+        let mut data = vec![1, 0, 0, 0];
+        data.extend_from_slice(&prev_hash[..]);
+        data.extend_from_slice(&merkle_root(txids)[..]);
+        data.extend_from_slice(&[height, 0, 0, 0, 0xff, 0xff, 0x7f, 0x20, 0, 0, 0, 0]);
+        data.push(txs.len() as u8);
+        for tx in txs {
+            data.extend(tx);
+        }
+        data
+    }
+
+    fn txid(n: u8) -> Hash {
+        Hash::from_data(&transaction(n))
+    }
+
+    /// Best chain of 14 blocks after the genesis block, forked at height 1 by
+    /// two stale blocks. Transaction 1 is confirmed at height 5, 2 at height
+    /// 13, after the window of the first stale block, and 3 and 4 never.
+    fn forked_chain(name: &str) -> (Blockchain, Vec<Hash>, Vec<Hash>) {
+        let mut main = vec![block(&ZERO_HASH, 0, &[])];
+        let mut hashes = vec![Block(&main[0]).header().cur_hash()];
+        for height in 1..15 {
+            let transactions: &[u8] = match height {
+                5 => &[1],
+                13 => &[2],
+                _ => &[],
+            };
+            main.push(block(&hashes[height - 1], height as u8, transactions));
+            hashes.push(Block(&main[height]).header().cur_hash());
+        }
+        let s2 = block(&hashes[1], 2, &[1, 2, 3]);
+        let s2_hash = Block(&s2).header().cur_hash();
+        let s3 = block(&s2_hash, 3, &[4]);
+        let stale = vec![s2_hash, Block(&s3).header().cur_hash()];
+
+        let dir = env::temp_dir().join(format!("blockfast-stale-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = Vec::new();
+        for data in main[..3].iter().chain(&[s2, s3]).chain(&main[3..]) {
+            file.extend_from_slice(&[0xfa, 0xbf, 0xb5, 0xda]);
+            file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(data);
+        }
+        fs::write(dir.join("blk00000.dat"), file).unwrap();
+        (
+            Blockchain::new(&dir.to_string_lossy(), 0, false),
+            hashes,
+            stale,
+        )
+    }
+
+    #[test]
+    fn stale_blocks_and_transactions() {
+        let (blockchain, main, stale) = forked_chain("blocks");
+        let mut headers = HeaderTree::new();
+        headers.scan(&blockchain, &REGTEST);
+        let chain = headers.best_chain();
+        assert_eq!(chain.len(), 15);
+
+        let blocks = StaleReport::new(&blockchain, &REGTEST, &headers, &chain).stale_blocks();
+        let found: Vec<_> = blocks
+            .iter()
+            .map(|b| (b.hash, b.height, b.main_hash, b.timestamp, b.tx_count))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (stale[0], 2, Some(main[2]), 2, 4),
+                (stale[1], 3, Some(main[3]), 3, 2),
+            ]
+        );
+        assert_eq!(blocks[0].stale_txids, [txid(2), txid(3)]);
+        assert_eq!(blocks[1].stale_txids, [txid(4)]);
+
+        // Blocks above a shorter best chain have no main block
+        let blocks = StaleReport::new(&blockchain, &REGTEST, &headers, &chain[..2]).stale_blocks();
+        assert_eq!(blocks.len(), 15);
+        assert_eq!(blocks[0].main_hash, None);
+        assert_eq!(blocks[0].stale_txids, []);
+    }
+
+    #[test]
+    fn write_csv_and_json() {
+        let (blockchain, main, stale) = forked_chain("write");
+        let mut headers = HeaderTree::new();
+        headers.scan(&blockchain, &REGTEST);
+        let chain = headers.best_chain();
+        let mut report = StaleReport::new(&blockchain, &REGTEST, &headers, &chain);
+
+        let dir = env::temp_dir().join("blockfast-stale-write");
+        let csv = dir.join("stale.csv");
+        report.write(&csv.to_string_lossy());
+        assert_eq!(
+            fs::read_to_string(&csv).unwrap(),
+            format!(
+                "hash,height,main_hash,timestamp,tx_count,stale_txids\n\
+                 {},2,{},2,4,{};{}\n\
+                 {},3,{},3,2,{}\n",
+                stale[0],
+                main[2],
+                txid(2),
+                txid(3),
+                stale[1],
+                main[3],
+                txid(4)
+            )
+        );
+
+        let path = dir.join("stale.json");
+        report.write(&path.to_string_lossy());
+        let json = Json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let blocks = json.as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        let first = blocks[0].as_object().unwrap();
+        assert_eq!(first["hash"], Json::String(stale[0].to_string()));
+        assert_eq!(first["height"], Json::U64(2));
+        assert_eq!(first["main_hash"], Json::String(main[2].to_string()));
+        assert_eq!(first["timestamp"], Json::U64(2));
+        assert_eq!(first["tx_count"], Json::U64(4));
+        assert_eq!(
+            first["stale_txids"],
+            Json::Array(vec![
+                Json::String(txid(2).to_string()),
+                Json::String(txid(3).to_string()),
+            ])
+        );
+        assert_eq!(blocks[1]["stale_txids"].as_array().unwrap().len(), 1);
+    }
+}
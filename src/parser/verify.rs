@@ -0,0 +1,312 @@
+use crossbeam_channel as channel;
+use crossbeam_utils::thread::scope;
+use std::fmt;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use vec_map::VecMap;
+
+use blockchain::block::{merkle_root, Block};
+use blockchain::buffer::capacity;
+use blockchain::hash::Hash;
+use blockchain::params::ChainParams;
+use blockchain::transaction::Transaction;
+use parser::blockchain::{BlockFile, Blockchain, Scanned};
+use parser::{Config, ParseError};
+
+/// A problem found in a blk file.
+pub enum Failure {
+    /// No network magic and valid length, data skipped up to the given offset
+    Framing(usize),
+    /// Incomplete block at the end of the file
+    Truncated,
+    /// Block length runs into the next block, which starts at the given offset
+    Overlapping(usize),
+    ProofOfWork(Hash),
+    /// Transaction count or a transaction can not be read
+    Transactions(Hash),
+    MerkleRoot(Hash),
+    /// Bytes left after the last transaction
+    TrailingBytes(Hash, usize),
+}
+
+/// Verification result of a single blk file.
+pub struct FileReport {
+    pub file: usize,
+    pub size: usize,
+    pub blocks: usize,
+    /// Offsets in the file and what failed there
+    pub failures: Vec<(usize, Failure)>,
+}
+
+pub struct Verifier<'a> {
+    blockchain: &'a Blockchain,
+    params: &'static ChainParams,
+}
+
+/// Verifies all blk files and writes the report, returns whether all of
+/// them passed.
+pub fn run(config: &Config, path: &str) -> bool {
+    let blockchain = Blockchain::new(&config.blocks_dir, config.max_block, false);
+    let reports = Verifier::new(&blockchain, config.params).run(config.threads);
+
+    let mut writer = LineWriter::new(File::create(path).expect("Unable to create verify report!"));
+    for report in &reports {
+        writeln!(writer, "{}", report).expect("Unable to write to verify report!");
+    }
+
+    let blocks: usize = reports.iter().map(|r| r.blocks).sum();
+    let failures: usize = reports.iter().map(|r| r.failures.len()).sum();
+    if failures == 0 {
+        info!(
+            "Verified {} blocks in {} files, no failures",
+            blocks,
+            reports.len()
+        );
+    } else {
+        warn!(
+            "Verified {} blocks in {} files, {} failures",
+            blocks,
+            reports.len(),
+            failures
+        );
+    }
+    failures == 0
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(blockchain: &'a Blockchain, params: &'static ChainParams) -> Self {
+        Verifier { blockchain, params }
+    }
+
+    /// Verifies the blk files on `threads` threads, reports are returned in
+    /// file order.
+    pub fn run(&self, threads: usize) -> Vec<FileReport> {
+        let (files_tx, files_rx) = channel::unbounded();
+        for n in self.blockchain.maps.keys() {
+            files_tx.send(n).unwrap();
+        }
+        drop(files_tx);
+
+        let (reports_tx, reports_rx) = channel::unbounded();
+        scope(|s| {
+            for _ in 0..threads {
+                let files_rx = files_rx.clone();
+                let reports_tx = reports_tx.clone();
+                s.spawn(move |_| {
+                    for n in files_rx.iter() {
+                        let report = self.verify_file(n, &self.blockchain.maps[n]);
+                        reports_tx.send(report).unwrap();
+                    }
+                });
+            }
+        })
+        .unwrap();
+        drop(reports_tx);
+
+        let mut reports: VecMap<FileReport> = reports_rx.iter().map(|r| (r.file, r)).collect();
+        reports.drain().map(|(_, r)| r).collect()
+    }
+
+    pub fn verify_file(&self, file: usize, data: &BlockFile) -> FileReport {
+        let last_file = self.blockchain.last_file().unwrap_or(0);
+        debug!("Verifying block file {}/{}", file, last_file);
+
+        let mut report = FileReport {
+            file,
+            size: data.len(),
+            blocks: 0,
+            failures: Vec::new(),
+        };
+
+        let (end, error) = data.scan(0, self.params, |scanned| match scanned {
+            Scanned::Block(offset, block) => {
+                report.blocks += 1;
+                self.verify_block(&block, offset, &mut report);
+            }
+            Scanned::Damaged(start, next) => report.failures.push((start, Failure::Framing(next))),
+            Scanned::Overlapping(offset, next) => {
+                report.failures.push((offset, Failure::Overlapping(next)))
+            }
+        });
+        match error {
+            Some(ParseError::Eof) => report.failures.push((end, Failure::Truncated)),
+            Some(ParseError::Invalid) => report.failures.push((end, Failure::Framing(data.len()))),
+            None => {}
+        }
+
+        if report.failures.is_empty() {
+            debug!("{}", report);
+        } else {
+            warn!("{}", report);
+        }
+        report
+    }
+
+    fn verify_block(&self, block: &Block, offset: usize, report: &mut FileReport) {
+        let header = block.header();
+        let hash = header.cur_hash();
+        if !header.check_pow() {
+            report.failures.push((offset, Failure::ProofOfWork(hash)));
+        }
+
+        let transactions = block.transactions();
+        let mut slice = transactions.slice;
+        let mut txids = Vec::with_capacity(capacity(transactions.count, slice, 60));
+        for _ in 0..transactions.count {
+            match Transaction::read_txid(&mut slice) {
+                Ok(txid) => txids.push(txid),
+                Err(_) => break,
+            }
+        }
+        if txids.is_empty() || txids.len() as u64 != transactions.count {
+            report.failures.push((offset, Failure::Transactions(hash)));
+            return;
+        }
+
+        if merkle_root(txids) != *header.merkle_root() {
+            report.failures.push((offset, Failure::MerkleRoot(hash)));
+        }
+        if !slice.is_empty() {
+            report
+                .failures
+                .push((offset, Failure::TrailingBytes(hash, slice.len())));
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Framing(next) => {
+                write!(f, "invalid block framing, skipped to offset {}", next)
+            }
+            Failure::Truncated => write!(f, "incomplete block at the end of the file"),
+            Failure::Overlapping(next) => {
+                write!(f, "block length overlaps the block at offset {}", next)
+            }
+            Failure::ProofOfWork(hash) => {
+                write!(f, "block {} does not meet its proof of work target", hash)
+            }
+            Failure::Transactions(hash) => write!(f, "block {} has unreadable transactions", hash),
+            Failure::MerkleRoot(hash) => write!(f, "block {} has an invalid merkle root", hash),
+            Failure::TrailingBytes(hash, len) => write!(
+                f,
+                "block {} has {} bytes after its last transaction",
+                hash, len
+            ),
+        }
+    }
+}
+
+impl fmt::Display for FileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "blk{:05}.dat: {} bytes, {} blocks, {}",
+            self.file,
+            self.size,
+            self.blocks,
+            match self.failures.len() {
+                0 => "OK".to_string(),
+                n => format!("{} failures", n),
+            }
+        )?;
+        for &(offset, ref failure) in &self.failures {
+            write!(f, "\n  offset {}: {}", offset, failure)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::params::REGTEST;
+    use std::env;
+    use std::fs;
+
+    /// Regtest block with one coinbase, mined unless `merkle_root` is
+    /// given.
+    fn block(nonce: u8, merkle_root: Option<Hash>) -> Vec<u8> {
+        let mut tx = vec![1, 0, 0, 0, 1];
+        tx.extend_from_slice(&[0; 32]);
+        tx.extend_from_slice(&[
+            0xff, 0xff, 0xff, 0xff, 2, 1, nonce, 0xff, 0xff, 0xff, 0xff, 1,
+        ]);
+        tx.extend_from_slice(&[0; 13]);
+
+        let mut data = vec![1, 0, 0, 0];
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(&merkle_root.unwrap_or_else(|| Hash::from_data(&tx))[..]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0x7f, 0x20, 0, 0, 0, 0]);
+        data.push(1);
+        data.extend(tx);
+        while !Block(&data).header().check_pow() {
+            data[76] += 1;
+        }
+
+        let mut framed = vec![0xfa, 0xbf, 0xb5, 0xda, data.len() as u8, 0, 0, 0];
+        framed.extend(data);
+        framed
+    }
+
+    fn verify(files: &[Vec<u8>]) -> Vec<FileReport> {
+        let dir = env::temp_dir().join("blockfast-verify");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (n, data) in files.iter().enumerate() {
+            fs::write(dir.join(format!("blk{:05}.dat", n)), data).unwrap();
+        }
+        let blockchain = Blockchain::new(&dir.to_string_lossy(), 0, false);
+        Verifier::new(&blockchain, &REGTEST).run(2)
+    }
+
+    #[test]
+    fn verify_files() {
+        let len = block(0, None).len();
+
+        // Two blocks and preallocated space
+        let mut clean = block(0, None);
+        clean.extend(block(1, None));
+        clean.extend(vec![0; 100]);
+
+        // A mainnet magic between blocks and a block with another merkle root
+        let mut bad_magic = block(2, None);
+        bad_magic.extend_from_slice(&[0xf9, 0xbe, 0xb4, 0xd9]);
+        bad_magic.extend(block(3, None));
+        bad_magic.extend(block(4, Some(Hash::from_data(b"other"))));
+
+        // The last block cut short
+        let mut truncated = block(5, None);
+        truncated.extend(block(6, None));
+        truncated.truncate(len + 50);
+
+        let reports = verify(&[clean, bad_magic, truncated]);
+        let reports: Vec<String> = reports.iter().map(|r| r.to_string()).collect();
+        let merkle_block = Block(&block(4, Some(Hash::from_data(b"other")))[8..])
+            .header()
+            .cur_hash();
+        assert_eq!(
+            reports,
+            [
+                format!("blk00000.dat: {} bytes, 2 blocks, OK", 2 * len + 100),
+                format!(
+                    "blk00001.dat: {} bytes, 3 blocks, 2 failures\n  \
+                     offset {}: invalid block framing, skipped to offset {}\n  \
+                     offset {}: block {} has an invalid merkle root",
+                    3 * len + 4,
+                    len,
+                    len + 4,
+                    2 * len + 4,
+                    merkle_block
+                ),
+                format!(
+                    "blk00002.dat: {} bytes, 1 blocks, 1 failures\n  \
+                     offset {}: incomplete block at the end of the file",
+                    len + 50,
+                    len
+                ),
+            ]
+        );
+    }
+}